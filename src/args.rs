@@ -7,18 +7,41 @@ use std::fmt::Result as FmtResult;
 use std::path::PathBuf;
 use std::str::FromStr;
 
+use chrono::Datelike as _;
 use chrono::Local;
+use chrono::Months;
 use chrono::NaiveDate;
+use chrono::NaiveTime;
+
+use chrono_tz::Tz;
+
+use num_decimal::Num;
+
+use regex::Regex;
 
 use structopt::StructOpt;
 
+use crate::fallback::PriceSource;
+
 
-const DEFAULT_INVESTMENT_ACCOUNT: &str = "Assets:Investments:Alpaca:Stock";
-const DEFAULT_BROKERAGE_ACCOUNT: &str = "Assets:Alpaca Brokerage";
-const DEFAULT_BROKERAGE_FEE_ACCOUNT: &str = "Expenses:Broker:Fee";
-const DEFAULT_DIVIDEND_ACCOUNT: &str = "Income:Dividend";
-const DEFAULT_SEC_FEE_ACCOUNT: &str = "Expenses:Broker:SEC Fee";
-const DEFAULT_FINRA_TAF_ACCOUNT: &str = "Expenses:Broker:FINRA TAF";
+pub const DEFAULT_INVESTMENT_ACCOUNT: &str = "Assets:Investments:Alpaca:Stock";
+pub const DEFAULT_BROKERAGE_ACCOUNT: &str = "Assets:Alpaca Brokerage";
+pub const DEFAULT_BROKERAGE_FEE_ACCOUNT: &str = "Expenses:Broker:Fee";
+pub const DEFAULT_DIVIDEND_ACCOUNT: &str = "Income:Dividend";
+pub const DEFAULT_SEC_FEE_ACCOUNT: &str = "Expenses:Broker:SEC Fee";
+pub const DEFAULT_FINRA_TAF_ACCOUNT: &str = "Expenses:Broker:FINRA TAF";
+const DEFAULT_NON_QUALIFIED_DIVIDEND_ACCOUNT: &str = "Income:Dividend:Non-Qualified";
+const DEFAULT_REORG_FEE_ACCOUNT: &str = "Expenses:Broker:Reorg Fee";
+const DEFAULT_TRANSFER_FEE_ACCOUNT: &str = "Expenses:Broker:Transfer Fee";
+const DEFAULT_OPTION_PREMIUM_ACCOUNT: &str = "Income:Option Premium";
+const DEFAULT_REORG_ACCOUNT: &str = "Income:Reorganization";
+const DEFAULT_CAPITAL_GAINS_ACCOUNT: &str = "Income:Capital Gains";
+const DEFAULT_SWEEP_ACCOUNT: &str = "Assets:Alpaca Sweep";
+const DEFAULT_CRYPTO_WALLET_ACCOUNT: &str = "Assets:Crypto Wallet";
+const DEFAULT_NETWORK_FEE_ACCOUNT: &str = "Expenses:Crypto:Network Fee";
+const DEFAULT_CRYPTO_REWARD_ACCOUNT: &str = "Income:Crypto Reward";
+const DEFAULT_OPENING_BALANCES_ACCOUNT: &str = "Equity:Opening Balances";
+const DEFAULT_CASH_PROGRAM_INTEREST_ACCOUNT: &str = "Income:Interest:Alpaca Cash";
 
 
 /// A command line client for formatting Alpaca trades in Ledger format.
@@ -27,54 +50,708 @@ const DEFAULT_FINRA_TAF_ACCOUNT: &str = "Expenses:Broker:FINRA TAF";
 pub struct Args {
   #[structopt(subcommand)]
   pub command: Command,
+  /// The path to a JSON configuration file containing named profiles
+  /// (e.g., `{"profile": {"ira": {"key_id": ..., "secret": ...}}}`).
+  /// May also be `-` to read from stdin or an `http(s)://` URL.
+  #[structopt(long, global = true)]
+  pub config: Option<PathBuf>,
+  /// The name of the profile from the configuration file to use.
+  #[structopt(long, global = true, requires = "config")]
+  pub profile: Option<String>,
+  /// The timeout for individual API requests, in seconds.
+  #[structopt(long, global = true)]
+  pub timeout: Option<u64>,
+  #[structopt(flatten)]
+  pub connection: Connection,
+  /// The number of worker threads to use. By default, everything runs
+  /// on a single thread. With more, account activities are retrieved
+  /// in parallel to processing them.
+  #[structopt(short, long, global = true)]
+  pub jobs: Option<usize>,
+  /// The pager to pipe output written to a terminal through. Defaults
+  /// to the `APCALEDGE_PAGER` or `PAGER` environment variable or, if
+  /// neither is set, `less`.
+  #[structopt(long, global = true)]
+  pub pager: Option<String>,
+  /// Do not pipe output through a pager.
+  #[structopt(long, global = true, conflicts_with = "pager")]
+  pub no_pager: bool,
   /// Increase verbosity (can be supplied multiple times).
   #[structopt(short = "v", long = "verbose", global = true, parse(from_occurrences))]
   pub verbosity: usize,
 }
 
 
+/// Options for connecting to Alpaca, e.g., for pointing the program at a
+/// mock server or for use in corporate environments.
+#[derive(Debug, StructOpt)]
+pub struct Connection {
+  /// The base URL of the Alpaca trading API to use, overriding the one
+  /// from the profile or environment.
+  #[structopt(long, global = true)]
+  pub api_base_url: Option<String>,
+  /// The base URL of the Alpaca market data API to use.
+  #[structopt(long, global = true)]
+  pub data_base_url: Option<String>,
+  /// The path to a PEM file with CA certificates to trust when
+  /// connecting to Alpaca, in addition to the system's, e.g., for use
+  /// behind an intercepting proxy.
+  #[structopt(long, global = true)]
+  pub ca_cert: Option<PathBuf>,
+  /// The URL of an HTTP proxy to connect through, of the form
+  /// `http://[user:password@]host[:port]`. Defaults to the value of the
+  /// `HTTPS_PROXY` environment variable. Hosts listed in `NO_PROXY` are
+  /// connected to directly.
+  #[structopt(long, global = true)]
+  pub proxy: Option<String>,
+}
+
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, StructOpt)]
 pub enum Command {
   /// List trades and other account activity.
   Activity(Activity),
   /// Import trades and other account activity.
   Prices(Prices),
+  /// Manage the registry mapping symbols to names.
+  Registry(RegistryCommand),
+  /// Export sales along with their cost basis as CSV suitable for IRS
+  /// Form 8949.
+  #[structopt(name = "8949")]
+  Form8949(Form8949),
+  /// Print the days the market is open along with opening and closing
+  /// times.
+  Calendar(Calendar),
+  /// Print the account's equity at the end of each day, as CSV or as
+  /// Ledger price directives of a net-worth pseudo-commodity.
+  History(History),
+  /// Print an opening balance transaction for the currently held
+  /// positions and cash, for bootstrapping a journal without the full
+  /// account history.
+  OpeningBalances(OpeningBalances),
+  /// Export account activity, update the prices of all held assets, and
+  /// reconcile positions, all in one go.
+  Sync(Sync),
+  /// Check that credentials are valid and that the account, the market
+  /// clock, and market data can be accessed, reporting what is
+  /// misconfigured.
+  Doctor,
 }
 
 
 /// Retrieve and print account activity.
 #[derive(Debug, StructOpt)]
 pub struct Activity {
-  /// The path to the JSON registry for looking up names from symbols.
-  pub registry: PathBuf,
+  /// The path to the JSON registry for looking up names (and,
+  /// optionally, ISINs and CUSIPs) from symbols. Entries map a symbol
+  /// either to a name or to an object of the form `{"name": ...,
+  /// "isin": ..., "cusip": ...}`. May also be `-` to read from stdin
+  /// or an `http(s)://` URL. Multiple registries may be provided, in
+  /// which case entries of later ones override those of earlier ones.
+  #[structopt(required = true)]
+  pub registry: Vec<PathBuf>,
+  /// The path to a JSON file mapping payee names to the ones to use
+  /// instead. May also be `-` to read from stdin or an `http(s)://`
+  /// URL.
+  #[structopt(long)]
+  pub payee_aliases: Option<PathBuf>,
+  /// The path to a JSON file of the form `{"deposit": ...,
+  /// "withdrawal": ...}` specifying accounts to additionally book cash
+  /// deposits and withdrawals against, using virtual postings.
+  #[structopt(long)]
+  pub virtual_postings: Option<PathBuf>,
+  /// The path to a JSON file containing a list of rules of the form
+  /// `{"description": ..., "amount": ..., "account": ...}` for
+  /// determining the account to book cash deposits and withdrawals
+  /// against, instead of `XXX`. `description` is a regular expression
+  /// to match the activity's description against and `amount` the
+  /// exact (signed) amount; both are optional. The first matching rule
+  /// wins. May also be `-` to read from stdin or an `http(s)://` URL.
+  #[structopt(long)]
+  pub transfer_accounts: Option<PathBuf>,
+  /// Tag cash deposits and withdrawals with a `transfer` tag whose
+  /// value (`<yyyy-mm-dd> <amount> <currency>`) can be used to pair
+  /// them up with the other leg of the transfer, e.g., as imported from
+  /// a bank statement.
+  #[structopt(long)]
+  pub transfer_tags: bool,
+  /// The path to a JSON file mapping generated payees and comments
+  /// (e.g., "Transfer" or "{name} got acquired") to translations.
+  /// Placeholders in braces are substituted.
+  #[structopt(long)]
+  pub translations: Option<PathBuf>,
   /// Only show activities dated at the given date or after (format:
-  /// yyyy-mm-dd).
+  /// yyyy-mm-dd). May also be one of `ytd` (the beginning of the
+  /// year), `mtd` (the beginning of the month), `1y` (one year ago),
+  /// all relative to today's date in the time zone provided via
+  /// --timezone, or `last-run` (the date of the last activity recorded
+  /// in the state file).
   #[structopt(short, long)]
-  pub begin: Option<NaiveDate>,
+  pub begin: Option<Begin>,
+  /// Only report activities of the given category (one of: trade,
+  /// non-trade). Regulatory fees associated with trades are reported
+  /// along with them and are considered part of the trade category.
+  #[structopt(long)]
+  pub category: Option<Category>,
+  /// Stop before the first activity with the given status (e.g.,
+  /// `pending`), as reported by Alpaca, so that activities that may
+  /// still change are only exported by a later run. May be provided
+  /// multiple times.
+  #[structopt(long)]
+  pub exclude_status: Vec<String>,
+  /// The order in which to emit activities (one of: asc, desc).
+  #[structopt(long, default_value = "asc")]
+  pub order: SortOrder,
   /// Force keeping regulatory fees separate and not match them up with
   /// trades on a best-effort basis.
   #[structopt(long)]
   pub force_separate_fees: bool,
   /// The name of the investment account, i.e., the one holding the
-  /// shares.
-  #[structopt(long, default_value = DEFAULT_INVESTMENT_ACCOUNT)]
-  pub investment_account: String,
+  /// shares [default: Assets:Investments:Alpaca:Stock].
+  #[structopt(long)]
+  pub investment_account: Option<String>,
   /// The name of the brokerage account, i.e., the one holding any
-  /// uninvested cash.
-  #[structopt(long, default_value = DEFAULT_BROKERAGE_ACCOUNT)]
-  pub brokerage_account: String,
-  /// The name of the brokerage's fee account.
-  #[structopt(long, default_value = DEFAULT_BROKERAGE_FEE_ACCOUNT)]
-  pub brokerage_fee_account: String,
-  /// The name of the account to account dividend payments against.
-  #[structopt(long, default_value = DEFAULT_DIVIDEND_ACCOUNT)]
-  pub dividend_account: String,
-  /// The name of the account to use for regulatory fees by the SEC.
-  #[structopt(long, default_value = DEFAULT_SEC_FEE_ACCOUNT)]
-  pub sec_fee_account: String,
-  /// The name of the account to use for FINRA trade activity fees.
-  #[structopt(long, default_value = DEFAULT_FINRA_TAF_ACCOUNT)]
-  pub finra_taf_account: String,
+  /// uninvested cash [default: Assets:Alpaca Brokerage].
+  #[structopt(long)]
+  pub brokerage_account: Option<String>,
+  /// The name of the brokerage's fee account [default:
+  /// Expenses:Broker:Fee].
+  #[structopt(long)]
+  pub brokerage_fee_account: Option<String>,
+  /// The name of the account to account dividend payments against
+  /// [default: Income:Dividend].
+  #[structopt(long)]
+  pub dividend_account: Option<String>,
+  /// The name of the account to account non-qualified dividends and
+  /// return of capital distributions against.
+  #[structopt(long, default_value = DEFAULT_NON_QUALIFIED_DIVIDEND_ACCOUNT)]
+  pub non_qualified_dividend_account: String,
+  /// Book return of capital distributions against the `Basis
+  /// Adjustment` sub-account of the investment account, reducing the
+  /// cost basis of the holding in aggregate, instead of treating them
+  /// as income. The cost of individual lots is not adjusted.
+  #[structopt(long)]
+  pub roc_reduces_basis: bool,
+  /// The name of the account to use for regulatory fees by the SEC
+  /// [default: Expenses:Broker:SEC Fee].
+  #[structopt(long)]
+  pub sec_fee_account: Option<String>,
+  /// The name of the account to use for FINRA trade activity fees
+  /// [default: Expenses:Broker:FINRA TAF].
+  #[structopt(long)]
+  pub finra_taf_account: Option<String>,
+  /// The name of the account to use for fees charged in the context of
+  /// corporate reorganizations.
+  #[structopt(long, default_value = DEFAULT_REORG_FEE_ACCOUNT)]
+  pub reorg_fee_account: String,
+  /// The name of the account to use for fees charged for ACH or wire
+  /// transfers.
+  #[structopt(long, default_value = DEFAULT_TRANSFER_FEE_ACCOUNT)]
+  pub transfer_fee_account: String,
+  /// Annotate sales of option contracts with the premium received per
+  /// day until expiration and, once a sold contract expires, book the
+  /// premium against the account provided via --option-premium-account.
+  /// Only contracts sold as part of the same run are known to expire.
+  #[structopt(long)]
+  pub option_premium: bool,
+  /// The name of the account to book the premium of expired option
+  /// contracts against.
+  #[structopt(long, default_value = DEFAULT_OPTION_PREMIUM_ACCOUNT)]
+  pub option_premium_account: String,
+  /// The name of the account to account cash from mandatory or
+  /// voluntary reorganizations against.
+  #[structopt(long, default_value = DEFAULT_REORG_ACCOUNT)]
+  pub reorg_account: String,
+  /// The name of the account to account cash paid in lieu of fractional
+  /// shares against.
+  #[structopt(long, default_value = DEFAULT_CAPITAL_GAINS_ACCOUNT)]
+  pub capital_gains_account: String,
+  /// The name of the account holding cash swept from the brokerage
+  /// account into a cash sweep program.
+  #[structopt(long, default_value = DEFAULT_SWEEP_ACCOUNT)]
+  pub sweep_account: String,
+  /// The name of the account representing the external wallet crypto
+  /// coins get deposited from and withdrawn to.
+  #[structopt(long, default_value = DEFAULT_CRYPTO_WALLET_ACCOUNT)]
+  pub crypto_wallet_account: String,
+  /// The name of the account to use for network fees paid for crypto
+  /// transfers.
+  #[structopt(long, default_value = DEFAULT_NETWORK_FEE_ACCOUNT)]
+  pub network_fee_account: String,
+  /// The name of the account to book crypto rewards, e.g., for staking,
+  /// against.
+  #[structopt(long, default_value = DEFAULT_CRYPTO_REWARD_ACCOUNT)]
+  pub crypto_reward_account: String,
+  /// The name of the account to account interest paid as part of the
+  /// high-yield cash program (Alpaca Cash) against.
+  #[structopt(long, default_value = DEFAULT_CASH_PROGRAM_INTEREST_ACCOUNT)]
+  pub cash_program_interest_account: String,
+  /// Book interest on an accrual basis, accruing it in the provided
+  /// receivable account at the end of the month it was paid for (as
+  /// named by the payment's description) or, failing that, at the end
+  /// of the month preceding its payment.
+  #[structopt(long, requires = "interest-accruals")]
+  pub interest_accrual_account: Option<String>,
+  /// Write interest accruals, in date order, to the provided file (or
+  /// `-` for stdout, after all other transactions). Accruals are dated
+  /// before the payments that reveal them and so can't be emitted
+  /// along with the rest of the journal in order.
+  #[structopt(long, requires = "interest-accrual-account")]
+  pub interest_accruals: Option<PathBuf>,
+  /// Write `account` and `commodity` declarations for all accounts and
+  /// commodities used to the provided file, for inclusion ahead of the
+  /// generated journal.
+  #[structopt(long)]
+  pub emit_declarations: Option<PathBuf>,
+  /// Write Ledger periodic transactions (`~ monthly`) for pass-thru
+  /// charges recurring monthly with the same amount, such as market
+  /// data subscriptions, to the provided file.
+  #[structopt(long)]
+  pub emit_periodic: Option<PathBuf>,
+  /// Instead of aborting on the first activity that could not be
+  /// processed, continue and write all such activities to the provided
+  /// file in JSON format.
+  #[structopt(long)]
+  pub error_report: Option<PathBuf>,
+  /// The path to a file used for persisting the last emitted activity
+  /// after each day, allowing a subsequent run to resume from there.
+  #[structopt(long)]
+  pub state: Option<PathBuf>,
+  /// Post a summary of the run (the number of activities processed as
+  /// well as the fees charged and dividends received) or the error it
+  /// failed with to the webhook at the provided URL, e.g., for keeping
+  /// an eye on unattended runs resuming via --state.
+  #[structopt(long)]
+  pub notify: Option<String>,
+  /// Write the warnings encountered (e.g., about ignored activities,
+  /// activities booked using a fallback, or partial fills that could
+  /// not be merged) to the provided file in JSON format, for surfacing
+  /// them in automated runs.
+  #[structopt(long)]
+  pub warnings: Option<PathBuf>,
+  /// The path to a file caching all account activities retrieved so
+  /// far, so that only new ones have to be retrieved from Alpaca.
+  #[structopt(long, conflicts_with = "state")]
+  pub cache: Option<PathBuf>,
+  /// Drop cached activities dated after the given date, causing them to
+  /// be retrieved again (format: yyyy-mm-dd).
+  #[structopt(long, requires = "cache")]
+  pub refresh_after: Option<NaiveDate>,
+  /// Stop after the given number of activities has been processed,
+  /// e.g., for a quick exploratory run on a large account.
+  #[structopt(long)]
+  pub limit: Option<usize>,
+  /// Retrieve activities in windows spanning the given number of days,
+  /// starting at the date provided via --begin or, when resuming from a
+  /// cache or state, at the date of the last known activity, instead of
+  /// requesting the entire history at once.
+  #[structopt(long)]
+  pub chunk_days: Option<u32>,
+  /// Write each account activity request along with the activities
+  /// retrieved to files in the provided directory, e.g., for sharing
+  /// activities that could not be processed.
+  #[structopt(long)]
+  pub dump_http: Option<PathBuf>,
+  /// Append the prices of the assets involved in the exported
+  /// activities to the provided Ledger price database: for every day
+  /// with activity those of the assets involved on that day, as well as
+  /// the current prices of all of them.
+  #[structopt(long)]
+  pub prices: Option<PathBuf>,
+  /// Tag trades belonging to orders whose client order ID starts with
+  /// the given prefix with a strategy (format: <prefix>=<strategy>; can
+  /// be supplied multiple times).
+  #[structopt(long = "strategy", number_of_values = 1)]
+  pub strategies: Vec<Strategy>,
+  /// Book trades tagged with a strategy against a per-strategy
+  /// sub-account of the investment account.
+  #[structopt(long, requires = "strategies")]
+  pub strategy_accounts: bool,
+  /// Emit the given transaction code for activities of a kind, where
+  /// the kind is either `buy`, `sell`, or an Alpaca activity type such
+  /// as `DIV` (format: <kind>=<code>; can be supplied multiple times).
+  #[structopt(long = "transaction-code", number_of_values = 1)]
+  pub transaction_codes: Vec<TransactionCode>,
+  /// Book shares against a per-symbol sub-account of the investment
+  /// account (e.g., `Assets:Investments:Alpaca:Stock:AAPL`).
+  #[structopt(long)]
+  pub per_symbol_accounts: bool,
+  /// Book ADR fees against a per-symbol sub-account of the brokerage
+  /// fee account (e.g., `Expenses:Broker:Fee:TSM`).
+  #[structopt(long)]
+  pub per_symbol_adr_fee_accounts: bool,
+  /// Annotate trades with details (type, time in force, submission
+  /// time) of the order they belong to.
+  #[structopt(long)]
+  pub order_details: bool,
+  /// Tag each trade posting with the time of the fill (in New York
+  /// time), to tell apart multiple same-day trades.
+  #[structopt(long)]
+  pub fill_times: bool,
+  /// Emit a single transaction per order and day, with one posting per
+  /// distinct fill price, instead of one transaction per fill price.
+  /// The legs of multi-leg orders (e.g., options spreads) end up in a
+  /// single transaction this way, with one posting per leg.
+  #[structopt(long)]
+  pub group_fills: bool,
+  /// Combine trades into a single transaction at the weighted average
+  /// price (one of: daily). `daily` combines all fills for the same
+  /// symbol and side on a given day.
+  #[structopt(long, conflicts_with = "group-fills")]
+  pub summarize_trades: Option<TradeSummary>,
+  /// Which comments to emit for activities (one of: none,
+  /// descriptions, full). `full` emits activity IDs in addition to
+  /// descriptions and attaches fee comments to the fee postings
+  /// themselves, instead of emitting them above.
+  #[structopt(long, default_value = "descriptions")]
+  pub comments: Comments,
+  /// The date to book dividends at (one of: pay, record). `record`
+  /// uses the record date from the activity description, falling back
+  /// to the pay date (with a warning) if there is none. Booking at the
+  /// ex-dividend date (`ex`) is not supported, as Alpaca does not
+  /// report it.
+  #[structopt(long, default_value = "pay")]
+  pub dividend_date: DividendDate,
+  /// The time zone to derive the dates of trades in (e.g.,
+  /// `Asia/Tokyo`). Alpaca reports trade times in UTC, which may be a
+  /// day off from the dates on broker statements in other time zones.
+  /// The time zone also delimits days for batching and for the state
+  /// file, and it is used for interpreting `--begin` and for dating
+  /// prices and reconciliations. The dates of non-trade activities are
+  /// not affected and fill times are always reported in New York
+  /// time.
+  #[structopt(long, default_value = "UTC")]
+  pub timezone: Tz,
+  /// Tag trade postings with the ISIN and CUSIP of the traded asset, if
+  /// provided by the registry.
+  #[structopt(long)]
+  pub security_ids: bool,
+  /// Emit a comment summarizing the total fees charged for a trade
+  /// above the individual fee postings.
+  #[structopt(long)]
+  pub fee_totals: bool,
+  /// Annotate transactions with comments explaining how activities got
+  /// classified, e.g., which description pattern a fee matched and
+  /// which trade it got associated with.
+  #[structopt(long)]
+  pub explain: bool,
+  /// Emit activities that can't be represented (e.g., because their
+  /// type is unsupported) as commented out blocks instead of only
+  /// warning about them.
+  #[structopt(long)]
+  pub emit_unknown: bool,
+  /// A shell command to pipe each generated transaction through. The
+  /// command receives the transaction as JSON on stdin and has to print
+  /// the transaction to emit in its place, in the same format, or
+  /// nothing at all to drop it.
+  #[structopt(long)]
+  pub filter: Option<String>,
+  /// Declare all accounts, commodities, and payees ahead of their first
+  /// use, as required for the output to pass `ledger --pedantic`.
+  #[structopt(long)]
+  pub pedantic: bool,
+  /// Fail with a summary of all activities that can't be represented
+  /// instead of only warning about them.
+  #[structopt(long)]
+  pub strict: bool,
+  /// The cash balance of the account before the first reported
+  /// activity. If provided, the running cash balance is tracked and a
+  /// warning (or, with `--strict`, an error) is emitted if it turns
+  /// negative, which usually hints at missing or mis-ordered
+  /// activities.
+  #[structopt(long)]
+  pub opening_balance: Option<Num>,
+  /// The path to a Ledger journal declaring accounts (via `account`
+  /// directives). If provided, all accounts to book activities against
+  /// are checked for being declared in it.
+  #[structopt(long)]
+  pub accounts_file: Option<PathBuf>,
+  /// Split output into one file per month or year (one of: month,
+  /// year), written below the directory provided via --output-dir,
+  /// along with a `main.journal` file including all of them.
+  #[structopt(long, requires = "output-dir")]
+  pub split_by: Option<SplitBy>,
+  /// The directory to write files to when splitting output.
+  #[structopt(long, requires = "split-by")]
+  pub output_dir: Option<PathBuf>,
+  /// Keep a copy of the previous version of each file replaced (journals
+  /// below --output-dir and the --prices database) with a `.bak`
+  /// suffix.
+  #[structopt(long)]
+  pub backup: bool,
+  /// Stage the files written below --output-dir (as well as the
+  /// --prices database) in the git repository containing them and
+  /// commit them with a message summarizing the run.
+  #[structopt(long, requires = "output-dir")]
+  pub git_commit: bool,
+  /// Render transactions with colors to the terminal instead of writing
+  /// any files, for checking what an export would produce.
+  #[structopt(
+    long,
+    conflicts_with_all = &[
+      "output-dir",
+      "state",
+      "prices",
+      "emit-declarations",
+      "emit-periodic",
+      "error-report",
+    ],
+  )]
+  pub preview: bool,
+  /// Only emit the transactions for the activity with the given ID
+  /// (e.g., as reported in the `id` note of a transaction), for
+  /// regenerating individual transactions. Can be supplied multiple
+  /// times.
+  #[structopt(
+    long = "id",
+    number_of_values = 1,
+    conflicts_with_all = &["output-dir", "state", "opening-balance"],
+  )]
+  pub ids: Vec<String>,
+  /// Only emit transactions for activities with a description or
+  /// symbol (of any of the trades or fees involved) matching the given
+  /// regular expression.
+  #[structopt(long, conflicts_with_all = &["output-dir", "state", "opening-balance"])]
+  pub filter_description: Option<Regex>,
+  /// Do not emit transactions for activities with a description or
+  /// symbol matching the given regular expression.
+  #[structopt(long, conflicts_with_all = &["output-dir", "state", "opening-balance"])]
+  pub exclude_description: Option<Regex>,
+  /// Render the account activities contained in the provided JSON file
+  /// instead of retrieving them from Alpaca. Used for testing.
+  #[structopt(long, hidden = true)]
+  pub render_fixture: Option<PathBuf>,
+}
+
+
+/// A category of account activity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+  /// Trade activity, i.e., fills.
+  Trade,
+  /// All other account activity (e.g., dividends or fees).
+  NonTrade,
+}
+
+impl FromStr for Category {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "trade" => Ok(Self::Trade),
+      "non-trade" => Ok(Self::NonTrade),
+      _ => Err(format!("invalid activity category: {string}")),
+    }
+  }
+}
+
+
+/// The comments to emit for activities.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comments {
+  /// Emit no comments beyond those necessary for understanding a
+  /// transaction.
+  None,
+  /// Emit the descriptions Alpaca provides.
+  Descriptions,
+  /// Emit descriptions as well as activity IDs.
+  Full,
+}
+
+impl FromStr for Comments {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "none" => Ok(Self::None),
+      "descriptions" => Ok(Self::Descriptions),
+      "full" => Ok(Self::Full),
+      _ => Err(format!("invalid comment mode: {string}")),
+    }
+  }
+}
+
+
+/// The date to book dividends at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DividendDate {
+  /// The date the dividend got paid out.
+  Pay,
+  /// The record date of the dividend.
+  Record,
+}
+
+impl FromStr for DividendDate {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "pay" => Ok(Self::Pay),
+      "record" => Ok(Self::Record),
+      "ex" => Err("booking dividends at the ex-dividend date is not supported".to_string()),
+      _ => Err(format!("invalid dividend date: {string}")),
+    }
+  }
+}
+
+
+/// The date at which to begin reporting activities.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Begin {
+  /// A fixed date.
+  Date(NaiveDate),
+  /// The date of the last activity emitted by the previous run.
+  LastRun,
+  /// The beginning of the current year.
+  YearToDate,
+  /// The beginning of the current month.
+  MonthToDate,
+  /// The same date one year ago.
+  Year,
+}
+
+impl Begin {
+  /// Resolve the beginning to a date, given today's date and the date
+  /// of the last activity emitted by the previous run, if any.
+  pub fn resolve(&self, today: NaiveDate, last_run: Option<NaiveDate>) -> Option<NaiveDate> {
+    match self {
+      Self::Date(date) => Some(*date),
+      Self::LastRun => last_run,
+      Self::YearToDate => today.with_ordinal(1),
+      Self::MonthToDate => today.with_day(1),
+      Self::Year => today.checked_sub_months(Months::new(12)),
+    }
+  }
+}
+
+impl FromStr for Begin {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "last-run" => Ok(Self::LastRun),
+      "ytd" => Ok(Self::YearToDate),
+      "mtd" => Ok(Self::MonthToDate),
+      "1y" => Ok(Self::Year),
+      _ => NaiveDate::from_str(string)
+        .map(Self::Date)
+        .map_err(|err| format!("invalid begin date {string}: {err}")),
+    }
+  }
+}
+
+
+/// The order in which activities are emitted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+  /// Oldest activities first.
+  Ascending,
+  /// Newest activities first.
+  Descending,
+}
+
+impl FromStr for SortOrder {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "asc" => Ok(Self::Ascending),
+      "desc" => Ok(Self::Descending),
+      _ => Err(format!("invalid sort order: {string}")),
+    }
+  }
+}
+
+
+/// The granularity at which to split output into files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SplitBy {
+  /// One file per month, e.g., `2023/06.journal`.
+  Month,
+  /// One file per year, e.g., `2023.journal`.
+  Year,
+}
+
+impl FromStr for SplitBy {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "month" => Ok(Self::Month),
+      "year" => Ok(Self::Year),
+      _ => Err(format!("invalid split granularity: {string}")),
+    }
+  }
+}
+
+
+/// The granularity at which to combine trades.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TradeSummary {
+  /// Combine all trades of the same symbol and side on a day.
+  Daily,
+}
+
+impl FromStr for TradeSummary {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "daily" => Ok(Self::Daily),
+      _ => Err(format!("invalid trade summary granularity: {string}")),
+    }
+  }
+}
+
+
+/// A mapping from a client order ID prefix to a strategy name.
+#[derive(Debug)]
+pub struct Strategy {
+  /// The prefix of the client order ID.
+  pub prefix: String,
+  /// The name of the strategy.
+  pub name: String,
+}
+
+impl FromStr for Strategy {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    let (prefix, name) = string
+      .split_once('=')
+      .ok_or_else(|| format!("invalid strategy mapping: {string}"))?;
+
+    Ok(Self {
+      prefix: prefix.to_string(),
+      name: name.to_string(),
+    })
+  }
+}
+
+
+/// A mapping from a kind of activity to a Ledger transaction code.
+#[derive(Debug)]
+pub struct TransactionCode {
+  /// The kind of activity, e.g., `buy` or `DIV`.
+  pub kind: String,
+  /// The transaction code to emit.
+  pub code: String,
+}
+
+impl FromStr for TransactionCode {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    let (kind, code) = string
+      .split_once('=')
+      .ok_or_else(|| format!("invalid transaction code mapping: {string}"))?;
+
+    Ok(Self {
+      kind: kind.to_string(),
+      code: code.to_string(),
+    })
+  }
 }
 
 
@@ -108,8 +785,327 @@ impl FromStr for Date {
 pub struct Prices {
   /// The symbols for which to retrieve the most recent price.
   pub symbols: Vec<String>,
+  /// The name of an Alpaca watchlist whose symbols to additionally
+  /// retrieve prices for.
+  #[structopt(long)]
+  pub watchlist: Option<String>,
+  /// Additionally report the account's total equity at the end of the
+  /// day as the price of a pseudo-commodity with the given name, e.g.,
+  /// NETWORTH.
+  #[structopt(long)]
+  pub equity: Option<String>,
+  /// Instead of retrieving prices from Alpaca, convert the prices in
+  /// the provided CSV file with lines of the form
+  /// `<symbol>,<yyyy-mm-dd>,<price>[,<currency>]` (optionally preceded
+  /// by a header line) into price directives. May also be `-` to read
+  /// from stdin or an `http(s)://` URL.
+  #[structopt(long, conflicts_with_all = &["watchlist", "equity"])]
+  pub from_csv: Option<PathBuf>,
+  /// The path to a Ledger price database to check retrieved prices
+  /// against, warning about prices deviating from the most recent one
+  /// recorded for the same commodity by more than --max-price-change
+  /// percent.
+  #[structopt(long, requires = "max-price-change")]
+  pub price_db: Option<PathBuf>,
+  /// The maximum change, in percent, of a price compared to the
+  /// previous one in the price database before a warning is emitted.
+  /// Large jumps may indicate a split or bad data.
+  #[structopt(long, requires = "price-db")]
+  pub max_price_change: Option<Num>,
   /// The date for which to retrieve the price or, if not specified,
   /// defaults today's date (format: yyyy-mm-dd).
   #[structopt(short, long, default_value)]
   pub date: Date,
+  #[structopt(flatten)]
+  pub options: PriceOptions,
+}
+
+
+/// Options controlling which price gets reported.
+#[derive(Debug, Default, StructOpt)]
+pub struct PriceOptions {
+  /// Report the price at the given time of day in New York time
+  /// (format: hh:mm) instead of the daily close.
+  #[structopt(long, parse(try_from_str = parse_time))]
+  pub time: Option<NaiveTime>,
+  /// The time frame of the bars to use for intraday prices (one of:
+  /// minute, hour).
+  #[structopt(long, default_value = "minute")]
+  pub timeframe: TimeFrame,
+  /// The bar field to report as price (one of: close, vwap).
+  #[structopt(long, default_value = "close")]
+  pub price_field: PriceField,
+  /// The number of bars (e.g., days) to average the price over. VWAPs
+  /// are weighted by volume.
+  #[structopt(long, default_value = "1")]
+  pub price_window: usize,
+  /// The corporate actions to adjust historical prices for (one of:
+  /// raw, split, dividend, all). Price databases used together with
+  /// explicitly booked splits should use raw or split-only adjusted
+  /// prices.
+  #[structopt(long, default_value = "all")]
+  pub adjustment: Adjustment,
+  /// A source of prices to fall back to, in the order provided, for
+  /// symbols Alpaca has no bars for (e.g., delisted or OTC securities).
+  /// Either a directory containing `<symbol>.csv` files with lines of
+  /// the form `<yyyy-mm-dd>,<price>` or an `http(s)://` URL template, in
+  /// which `{symbol}` and `{date}` get replaced, pointing to a JSON
+  /// object of the form `{"price": ..., "date": ...}`.
+  #[structopt(long)]
+  pub fallback: Vec<PriceSource>,
+}
+
+
+/// The corporate actions historical prices are adjusted for.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Adjustment {
+  /// No adjustment.
+  Raw,
+  /// Adjustment for splits.
+  Split,
+  /// Adjustment for dividends.
+  Dividend,
+  /// Adjustment for all corporate actions.
+  #[default]
+  All,
+}
+
+impl FromStr for Adjustment {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "raw" => Ok(Self::Raw),
+      "split" => Ok(Self::Split),
+      "dividend" => Ok(Self::Dividend),
+      "all" => Ok(Self::All),
+      _ => Err(format!("invalid adjustment: {string}")),
+    }
+  }
+}
+
+
+/// The bar field used as price.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PriceField {
+  /// The closing price.
+  #[default]
+  Close,
+  /// The volume weighted average price.
+  Vwap,
+}
+
+impl FromStr for PriceField {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "close" => Ok(Self::Close),
+      "vwap" => Ok(Self::Vwap),
+      _ => Err(format!("invalid price field: {string}")),
+    }
+  }
+}
+
+
+/// The time frame of bars used for retrieving intraday prices.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimeFrame {
+  /// One bar per minute.
+  #[default]
+  Minute,
+  /// One bar per hour.
+  Hour,
+}
+
+impl FromStr for TimeFrame {
+  type Err = String;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    match string {
+      "minute" => Ok(Self::Minute),
+      "hour" => Ok(Self::Hour),
+      _ => Err(format!("invalid time frame: {string}")),
+    }
+  }
+}
+
+
+/// Parse a time of day in the format hh:mm.
+fn parse_time(string: &str) -> Result<NaiveTime, String> {
+  NaiveTime::parse_from_str(string, "%H:%M").map_err(|err| format!("invalid time {string}: {err}"))
+}
+
+
+/// A sub-command for managing the registry.
+#[derive(Debug, StructOpt)]
+pub enum RegistryCommand {
+  /// Create or complete a registry with all symbols found in the
+  /// account's activities and current positions.
+  Init(RegistryInit),
+  /// Report symbols that are missing from a registry as well as
+  /// registry entries that are never used.
+  Check(RegistryCheck),
+}
+
+
+/// Create or complete a registry.
+#[derive(Debug, StructOpt)]
+pub struct RegistryInit {
+  /// The path to the JSON registry to write. Entries already present
+  /// in it are preserved.
+  pub registry: PathBuf,
+}
+
+
+/// Check a registry against the account's history.
+#[derive(Debug, StructOpt)]
+pub struct RegistryCheck {
+  /// The path to the JSON registry to check.
+  pub registry: PathBuf,
+}
+
+
+/// Export sales for IRS Form 8949.
+///
+/// Shares are matched on a first in, first out basis, with fees
+/// included in cost basis and proceeds. Corporate actions such as
+/// splits are not accounted for and short sales are skipped.
+#[derive(Debug, StructOpt)]
+pub struct Form8949 {
+  /// Only report sales made in the given year.
+  #[structopt(long)]
+  pub year: Option<i32>,
+  /// Report the holding period of each sale in days, along with
+  /// whether it counts as short- or long-term, as two additional
+  /// columns.
+  #[structopt(long)]
+  pub holding_periods: bool,
+  /// The number of days shares have to be held for, at the least, for
+  /// their sale to count as long-term when reporting holding periods.
+  /// Sales of shares held for longer are reported as long-term, all
+  /// others as short-term.
+  #[structopt(long, default_value = "365")]
+  pub long_term_days: i64,
+}
+
+
+/// Print market days.
+#[derive(Debug, StructOpt)]
+pub struct Calendar {
+  /// The first day to report or, if not specified, today's date
+  /// (format: yyyy-mm-dd).
+  #[structopt(long, default_value)]
+  pub begin: Date,
+  /// The last day to report or, if not specified, the day 30 days
+  /// after the first one (format: yyyy-mm-dd).
+  #[structopt(long)]
+  pub end: Option<Date>,
+  /// Print the market days as JSON instead of plain text.
+  #[structopt(long)]
+  pub json: bool,
+}
+
+
+/// Print the account's daily equity.
+#[derive(Debug, StructOpt)]
+pub struct History {
+  /// The first day to report or, if not specified, the day 30 days
+  /// before the last one (format: yyyy-mm-dd).
+  #[structopt(long)]
+  pub begin: Option<Date>,
+  /// The last day to report or, if not specified, today's date
+  /// (format: yyyy-mm-dd).
+  #[structopt(long, default_value)]
+  pub end: Date,
+  /// Print Ledger price directives (`P`) for the `--commodity`
+  /// pseudo-commodity instead of CSV.
+  #[structopt(long)]
+  pub ledger: bool,
+  /// The name of the pseudo-commodity representing the account's
+  /// equity in price directives.
+  #[structopt(long, default_value = "NETWORTH")]
+  pub commodity: String,
+}
+
+
+/// Print an opening balance transaction.
+///
+/// The cost basis of positions is derived from the average entry price
+/// Alpaca reports, not from individual lots.
+#[derive(Debug, StructOpt)]
+pub struct OpeningBalances {
+  /// The date of the transaction or, if not specified, today's date
+  /// (format: yyyy-mm-dd).
+  #[structopt(long, default_value)]
+  pub date: Date,
+  /// The name of the investment account, i.e., the one holding the
+  /// shares [default: Assets:Investments:Alpaca:Stock].
+  #[structopt(long)]
+  pub investment_account: Option<String>,
+  /// Book shares against a per-symbol sub-account of the investment
+  /// account (e.g., `Assets:Investments:Alpaca:Stock:AAPL`), as the
+  /// `activity` sub-command's option of the same name does.
+  #[structopt(long)]
+  pub per_symbol_accounts: bool,
+  /// The name of the brokerage account, i.e., the one holding any
+  /// uninvested cash [default: Assets:Alpaca Brokerage].
+  #[structopt(long)]
+  pub brokerage_account: Option<String>,
+  /// The name of the account to balance the transaction against.
+  #[structopt(long, default_value = DEFAULT_OPENING_BALANCES_ACCOUNT)]
+  pub equity_account: String,
+}
+
+
+/// Export account activity, update prices, and reconcile positions.
+#[derive(Debug, StructOpt)]
+pub struct Sync {
+  #[structopt(flatten)]
+  pub activity: Activity,
+  /// Append the current prices of all held assets to the provided
+  /// Ledger price database.
+  #[structopt(long)]
+  pub update_prices: Option<PathBuf>,
+  /// (Over)write the provided file with a transaction asserting the
+  /// balances of all held positions and cash as reported by Alpaca,
+  /// for inclusion after the journal.
+  #[structopt(long)]
+  pub reconcile: Option<PathBuf>,
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we can resolve the various begin dates.
+  #[test]
+  fn resolve_begin() {
+    let today = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+    let last_run = NaiveDate::from_ymd_opt(2024, 1, 17).unwrap();
+    let resolve = |begin: &str, last_run| Begin::from_str(begin).unwrap().resolve(today, last_run);
+
+    assert_eq!(
+      resolve("2023-05-06", None),
+      NaiveDate::from_ymd_opt(2023, 5, 6)
+    );
+    assert_eq!(resolve("ytd", None), NaiveDate::from_ymd_opt(2024, 1, 1));
+    assert_eq!(resolve("mtd", None), NaiveDate::from_ymd_opt(2024, 2, 1));
+    assert_eq!(resolve("1y", None), NaiveDate::from_ymd_opt(2023, 2, 28));
+    assert_eq!(resolve("last-run", Some(last_run)), Some(last_run));
+    assert_eq!(resolve("last-run", None), None);
+    assert!(Begin::from_str("2y").is_err());
+  }
+
+  /// Check that booking dividends at the ex-dividend date is rejected
+  /// explicitly.
+  #[test]
+  fn parse_dividend_date() {
+    assert_eq!(DividendDate::from_str("pay"), Ok(DividendDate::Pay));
+    assert_eq!(DividendDate::from_str("record"), Ok(DividendDate::Record));
+    let err = DividendDate::from_str("ex").unwrap_err();
+    assert!(err.contains("not supported"), "{err}");
+  }
 }
@@ -0,0 +1,155 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::ffi::OsString;
+use std::fs::copy;
+use std::fs::rename;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+
+/// Create a path by appending a suffix to the given one.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut path = OsString::from(path);
+  let () = path.push(suffix);
+  PathBuf::from(path)
+}
+
+
+/// A file that is written to a temporary location first and only
+/// atomically replaces its destination once committed, so that an
+/// aborted run can never leave a truncated file behind.
+#[derive(Debug)]
+pub struct AtomicFile {
+  /// The destination path.
+  path: PathBuf,
+  /// The temporary path written to.
+  tmp: PathBuf,
+  /// The temporary file.
+  file: BufWriter<File>,
+}
+
+impl AtomicFile {
+  /// Create an atomic file for the given destination path. If `append`
+  /// is set, the contents of an existing file are preserved and
+  /// appended to.
+  pub fn create(path: &Path, append: bool) -> Result<Self> {
+    let tmp = with_suffix(path, ".tmp");
+    let copied = if append {
+      match copy(path, &tmp) {
+        Ok(_) => true,
+        Err(err) if err.kind() == ErrorKind::NotFound => false,
+        Err(err) => {
+          return Err(err)
+            .with_context(|| format!("failed to copy {} to {}", path.display(), tmp.display()))
+        },
+      }
+    } else {
+      false
+    };
+
+    let file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .append(copied)
+      .truncate(!copied)
+      .open(&tmp)
+      .with_context(|| format!("failed to open {}", tmp.display()))?;
+
+    Ok(Self {
+      path: path.to_path_buf(),
+      tmp,
+      file: BufWriter::new(file),
+    })
+  }
+
+  /// Retrieve the destination path.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Replace the destination with everything written so far,
+  /// optionally keeping a copy of the previous version with a `.bak`
+  /// suffix.
+  pub fn commit(self, backup: bool) -> Result<()> {
+    let Self { path, tmp, file } = self;
+    let file = file
+      .into_inner()
+      .map_err(|err| err.into_error())
+      .with_context(|| format!("failed to flush {}", tmp.display()))?;
+    let () = file
+      .sync_all()
+      .with_context(|| format!("failed to sync {}", tmp.display()))?;
+
+    if backup {
+      let bak = with_suffix(&path, ".bak");
+      match copy(&path, &bak) {
+        Ok(_) => (),
+        Err(err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => {
+          return Err(err)
+            .with_context(|| format!("failed to back up {} to {}", path.display(), bak.display()))
+        },
+      }
+    }
+
+    let () = rename(&tmp, &path)
+      .with_context(|| format!("failed to rename {} to {}", tmp.display(), path.display()))?;
+    Ok(())
+  }
+}
+
+impl Write for AtomicFile {
+  fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+    self.file.write(buf)
+  }
+
+  fn flush(&mut self) -> IoResult<()> {
+    self.file.flush()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env::temp_dir;
+  use std::fs::create_dir_all;
+  use std::fs::read_to_string;
+  use std::fs::write;
+  use std::process;
+
+
+  /// Check that the destination only changes once the file is
+  /// committed.
+  #[test]
+  fn atomic_replace() {
+    let dir = temp_dir().join(format!("apcaledge-atomic-{}", process::id()));
+    let () = create_dir_all(&dir).unwrap();
+    let path = dir.join("test.journal");
+    let () = write(&path, "old\n").unwrap();
+
+    let mut file = AtomicFile::create(&path, true).unwrap();
+    let () = writeln!(file, "new").unwrap();
+    assert_eq!(read_to_string(&path).unwrap(), "old\n");
+
+    let () = file.commit(true).unwrap();
+    assert_eq!(read_to_string(&path).unwrap(), "old\nnew\n");
+    assert_eq!(read_to_string(with_suffix(&path, ".bak")).unwrap(), "old\n");
+    assert!(!with_suffix(&path, ".tmp").exists());
+
+    let file = AtomicFile::create(&path, false).unwrap();
+    let () = file.commit(false).unwrap();
+    assert_eq!(read_to_string(&path).unwrap(), "");
+  }
+}
@@ -0,0 +1,105 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead as _;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Write as _;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use apca::api::v2::account_activities::Activity;
+
+use chrono::NaiveDate;
+
+use serde_json::from_str as from_json;
+use serde_json::to_string as to_json;
+
+use crate::endpoints::StatusActivity;
+use crate::json;
+
+
+/// A persistent cache of account activities, stored as one JSON
+/// encoded activity per line in ascending order.
+///
+/// Account activities are immutable once reported, so the cache only
+/// ever gets appended to.
+pub struct Cache {
+  /// The path to the cache file.
+  path: PathBuf,
+  /// The cache file, opened for appending.
+  file: File,
+}
+
+impl Cache {
+  /// Open the cache at the given path, returning it along with all
+  /// activities it contains.
+  ///
+  /// Activities dated after `refresh_after` are dropped from the cache,
+  /// causing them to be retrieved again.
+  pub fn open(path: &Path, refresh_after: Option<NaiveDate>) -> Result<(Self, Vec<Activity>)> {
+    let mut activities = match File::open(path) {
+      Ok(file) => BufReader::new(file)
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+          let line = line.with_context(|| format!("failed to read {}", path.display()))?;
+          from_json::<StatusActivity>(&line)
+            .map(|activity| activity.activity)
+            .with_context(|| {
+              format!(
+                "failed to parse cached activity at {}:{}",
+                path.display(),
+                idx + 1
+              )
+            })
+        })
+        .collect::<Result<Vec<_>>>()?,
+      Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+      Err(err) => {
+        return Err(err).with_context(|| format!("failed to open cache {}", path.display()))
+      },
+    };
+
+    let count = activities.len();
+    if let Some(date) = refresh_after {
+      activities.retain(|activity| activity.time().date_naive() <= date);
+    }
+
+    let mut cache = Self {
+      path: path.to_path_buf(),
+      file: OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open cache {}", path.display()))?,
+    };
+
+    if activities.len() != count {
+      // Some activities are to be refreshed; rewrite the cache without
+      // them.
+      let () = cache
+        .file
+        .set_len(0)
+        .with_context(|| format!("failed to truncate cache {}", path.display()))?;
+      let () = cache.append(&activities)?;
+    }
+    Ok((cache, activities))
+  }
+
+  /// Append the provided activities to the cache.
+  pub fn append(&mut self, activities: &[Activity]) -> Result<()> {
+    for activity in activities {
+      let json =
+        to_json(&json::activity(activity)).context("failed to serialize account activity")?;
+      let () = writeln!(self.file, "{json}")
+        .with_context(|| format!("failed to write to cache {}", self.path.display()))?;
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,119 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde::Deserialize;
+use serde_json::from_reader as json_from_reader;
+
+use crate::input;
+
+
+/// The program's configuration, as read from a JSON file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+  /// Named profiles, keyed by their name.
+  #[serde(default)]
+  pub profile: HashMap<String, Profile>,
+}
+
+impl Config {
+  /// Load the configuration from the JSON file at the given path (see
+  /// `input::open` for the supported kinds of paths).
+  pub fn load(path: &Path) -> Result<Self> {
+    let file = input::open(path)
+      .with_context(|| format!("failed to open configuration file {}", path.display()))?;
+    let config = json_from_reader::<_, Self>(file)
+      .with_context(|| format!("failed to read configuration {}", path.display()))?;
+    Ok(config)
+  }
+
+  /// Look up the profile with the given name.
+  pub fn profile(&self, name: &str) -> Result<&Profile> {
+    self
+      .profile
+      .get(name)
+      .with_context(|| format!("profile {} not present in configuration", name))
+  }
+}
+
+
+/// A profile, bundling credentials and account names for one Alpaca
+/// account.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+  /// The base URL of the Alpaca API to use.
+  pub api_base_url: Option<String>,
+  /// The Alpaca API key ID.
+  pub key_id: Option<String>,
+  /// The Alpaca API secret.
+  pub secret: Option<String>,
+  /// A shell command printing the Alpaca API key ID, e.g., to retrieve
+  /// it from the system keyring or an encrypted file.
+  pub key_id_command: Option<String>,
+  /// A shell command printing the Alpaca API secret.
+  pub secret_command: Option<String>,
+  /// See `args::Activity::investment_account`.
+  pub investment_account: Option<String>,
+  /// See `args::Activity::brokerage_account`.
+  pub brokerage_account: Option<String>,
+  /// See `args::Activity::brokerage_fee_account`.
+  pub brokerage_fee_account: Option<String>,
+  /// See `args::Activity::dividend_account`.
+  pub dividend_account: Option<String>,
+  /// See `args::Activity::sec_fee_account`.
+  pub sec_fee_account: Option<String>,
+  /// See `args::Activity::finra_taf_account`.
+  pub finra_taf_account: Option<String>,
+}
+
+impl Profile {
+  /// Retrieve the API key ID, if configured.
+  pub fn key_id(&self) -> Result<Option<String>> {
+    resolve(&self.key_id, &self.key_id_command)
+  }
+
+  /// Retrieve the API secret, if configured.
+  pub fn secret(&self) -> Result<Option<String>> {
+    resolve(&self.secret, &self.secret_command)
+  }
+}
+
+
+/// Resolve a credential, either using the literal value or by running
+/// the provided command and capturing its output.
+fn resolve(value: &Option<String>, command: &Option<String>) -> Result<Option<String>> {
+  match (value, command) {
+    (Some(value), _) => Ok(Some(value.clone())),
+    (None, Some(command)) => {
+      let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        // Let the command interact with the user, e.g., to ask for a
+        // passphrase.
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to run credential command `{}`", command))?;
+      ensure!(
+        output.status.success(),
+        "credential command `{}` failed: {}",
+        command,
+        output.status
+      );
+      let value = String::from_utf8(output.stdout)
+        .with_context(|| format!("credential command `{}` emitted invalid UTF-8", command))?;
+      Ok(Some(value.trim_end_matches(&['\r', '\n'][..]).to_string()))
+    },
+    (None, None) => Ok(None),
+  }
+}
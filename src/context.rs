@@ -0,0 +1,325 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use chrono::NaiveDate;
+
+use chrono_tz::Tz;
+
+use num_decimal::Num;
+
+use regex::Regex;
+
+use serde::de::Error as _;
+use serde::Deserialize;
+use serde::Deserializer;
+
+use crate::args::Comments;
+use crate::args::DividendDate;
+use crate::args::Strategy;
+use crate::filter::Filter;
+use crate::ledger::Declarations;
+use crate::ledger::References;
+use crate::ledger::Transaction;
+use crate::notify::Summary;
+use crate::registry::Registry;
+use crate::warnings::Kind as WarningKind;
+use crate::warnings::Warning;
+
+
+/// The names of the accounts that activities get booked against.
+#[derive(Debug)]
+pub struct Accounts {
+  /// The account holding the shares.
+  pub investment: String,
+  /// The account holding any uninvested cash.
+  pub brokerage: String,
+  /// The account for fees charged by the brokerage.
+  pub brokerage_fee: String,
+  /// The account for (qualified) dividends.
+  pub dividend: String,
+  /// The account for non-qualified dividends.
+  pub non_qualified_dividend: String,
+  /// The account for regulatory fees by the SEC.
+  pub sec_fee: String,
+  /// The account for FINRA trade activity fees.
+  pub finra_taf: String,
+  /// The account for reorganization fees.
+  pub reorg_fee: String,
+  /// The account for fees charged for ACH or wire transfers.
+  pub transfer_fee: String,
+  /// The account for the premium of expired option contracts.
+  pub option_premium: String,
+  /// The account for cash from reorganizations.
+  pub reorg: String,
+  /// The account for capital gains, such as cash in lieu.
+  pub capital_gains: String,
+  /// The account holding cash swept into a cash sweep program.
+  pub sweep: String,
+  /// The external wallet crypto coins get transferred from and to.
+  pub crypto_wallet: String,
+  /// The account for network fees paid for crypto transfers.
+  pub network_fee: String,
+  /// The account for rewards credited in crypto coins.
+  pub crypto_reward: String,
+  /// The account for interest payments.
+  pub interest: String,
+  /// The account for interest paid on cash in the high-yield cash
+  /// program.
+  pub cash_program_interest: String,
+}
+
+impl Accounts {
+  /// Retrieve the names of all accounts.
+  pub fn all(&self) -> impl Iterator<Item = &str> {
+    [
+      &self.investment,
+      &self.brokerage,
+      &self.brokerage_fee,
+      &self.dividend,
+      &self.non_qualified_dividend,
+      &self.sec_fee,
+      &self.finra_taf,
+      &self.reorg_fee,
+      &self.transfer_fee,
+      &self.option_premium,
+      &self.reorg,
+      &self.capital_gains,
+      &self.sweep,
+      &self.crypto_wallet,
+      &self.network_fee,
+      &self.crypto_reward,
+      &self.interest,
+      &self.cash_program_interest,
+    ]
+    .into_iter()
+    .map(String::as_str)
+  }
+}
+
+
+/// Accounts to additionally book cash transfers against, using
+/// (unbalanced) virtual postings.
+///
+/// This allows for integrating with envelope-budgeting setups, e.g., by
+/// recording deposits against `Budget:Investing`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualPostings {
+  /// The account to book cash deposits against.
+  #[serde(default)]
+  pub deposit: Option<String>,
+  /// The account to book cash withdrawals against.
+  #[serde(default)]
+  pub withdrawal: Option<String>,
+}
+
+impl VirtualPostings {
+  /// Retrieve the names of all accounts.
+  pub fn all(&self) -> impl Iterator<Item = &str> {
+    self
+      .deposit
+      .iter()
+      .chain(&self.withdrawal)
+      .map(String::as_str)
+  }
+}
+
+
+/// Deserialize an optional regular expression.
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  Option::<String>::deserialize(deserializer)?
+    .map(|pattern| Regex::new(&pattern).map_err(D::Error::custom))
+    .transpose()
+}
+
+
+/// A rule for determining the account on the other side of a cash
+/// deposit or withdrawal, e.g., the bank account the money came from.
+///
+/// A rule matches an activity if all of its conditions are met.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransferRule {
+  /// A regular expression the activity's description (e.g., the ACH
+  /// memo) has to match.
+  #[serde(default, deserialize_with = "deserialize_regex")]
+  pub description: Option<Regex>,
+  /// The amount the activity has to be for, negative for withdrawals.
+  #[serde(default)]
+  pub amount: Option<Num>,
+  /// The account to book the transfer against.
+  pub account: String,
+}
+
+impl TransferRule {
+  /// Check whether the rule matches an activity with the given
+  /// description and amount.
+  pub fn matches(&self, description: Option<&str>, amount: &Num) -> bool {
+    let description_matches = match (&self.description, description) {
+      (Some(regex), Some(description)) => regex.is_match(description),
+      (Some(_), None) => false,
+      (None, _) => true,
+    };
+    let amount_matches = match &self.amount {
+      Some(value) => value == amount,
+      None => true,
+    };
+    description_matches && amount_matches
+  }
+}
+
+
+/// State shared by everything involved in formatting account
+/// activities.
+#[derive(Debug)]
+pub struct Context {
+  /// The accounts to book activities against.
+  pub accounts: Accounts,
+  /// The registry for looking up names from symbols.
+  pub registry: Registry,
+  /// The map of payee aliases.
+  pub payees: HashMap<String, String>,
+  /// Translations of generated payees and comments.
+  pub translations: HashMap<String, String>,
+  /// The receivable account to accrue interest in, if interest is to be
+  /// booked on an accrual basis.
+  pub interest_receivable: Option<String>,
+  /// The interest accruals booked so far, to be written out separately
+  /// once all activities have been processed.
+  pub accruals: RefCell<Vec<Transaction>>,
+  /// Additional virtual postings to emit for cash transfers.
+  pub virtual_postings: VirtualPostings,
+  /// The rules for determining the counter accounts of cash deposits
+  /// and withdrawals, in order of precedence.
+  pub transfer_rules: Vec<TransferRule>,
+  /// Whether to tag cash deposits and withdrawals with a key for
+  /// pairing them up with the other leg of the transfer.
+  pub transfer_tags: bool,
+  /// The currency of the Alpaca account.
+  pub currency: String,
+  /// Mappings from client order ID prefixes to strategies.
+  pub strategies: Vec<Strategy>,
+  /// Transaction codes to emit, keyed by upper case activity kind.
+  pub transaction_codes: HashMap<String, String>,
+  /// Whether to book trades against per-strategy sub-accounts.
+  pub strategy_accounts: bool,
+  /// Whether to book shares against per-symbol investment sub-accounts.
+  pub per_symbol_accounts: bool,
+  /// Whether to book ADR fees against per-symbol sub-accounts of the
+  /// brokerage fee account.
+  pub per_symbol_adr_fee_accounts: bool,
+  /// Whether to annotate trades with order details.
+  pub order_details: bool,
+  /// Whether to tag trade postings with the time of the fill.
+  pub fill_times: bool,
+  /// Whether return of capital distributions reduce cost basis.
+  pub roc_reduces_basis: bool,
+  /// Which comments to emit for activities.
+  pub comments: Comments,
+  /// Whether to tag trade postings with security identifiers.
+  pub security_ids: bool,
+  /// Whether to summarize the total fees charged for a trade.
+  pub fee_totals: bool,
+  /// The time zone to derive the dates of trades in.
+  pub timezone: Tz,
+  /// The current date in the time zone, determined once so that all
+  /// parts of a run agree on it.
+  pub today: NaiveDate,
+  /// The date to book dividends at.
+  pub dividend_date: DividendDate,
+  /// Whether to annotate transactions with classification decisions.
+  pub explain: bool,
+  /// Whether to render transactions with colors, for previewing them.
+  pub preview: bool,
+  /// Whether to amortize the premium of sold option contracts and book
+  /// it as income on expiration.
+  pub option_premium: bool,
+  /// The quantities of option contracts sold so far along with the
+  /// total premium received for them, by symbol.
+  pub premiums: RefCell<HashMap<String, (Num, Num)>>,
+  /// The IDs of the activities to emit transactions for. If empty, all
+  /// activities are emitted.
+  pub ids: HashSet<String>,
+  /// The pattern activity descriptions or symbols have to match to be
+  /// emitted, if any.
+  pub filter_description: Option<Regex>,
+  /// The pattern activity descriptions or symbols must not match to be
+  /// emitted, if any.
+  pub exclude_description: Option<Regex>,
+  /// The filter to pass generated transactions through, if any.
+  pub filter: Option<Filter>,
+  /// The declarations emitted so far, if declarations are to be emitted
+  /// ahead of their first use.
+  pub declarations: RefCell<Option<Declarations>>,
+  /// The accounts and commodities referenced by the transactions
+  /// emitted so far.
+  pub references: RefCell<References>,
+  /// Whether to emit activities that can't be represented as comments.
+  pub emit_unknown: bool,
+  /// Whether to fail on activities that can't be represented.
+  pub strict: bool,
+  /// Descriptions of the activities that could not be represented, if
+  /// running in strict mode.
+  pub ignored: RefCell<Vec<String>>,
+  /// The running cash balance, if it is being tracked.
+  pub cash_balance: RefCell<Option<Num>>,
+  /// A summary of the activities processed so far.
+  pub summary: RefCell<Summary>,
+  /// The warnings encountered so far.
+  pub warnings: RefCell<Vec<Warning>>,
+}
+
+impl Context {
+  /// Record a warning, optionally about the activity with the given ID,
+  /// for reporting it once done.
+  pub fn record_warning(&self, kind: WarningKind, activity: Option<&str>, message: String) {
+    let warning = Warning {
+      kind,
+      activity: activity.map(String::from),
+      message,
+    };
+    let () = self.warnings.borrow_mut().push(warning);
+  }
+
+  /// Retrieve the investment account to book shares of the asset with
+  /// the given symbol against, optionally on behalf of a strategy.
+  pub fn investment_account(&self, symbol: &str, strategy: Option<&Strategy>) -> Cow<'_, str> {
+    let mut account = Cow::from(self.accounts.investment.as_str());
+    if let Some(strategy) = strategy.filter(|_| self.strategy_accounts) {
+      account = Cow::from(format!("{account}:{}", strategy.name));
+    }
+    if self.per_symbol_accounts {
+      account = Cow::from(format!("{account}:{symbol}"));
+    }
+    account
+  }
+
+  /// Retrieve the names of all accounts activities may get booked
+  /// against.
+  pub fn all_accounts(&self) -> BTreeSet<String> {
+    let strategy_accounts = self
+      .strategies
+      .iter()
+      .filter(|_| self.strategy_accounts)
+      .map(|strategy| format!("{}:{}", self.accounts.investment, strategy.name));
+
+    self
+      .accounts
+      .all()
+      .chain(self.virtual_postings.all())
+      .chain(self.transfer_rules.iter().map(|rule| rule.account.as_str()))
+      .chain(self.interest_receivable.as_deref())
+      .map(String::from)
+      .chain(strategy_accounts)
+      .collect()
+  }
+}
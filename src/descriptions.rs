@@ -0,0 +1,292 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Parsing of the free-form descriptions Alpaca attaches to non-trade
+//! activities.
+//!
+//! Alpaca occasionally tweaks the wording of descriptions, so patterns
+//! are kept deliberately tolerant: matching is case-insensitive,
+//! singular and plural forms are accepted where we have seen both, and
+//! amounts may contain thousands separators.
+
+use std::str::FromStr as _;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use chrono::Month;
+use chrono::NaiveDate;
+
+use num_decimal::Num;
+
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
+
+/// The pattern for an amount, which may contain thousands separators
+/// and may or may not have a fractional part.
+const AMOUNT: &str = r"\d[\d,]*(\.\d+)?";
+
+static TAF_RE: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(&format!(
+    r"(?i)\bTAF fees? for proceeds? of (?P<shares>{AMOUNT}) shares?\b"
+  ))
+  .unwrap()
+});
+static REG_RE: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(&format!(
+    r"(?i)\bREG fees? for proceeds? of \$?(?P<proceeds>{AMOUNT})"
+  ))
+  .unwrap()
+});
+static ADR_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"^(?i:ADR Fees?)(\s+(?P<symbol>[A-Z][A-Z.]*)\b)?").unwrap());
+static REORG_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"^(?i:(Mandatory|Voluntary) Reorg Fees?)\b").unwrap());
+static TRANSFER_FEE_RE: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(r"(?i)\b(wire|ACH)\b.*\b(fee|charge)s?\b|\b(fee|charge)s?\b.*\b(wire|ACH)\b").unwrap()
+});
+static CIL_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^(CIL\b|cash in lieu)").unwrap());
+static REC_DATE_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"(?i)Rec(ord)? Date: (?P<date>\d{4}-\d{2}-\d{2})").unwrap());
+static NON_QUALIFIED_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"(?i)\bnon[- ]?qualified\b").unwrap());
+static SWEEP_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)\bsweep\b").unwrap());
+static REWARD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)\b(staking|rewards?)\b").unwrap());
+static CASH_PROGRAM_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"(?i)\b(alpaca cash|high[- ]yield)\b").unwrap());
+static ROC_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"(?i)(\breturn of capital\b|\bROC\b)").unwrap());
+static INTEREST_MONTH_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"(?i)\binterest for (?P<month>[a-z]+)\b").unwrap());
+static ACQ_PRICE_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(&format!(r"(?i)\bCash Merger \$?(?P<price>{AMOUNT})")).unwrap());
+
+
+/// Parse an amount as contained in a description, e.g., `1,234.56`,
+/// ignoring any thousands separators.
+fn parse_amount(amount: &str) -> Result<Num> {
+  let normalized = amount.replace(',', "");
+  Num::from_str(&normalized)
+    .with_context(|| format!("failed to parse amount string '{}' as number", amount))
+}
+
+
+/// The kind of fee a fee activity represents.
+#[derive(Debug, PartialEq)]
+pub enum Fee {
+  /// A FINRA trading activity fee for the sale of the given number of
+  /// shares.
+  Taf { shares: Num },
+  /// An SEC regulatory fee for a sale with the given proceeds.
+  Reg { proceeds: Num },
+  /// A depositary receipt custody fee, optionally naming the symbol of
+  /// the holding it was charged for.
+  Adr { symbol: Option<String> },
+  /// A fee for a reorganization.
+  Reorg,
+  /// A fee for an ACH or wire transfer.
+  Transfer,
+}
+
+/// Parse the description of a fee activity, returning `None` if it is
+/// not in any of the known formats.
+pub fn parse_fee(description: &str) -> Option<Fee> {
+  if let Some(captures) = TAF_RE.captures(description) {
+    let shares = parse_amount(&captures["shares"]).ok()?;
+    Some(Fee::Taf { shares })
+  } else if let Some(captures) = REG_RE.captures(description) {
+    let proceeds = parse_amount(&captures["proceeds"]).ok()?;
+    Some(Fee::Reg { proceeds })
+  } else if let Some(captures) = ADR_RE.captures(description) {
+    let symbol = captures
+      .name("symbol")
+      .map(|symbol| symbol.as_str().to_string());
+    Some(Fee::Adr { symbol })
+  } else if REORG_RE.is_match(description) {
+    Some(Fee::Reorg)
+  } else if TRANSFER_FEE_RE.is_match(description) {
+    Some(Fee::Transfer)
+  } else {
+    None
+  }
+}
+
+
+/// Check whether a description denotes a cash-in-lieu payment.
+pub fn is_cash_in_lieu(description: &str) -> bool {
+  CIL_RE.is_match(description)
+}
+
+
+/// Check whether a description denotes a transfer from or to a cash
+/// sweep program.
+pub fn is_sweep(description: &str) -> bool {
+  SWEEP_RE.is_match(description)
+}
+
+
+/// Check whether a description denotes a reward credited in crypto
+/// coins, e.g., for staking.
+pub fn is_reward(description: &str) -> bool {
+  REWARD_RE.is_match(description)
+}
+
+
+/// Check whether an interest description denotes interest paid as part
+/// of the high-yield cash program.
+pub fn is_cash_program_interest(description: &str) -> bool {
+  CASH_PROGRAM_RE.is_match(description)
+}
+
+
+/// Check whether a dividend description denotes a return of capital.
+pub fn is_return_of_capital(description: &str) -> bool {
+  ROC_RE.is_match(description)
+}
+
+
+/// Check whether a dividend description denotes a non-qualified
+/// dividend.
+pub fn is_non_qualified(description: &str) -> bool {
+  NON_QUALIFIED_RE.is_match(description)
+}
+
+
+/// Extract the record date from a dividend description.
+pub fn record_date(description: &str) -> Option<NaiveDate> {
+  let captures = REC_DATE_RE.captures(description)?;
+  NaiveDate::from_str(&captures["date"]).ok()
+}
+
+
+/// Extract the month interest was paid for from an interest
+/// description, e.g., `Interest for June`.
+pub fn interest_month(description: &str) -> Option<Month> {
+  let captures = INTEREST_MONTH_RE.captures(description)?;
+  Month::from_str(&captures["month"]).ok()
+}
+
+
+/// Extract the per-share cash price from an acquisition description.
+pub fn acquisition_price(description: &str) -> Result<Num> {
+  let captures = ACQ_PRICE_RE
+    .captures(description)
+    .with_context(|| format!("no cash merger price found in '{}'", description))?;
+  parse_amount(&captures["price"])
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we parse fee descriptions as seen in the wild.
+  #[test]
+  fn parse_fee_descriptions() {
+    let corpus = [
+      (
+        "TAF fee for proceed of 56 shares (3 trades) on 2021-06-15 by 999999999",
+        Some(Fee::Taf {
+          shares: Num::from(56),
+        }),
+      ),
+      (
+        "TAF fee for proceed of 1,200 shares (1 trades) on 2021-06-17 by 999999999",
+        Some(Fee::Taf {
+          shares: Num::from(1200),
+        }),
+      ),
+      (
+        "TAF fee for proceeds of 0.5 shares (1 trade) on 2023-01-05 by 999999999",
+        Some(Fee::Taf {
+          shares: Num::new(1, 2),
+        }),
+      ),
+      (
+        "REG fee for proceed of $522.48 on 2021-06-15 by 999999999",
+        Some(Fee::Reg {
+          proceeds: Num::new(52248, 100),
+        }),
+      ),
+      (
+        "REG fee for proceed of $1,234.56 on 2021-06-15 by 999999999",
+        Some(Fee::Reg {
+          proceeds: Num::new(123456, 100),
+        }),
+      ),
+      (
+        "Reg Fee for proceeds of $100 on 2024-02-01 by 999999999",
+        Some(Fee::Reg {
+          proceeds: Num::from(100),
+        }),
+      ),
+      (
+        "ADR Fees EFG",
+        Some(Fee::Adr {
+          symbol: Some("EFG".to_string()),
+        }),
+      ),
+      ("ADR Fees", Some(Fee::Adr { symbol: None })),
+      ("Mandatory Reorg Fee XYZ", Some(Fee::Reorg)),
+      ("Voluntary reorg fee", Some(Fee::Reorg)),
+      ("Fee for reorg of XYZ wire transfer", Some(Fee::Transfer)),
+      ("Reorganization adjustment", None),
+      ("Outgoing Wire Fee", Some(Fee::Transfer)),
+      ("WIRE OUT FEE - REF 123456", Some(Fee::Transfer)),
+      ("ACH Return Charge", Some(Fee::Transfer)),
+      ("Fee for outgoing wire", Some(Fee::Transfer)),
+      ("Miscellaneous adjustment", None),
+    ];
+
+    for (description, expected) in corpus {
+      assert_eq!(parse_fee(description), expected, "{description}");
+    }
+  }
+
+  /// Check that we extract information from dividend and acquisition
+  /// descriptions.
+  #[test]
+  fn parse_other_descriptions() {
+    let description = "Cash DIV @ 0.17, Pos QTY: 11.0, Rec Date: 2021-05-20";
+    assert_eq!(
+      record_date(description),
+      NaiveDate::from_ymd_opt(2021, 5, 20)
+    );
+    assert!(!is_return_of_capital(description));
+    assert!(!is_non_qualified(description));
+
+    assert!(is_return_of_capital("Cash DIV @ 0.10 ROC"));
+    assert!(is_non_qualified("Non-Qualified Cash DIV @ 0.10"));
+    assert!(is_cash_in_lieu("CIL XYZ"));
+    assert!(is_cash_in_lieu("Cash in lieu of fractional shares"));
+    assert!(is_sweep("FDIC Sweep Out"));
+    assert!(is_sweep("Cash sweep in"));
+    assert!(!is_sweep("Sweepstakes"));
+    assert!(is_reward("Staking reward ETH"));
+    assert!(is_reward("Crypto Rewards"));
+    assert!(!is_reward("Rewarding stock split"));
+    assert!(is_cash_program_interest("Alpaca Cash interest for June"));
+    assert!(is_cash_program_interest("High-Yield Cash Interest"));
+    assert!(!is_cash_program_interest("Interest for June"));
+    assert_eq!(interest_month("Interest for June"), Some(Month::June));
+    assert_eq!(
+      interest_month("Alpaca Cash interest for dec"),
+      Some(Month::December)
+    );
+    assert_eq!(interest_month("Interest for the period"), None);
+    assert_eq!(interest_month("Interest adjustment"), None);
+
+    assert_eq!(
+      acquisition_price("Cash Merger $12.3456 per share").unwrap(),
+      Num::new(123456, 10000)
+    );
+    assert_eq!(
+      acquisition_price("Cash Merger $1,015").unwrap(),
+      Num::from(1015)
+    );
+    assert!(acquisition_price("Stock Merger 1:2").is_err());
+  }
+}
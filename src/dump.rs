@@ -0,0 +1,67 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::create_dir_all;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde::Serialize;
+use serde_json::to_writer_pretty as to_json_writer_pretty;
+
+
+/// A directory that API requests and the corresponding responses get
+/// dumped to, for debugging purposes.
+///
+/// Each exchange is written to a pair of files named
+/// `<sequence>-<name>-request.json` and `<sequence>-<name>-response.json`.
+/// Credentials are never part of requests, so the files are safe to
+/// share.
+pub struct Dump {
+  /// The directory to write files to.
+  dir: PathBuf,
+  /// The number of exchanges dumped so far.
+  count: usize,
+}
+
+impl Dump {
+  /// Create a dump writing to the given directory, creating it if it
+  /// does not exist.
+  pub fn new(dir: &Path) -> Result<Self> {
+    let () = create_dir_all(dir)
+      .with_context(|| format!("failed to create directory {}", dir.display()))?;
+
+    Ok(Self {
+      dir: dir.to_path_buf(),
+      count: 0,
+    })
+  }
+
+  /// Dump a request along with the response it produced.
+  pub fn write<Q, R>(&mut self, name: &str, request: &Q, response: &R) -> Result<()>
+  where
+    Q: Serialize,
+    R: Serialize,
+  {
+    self.count += 1;
+    let () = self.write_file(&format!("{:04}-{name}-request.json", self.count), request)?;
+    let () = self.write_file(&format!("{:04}-{name}-response.json", self.count), response)?;
+    Ok(())
+  }
+
+  /// Write a value as JSON to the file with the given name.
+  fn write_file<T>(&self, name: &str, value: &T) -> Result<()>
+  where
+    T: Serialize,
+  {
+    let path = self.dir.join(name);
+    let file =
+      File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+    let () = to_json_writer_pretty(file, value)
+      .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+  }
+}
@@ -0,0 +1,466 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Definitions of Alpaca API endpoints, or parts of their responses,
+//! that apca does not cover.
+
+use std::collections::HashMap;
+
+use apca::api::v2::account_activities::Activity;
+use apca::api::v2::account_activities::ActivityReq;
+use apca::api::v2::account_activities::ActivityType;
+use apca::api::v2::account_activities::Get as GetActivitiesApca;
+use apca::api::v2::account_activities::NonTradeActivity;
+use apca::ApiError;
+
+use chrono::DateTime;
+use chrono::NaiveDate;
+use chrono::SecondsFormat;
+use chrono::TimeZone as _;
+use chrono::Utc;
+use chrono_tz::America::New_York;
+
+use http_endpoint::EndpointDef;
+use http_endpoint::Str;
+
+use num_decimal::Num;
+
+use serde::de::Error as _;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde_json::from_slice as json_from_slice;
+use serde_json::Error as JsonError;
+use serde_json::Value;
+
+use url::form_urlencoded::Serializer;
+
+
+/// The base URL of Alpaca's market data API.
+const DATA_BASE_URL: &str = "https://data.alpaca.markets";
+
+
+/// The name of an asset, which apca's `Asset` type does not expose.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AssetName {
+  /// The asset's name, e.g., the company name for stocks.
+  #[serde(rename = "name")]
+  pub name: String,
+}
+
+
+EndpointDef! {
+  /// A GET request to the /v2/assets/{symbol} endpoint, retrieving the
+  /// name of the asset with the given symbol.
+  pub GetAssetName(String),
+  Ok => AssetName, [
+    /// The asset was retrieved successfully.
+    /* 200 */ OK,
+  ],
+  Err => GetAssetNameError, [
+    /// The request was not permitted, e.g., because of invalid
+    /// credentials.
+    /* 403 */ FORBIDDEN => NotPermitted,
+    /// No asset was found for the given symbol.
+    /* 404 */ NOT_FOUND => NotFound,
+    /// The rate limit was exceeded, causing the request to be denied.
+    /* 429 */ TOO_MANY_REQUESTS => RateLimitExceeded,
+  ],
+  ConversionErr => JsonError,
+  ApiErr => ApiError,
+
+  fn path(input: &Self::Input) -> Str {
+    format!("/v2/assets/{input}").into()
+  }
+
+  fn parse(body: &[u8]) -> Result<Self::Output, Self::ConversionError> {
+    json_from_slice(body)
+  }
+
+  fn parse_err(body: &[u8]) -> Result<Self::ApiError, Vec<u8>> {
+    json_from_slice(body).map_err(|_| body.to_vec())
+  }
+}
+
+
+/// An account activity along with its status (e.g., `executed`), which
+/// apca's `Activity` does not expose. Only non-trade activities have
+/// one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatusActivity {
+  /// The activity.
+  pub activity: Activity,
+  /// The activity's status, if any.
+  pub status: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for StatusActivity {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let value = Value::deserialize(deserializer)?;
+    let status = value
+      .get("status")
+      .and_then(Value::as_str)
+      .map(str::to_string);
+    let network_fee = value
+      .get("network_fee")
+      .cloned()
+      .map(Num::deserialize)
+      .transpose()
+      .map_err(D::Error::custom)?;
+    let mut activity = Activity::deserialize(value).map_err(D::Error::custom)?;
+    if let Activity::NonTrade(non_trade) = &mut activity {
+      if is_crypto_transfer(non_trade) {
+        non_trade.price = network_fee;
+      }
+    }
+    Ok(Self { activity, status })
+  }
+}
+
+
+/// Check whether a non-trade activity is a transfer of crypto coins,
+/// as opposed to one of cash.
+///
+/// Crypto transfers are reported as deposits and withdrawals carrying
+/// the coin's symbol and the quantity transferred. Their network fee,
+/// which apca's `NonTradeActivity` has no field for, is carried in the
+/// otherwise unused `price`.
+pub fn is_crypto_transfer(non_trade: &NonTradeActivity) -> bool {
+  matches!(
+    non_trade.type_,
+    ActivityType::CashDeposit | ActivityType::CashWithdrawal
+  ) && non_trade.symbol.is_some()
+    && non_trade.quantity.is_some()
+}
+
+
+EndpointDef! {
+  /// A GET request to the /v2/account/activities endpoint, retrieving
+  /// account activities along with their status.
+  pub GetActivities(ActivityReq),
+  Ok => Vec<StatusActivity>, [
+    /// The activities were retrieved successfully.
+    /* 200 */ OK,
+  ],
+  Err => GetActivitiesError, [
+    /// The request was not permitted, e.g., because of invalid
+    /// credentials.
+    /* 403 */ FORBIDDEN => NotPermitted,
+    /// The rate limit was exceeded, causing the request to be denied.
+    /* 429 */ TOO_MANY_REQUESTS => RateLimitExceeded,
+  ],
+  ConversionErr => JsonError,
+  ApiErr => ApiError,
+
+  fn path(_input: &Self::Input) -> Str {
+    "/v2/account/activities".into()
+  }
+
+  fn query(input: &Self::Input) -> Result<Option<Str>, Self::ConversionError> {
+    // Encode the request just like apca does.
+    GetActivitiesApca::query(input).map_err(JsonError::custom)
+  }
+
+  fn parse(body: &[u8]) -> Result<Self::Output, Self::ConversionError> {
+    json_from_slice(body)
+  }
+
+  fn parse_err(body: &[u8]) -> Result<Self::ApiError, Vec<u8>> {
+    json_from_slice(body).map_err(|_| body.to_vec())
+  }
+}
+
+
+/// A request for the account's portfolio history at daily resolution.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortfolioHistoryReq {
+  /// The first day to report, in New York time.
+  pub start: NaiveDate,
+  /// The last day to report, in New York time.
+  pub end: NaiveDate,
+}
+
+
+/// The account's portfolio history, with one data point per day.
+///
+/// All vectors are of equal length, containing the values for the data
+/// point with the timestamp at the same index. Values are absent for
+/// days the account did not exist yet.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PortfolioHistory {
+  /// The Unix timestamps of the data points.
+  #[serde(rename = "timestamp")]
+  pub timestamps: Vec<i64>,
+  /// The equity at each data point.
+  #[serde(rename = "equity")]
+  pub equity: Vec<Option<Num>>,
+  /// The profit or loss at each data point, relative to the base value.
+  #[serde(rename = "profit_loss")]
+  pub profit_loss: Vec<Option<Num>>,
+  /// The profit or loss at each data point as a fraction of the base
+  /// value.
+  #[serde(rename = "profit_loss_pct")]
+  pub profit_loss_pct: Vec<Option<Num>>,
+}
+
+
+/// Format the beginning of the given day in New York time as an RFC 3339
+/// timestamp.
+fn start_of_day(date: NaiveDate) -> String {
+  // SANITY: Midnight always exists in New York, as daylight saving time
+  //         changes happen at 2am.
+  let time = date.and_hms_opt(0, 0, 0).unwrap();
+  New_York
+    .from_local_datetime(&time)
+    .unwrap()
+    .with_timezone(&Utc)
+    .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+
+EndpointDef! {
+  /// A GET request to the /v2/account/portfolio/history endpoint,
+  /// retrieving the account's equity at the end of each day.
+  pub GetPortfolioHistory(PortfolioHistoryReq),
+  Ok => PortfolioHistory, [
+    /// The portfolio history was retrieved successfully.
+    /* 200 */ OK,
+  ],
+  Err => GetPortfolioHistoryError, [
+    /// Some of the provided input was invalid.
+    /* 400 */ BAD_REQUEST => InvalidInput,
+    /// The request was not permitted, e.g., because of invalid
+    /// credentials.
+    /* 403 */ FORBIDDEN => NotPermitted,
+    /// The rate limit was exceeded, causing the request to be denied.
+    /* 429 */ TOO_MANY_REQUESTS => RateLimitExceeded,
+  ],
+  ConversionErr => JsonError,
+  ApiErr => ApiError,
+
+  fn path(_input: &Self::Input) -> Str {
+    "/v2/account/portfolio/history".into()
+  }
+
+  fn query(input: &Self::Input) -> Result<Option<Str>, Self::ConversionError> {
+    // Data points are labeled with the beginning of the day they cover,
+    // so the beginning of the last day is inclusive.
+    let query = format!(
+      "timeframe=1D&start={start}&end={end}",
+      start = start_of_day(input.start),
+      end = start_of_day(input.end),
+    );
+    Ok(Some(query.into()))
+  }
+
+  fn parse(body: &[u8]) -> Result<Self::Output, Self::ConversionError> {
+    json_from_slice(body)
+  }
+
+  fn parse_err(body: &[u8]) -> Result<Self::ApiError, Vec<u8>> {
+    json_from_slice(body).map_err(|_| body.to_vec())
+  }
+}
+
+
+/// A request for the daily bar of a crypto currency pair.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CryptoBarReq {
+  /// The currency pair, e.g., `BTC/USD`.
+  pub symbol: String,
+  /// The (UTC) day to retrieve the bar for.
+  pub date: NaiveDate,
+}
+
+
+/// A bar of a crypto currency pair.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CryptoBar {
+  /// The beginning of the time period the bar covers.
+  #[serde(rename = "t")]
+  pub time: DateTime<Utc>,
+  /// The closing price.
+  #[serde(rename = "c")]
+  pub close: Num,
+}
+
+
+/// The bars of crypto currency pairs.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CryptoBars {
+  /// The bars, keyed by currency pair.
+  #[serde(rename = "bars")]
+  pub bars: HashMap<String, Vec<CryptoBar>>,
+}
+
+
+EndpointDef! {
+  /// A GET request to the /v1beta3/crypto/us/bars endpoint of the market
+  /// data API, retrieving the daily bar of a crypto currency pair, which
+  /// apca only supports for stocks.
+  pub GetCryptoBar(CryptoBarReq),
+  Ok => CryptoBars, [
+    /// The bars were retrieved successfully.
+    /* 200 */ OK,
+  ],
+  Err => GetCryptoBarError, [
+    /// Some of the provided input was invalid.
+    /* 400 */ BAD_REQUEST => InvalidInput,
+    /// The request was not permitted, e.g., because of invalid
+    /// credentials.
+    /* 403 */ FORBIDDEN => NotPermitted,
+    /// The rate limit was exceeded, causing the request to be denied.
+    /* 429 */ TOO_MANY_REQUESTS => RateLimitExceeded,
+  ],
+  ConversionErr => JsonError,
+  ApiErr => ApiError,
+
+  fn base_url() -> Option<Str> {
+    Some(DATA_BASE_URL.into())
+  }
+
+  fn path(_input: &Self::Input) -> Str {
+    "/v1beta3/crypto/us/bars".into()
+  }
+
+  fn query(input: &Self::Input) -> Result<Option<Str>, Self::ConversionError> {
+    // Crypto currencies trade around the clock and daily bars cover
+    // UTC days.
+    let date = input.date.format("%Y-%m-%dT00:00:00Z").to_string();
+    let query = Serializer::new(String::new())
+      .append_pair("symbols", &input.symbol)
+      .append_pair("timeframe", "1Day")
+      .append_pair("start", &date)
+      .append_pair("end", &date)
+      .finish();
+    Ok(Some(query.into()))
+  }
+
+  fn parse(body: &[u8]) -> Result<Self::Output, Self::ConversionError> {
+    json_from_slice(body)
+  }
+
+  fn parse_err(body: &[u8]) -> Result<Self::ApiError, Vec<u8>> {
+    json_from_slice(body).map_err(|_| body.to_vec())
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use apca::api::v2::account_activities::Direction;
+
+  use http::StatusCode;
+  use http_endpoint::Endpoint as _;
+
+
+  /// Check that we can parse the name from an asset response.
+  #[test]
+  fn parse_asset_name() {
+    let body = br#"{"id":"b0b6dd9d-8b9b-48a9-ba46-b9d54906e415","class":"us_equity","exchange":"NASDAQ","symbol":"AAPL","name":"Apple Inc. Common Stock","status":"active","tradable":true}"#;
+    let asset = GetAssetName::evaluate(StatusCode::OK, body).unwrap();
+    assert_eq!(asset.name, "Apple Inc. Common Stock");
+
+    let body = br#"{"code":40410000,"message":"asset not found for XYZ"}"#;
+    let err = GetAssetName::evaluate(StatusCode::NOT_FOUND, body).unwrap_err();
+    assert!(
+      matches!(err, GetAssetNameError::NotFound(Ok(..))),
+      "{err:?}"
+    );
+  }
+
+  /// Check that we can parse account activities along with their
+  /// status.
+  #[test]
+  fn parse_activities() {
+    let body = br#"[
+{"id":"11111111111111111::11111111-1111-1111-1111-111111111111","activity_type":"FILL","transaction_time":"2021-06-15T16:19:18.136Z","type":"fill","price":"9.33","qty":"54","side":"sell","symbol":"XYZ","leaves_qty":"0","order_id":"12345678-9012-3456-7890-123456789012","cum_qty":"54","order_status":"filled"},
+{"id":"22222222222222222::22222222-2222-2222-2222-222222222222","activity_type":"DIV","date":"2021-06-16","net_amount":"1.87","description":"Cash DIV @ 0.17","symbol":"EFG","qty":"11","per_share_amount":"0.17","status":"pending"}
+]"#;
+    let activities = GetActivities::evaluate(StatusCode::OK, body).unwrap();
+    assert_eq!(activities.len(), 2);
+    assert!(matches!(activities[0].activity, Activity::Trade(..)));
+    assert_eq!(activities[0].status, None);
+    assert!(matches!(activities[1].activity, Activity::NonTrade(..)));
+    assert_eq!(activities[1].status.as_deref(), Some("pending"));
+
+    let request = ActivityReq {
+      direction: Direction::Ascending,
+      page_token: Some("abc".to_string()),
+      ..Default::default()
+    };
+    let query = GetActivities::query(&request).unwrap().unwrap();
+    assert!(query.contains("direction=asc"), "{query}");
+    assert!(query.contains("page_token=abc"), "{query}");
+  }
+
+  /// Check that the network fee of crypto transfers is retained.
+  #[test]
+  fn parse_crypto_transfer() {
+    let body = br#"[
+{"id":"11111111111111111::22222222-3333-4444-5555-666666666666","activity_type":"CSW","date":"2024-03-01","net_amount":"0","symbol":"BTC","qty":"0.5","network_fee":"0.0001","status":"executed"},
+{"id":"22222222222222222::22222222-3333-4444-5555-666666666666","activity_type":"CSD","date":"2024-03-02","net_amount":"100","price":"1.5","status":"executed"}
+]"#;
+    let activities = GetActivities::evaluate(StatusCode::OK, body).unwrap();
+    let transfer = match &activities[0].activity {
+      Activity::NonTrade(non_trade) => non_trade,
+      _ => panic!("encountered unexpected account activity"),
+    };
+    assert!(is_crypto_transfer(transfer));
+    assert_eq!(transfer.price, Some(Num::new(1, 10000)));
+
+    let deposit = match &activities[1].activity {
+      Activity::NonTrade(non_trade) => non_trade,
+      _ => panic!("encountered unexpected account activity"),
+    };
+    assert!(!is_crypto_transfer(deposit));
+    assert_eq!(deposit.price, Some(Num::new(15, 10)));
+  }
+
+  /// Check that we can request and parse the daily bar of a crypto
+  /// currency pair.
+  #[test]
+  fn crypto_bar() {
+    let request = CryptoBarReq {
+      symbol: "BTC/USD".to_string(),
+      date: NaiveDate::from_ymd_opt(2024, 3, 5).unwrap(),
+    };
+    let query = GetCryptoBar::query(&request).unwrap().unwrap();
+    assert_eq!(
+      query,
+      "symbols=BTC%2FUSD&timeframe=1Day&start=2024-03-05T00%3A00%3A00Z&end=2024-03-05T00%3A00%3A00Z"
+    );
+
+    let body = br#"{"bars":{"BTC/USD":[{"c":63792.4,"h":69000,"l":59005,"n":1033,"o":68350.1,"t":"2024-03-05T00:00:00Z","v":12.5,"vw":65671.2}]},"next_page_token":null}"#;
+    let bars = GetCryptoBar::evaluate(StatusCode::OK, body).unwrap();
+    let bar = &bars.bars["BTC/USD"][0];
+    assert_eq!(bar.close, Num::new(637924, 10));
+    assert_eq!(bar.time.date_naive(), request.date);
+  }
+
+  /// Check that we can request and parse the portfolio history.
+  #[test]
+  fn portfolio_history() {
+    let request = PortfolioHistoryReq {
+      start: NaiveDate::from_ymd_opt(2024, 1, 2).unwrap(),
+      end: NaiveDate::from_ymd_opt(2024, 7, 3).unwrap(),
+    };
+    let query = GetPortfolioHistory::query(&request).unwrap().unwrap();
+    assert_eq!(
+      query,
+      "timeframe=1D&start=2024-01-02T05:00:00Z&end=2024-07-03T04:00:00Z"
+    );
+
+    let body = br#"{"timestamp":[1704171600,1704258000,1704344400],"equity":[null,10000.5,10123.45],"profit_loss":[null,0,122.95],"profit_loss_pct":[null,0,0.012294],"base_value":10000.5,"base_value_asof":"2024-01-03","timeframe":"1D"}"#;
+    let history = GetPortfolioHistory::evaluate(StatusCode::OK, body).unwrap();
+    assert_eq!(history.timestamps.len(), 3);
+    assert_eq!(history.equity[0], None);
+    assert_eq!(history.equity[2], Some(Num::new(1012345, 100)));
+    assert_eq!(history.profit_loss[2], Some(Num::new(12295, 100)));
+    assert_eq!(history.profit_loss_pct[2], Some(Num::new(12294, 1000000)));
+  }
+}
@@ -0,0 +1,170 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+
+use anyhow::Error;
+use anyhow::Result;
+
+
+/// The class of a failure, determining the program's exit code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+  /// The configuration or one of the input files is invalid.
+  Config,
+  /// Communication with the Alpaca API failed, e.g., because of
+  /// invalid credentials or network issues.
+  Api,
+  /// An account activity could not be classified or represented.
+  Classification,
+}
+
+impl Failure {
+  /// Retrieve the exit code corresponding to the failure.
+  fn exit_code(&self) -> i32 {
+    match self {
+      Self::Config => 3,
+      Self::Api => 4,
+      Self::Classification => 5,
+    }
+  }
+}
+
+
+/// An error tagged with the class of failure it represents.
+///
+/// The error is transparent, in that it displays as and reports the
+/// same source as the error it wraps.
+#[derive(Debug)]
+struct Classified {
+  /// The class of failure.
+  failure: Failure,
+  /// The actual error.
+  error: Error,
+}
+
+impl Display for Classified {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    Display::fmt(&self.error, f)
+  }
+}
+
+impl StdError for Classified {
+  fn source(&self) -> Option<&(dyn StdError + 'static)> {
+    self.error.source()
+  }
+}
+
+
+/// An extension trait for tagging errors with a class of failure.
+pub trait Classify<T> {
+  /// Tag the error, if any, with the given class of failure.
+  fn classify(self, failure: Failure) -> Result<T>;
+}
+
+impl<T, E> Classify<T> for Result<T, E>
+where
+  E: Into<Error>,
+{
+  fn classify(self, failure: Failure) -> Result<T> {
+    self.map_err(|error| {
+      Error::new(Classified {
+        failure,
+        error: error.into(),
+      })
+    })
+  }
+}
+
+
+/// An error indicating that an operation succeeded only for some of the
+/// items it was performed on.
+#[derive(Debug)]
+pub struct PartialFailure {
+  /// A description of what failed.
+  pub what: &'static str,
+  /// The total number of items.
+  pub count: usize,
+  /// The items for which the operation failed, along with the reason.
+  pub failed: Vec<(String, Error)>,
+}
+
+impl Display for PartialFailure {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    write!(
+      f,
+      "{} for {} of {} items",
+      self.what,
+      self.failed.len(),
+      self.count
+    )?;
+    for (item, err) in &self.failed {
+      write!(f, "\n  {item}: {err:#}")?;
+    }
+    Ok(())
+  }
+}
+
+impl StdError for PartialFailure {}
+
+
+/// Determine the exit code to use for the given error.
+///
+/// - 1: unclassified failure
+/// - 2: partial success
+/// - 3: configuration error
+/// - 4: Alpaca API error
+/// - 5: classification error
+pub fn exit_code(error: &Error) -> i32 {
+  if error.downcast_ref::<PartialFailure>().is_some() {
+    return 2
+  }
+
+  error
+    .chain()
+    .find_map(|cause| cause.downcast_ref::<Classified>())
+    .map(|classified| classified.failure.exit_code())
+    .unwrap_or(1)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use anyhow::anyhow;
+  use anyhow::Context as _;
+
+
+  /// Check that classified errors map to the expected exit codes and
+  /// display transparently.
+  #[test]
+  fn classified_exit_codes() {
+    let error = Err::<(), _>(anyhow!("no route to host"))
+      .classify(Failure::Api)
+      .context("failed to retrieve account activities")
+      .unwrap_err();
+    assert_eq!(exit_code(&error), 4);
+    assert_eq!(
+      format!("{error:#}"),
+      "failed to retrieve account activities: no route to host"
+    );
+
+    let error = anyhow!("something went wrong");
+    assert_eq!(exit_code(&error), 1);
+
+    let error = Error::new(PartialFailure {
+      what: "failed to retrieve prices",
+      count: 2,
+      failed: vec![("XYZ".to_string(), anyhow!("no bars"))],
+    });
+    assert_eq!(exit_code(&error), 2);
+    assert_eq!(
+      error.to_string(),
+      "failed to retrieve prices for 1 of 2 items\n  XYZ: no bars"
+    );
+  }
+}
@@ -0,0 +1,170 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::BufRead as _;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use chrono::NaiveDate;
+
+use num_decimal::Num;
+
+use serde::Deserialize;
+use serde_json::from_reader as json_from_reader;
+
+use crate::input;
+
+
+/// A price as reported by an endpoint.
+#[derive(Debug, Deserialize)]
+struct Price {
+  /// The price.
+  price: Num,
+  /// The date the price is for, if it differs from the one requested.
+  #[serde(default)]
+  date: Option<NaiveDate>,
+}
+
+
+/// A source of prices to fall back to for symbols Alpaca does not
+/// provide bars for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PriceSource {
+  /// A directory containing a `<symbol>.csv` file per symbol, with
+  /// lines of the form `<yyyy-mm-dd>,<price>`.
+  Directory(PathBuf),
+  /// An `http(s)://` URL template, in which `{symbol}` and `{date}` get
+  /// replaced, pointing to a JSON object of the form `{"price": ...,
+  /// "date": ...}`, with `date` being optional.
+  Url(String),
+}
+
+impl PriceSource {
+  /// Retrieve the price of the given symbol at the given date or, if
+  /// there is none, the most recent one before it.
+  ///
+  /// `None` is returned if the source does not know about the symbol.
+  pub fn price(&self, symbol: &str, date: NaiveDate) -> Result<Option<(NaiveDate, Num)>> {
+    match self {
+      Self::Directory(dir) => csv_price(&dir.join(format!("{symbol}.csv")), date),
+      Self::Url(template) => {
+        let url = template
+          .replace("{symbol}", symbol)
+          .replace("{date}", &date.format("%Y-%m-%d").to_string());
+        let reader = input::open(Path::new(&url))?;
+        let price = json_from_reader::<_, Price>(reader)
+          .with_context(|| format!("failed to parse price retrieved from {url}"))?;
+        Ok(Some((price.date.unwrap_or(date), price.price)))
+      },
+    }
+  }
+}
+
+impl FromStr for PriceSource {
+  type Err = Infallible;
+
+  fn from_str(string: &str) -> Result<Self, Self::Err> {
+    if string.starts_with("https://") || string.starts_with("http://") {
+      Ok(Self::Url(string.to_string()))
+    } else {
+      Ok(Self::Directory(PathBuf::from(string)))
+    }
+  }
+}
+
+
+/// Find the most recent price at or before the given date in the CSV
+/// file at the provided path.
+fn csv_price(path: &Path, date: NaiveDate) -> Result<Option<(NaiveDate, Num)>> {
+  let file = match File::open(path) {
+    Ok(file) => file,
+    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+    Err(err) => return Err(err).with_context(|| format!("failed to open {}", path.display())),
+  };
+
+  let mut latest = None;
+  for (index, line) in BufReader::new(file).lines().enumerate() {
+    let line = line.with_context(|| format!("failed to read {}", path.display()))?;
+    let (day, price) = match line.split_once(',') {
+      Some((day, price)) => (day.trim(), price.trim()),
+      None => continue,
+    };
+    let day = match NaiveDate::parse_from_str(day, "%Y-%m-%d") {
+      Ok(day) => day,
+      // Tolerate a header line.
+      Err(_) if index == 0 => continue,
+      Err(err) => {
+        return Err(err).with_context(|| {
+          format!(
+            "{}:{}: failed to parse date '{}'",
+            path.display(),
+            index + 1,
+            day
+          )
+        })
+      },
+    };
+
+    if day <= date
+      && latest
+        .as_ref()
+        .map(|(latest, _)| day >= *latest)
+        .unwrap_or(true)
+    {
+      let price = price.parse::<Num>().with_context(|| {
+        format!(
+          "{}:{}: failed to parse price '{}'",
+          path.display(),
+          index + 1,
+          price
+        )
+      })?;
+      latest = Some((day, price));
+    }
+  }
+  Ok(latest)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env::temp_dir;
+  use std::fs::create_dir_all;
+  use std::fs::write;
+  use std::process;
+
+
+  /// Check that we pick the most recent price from a CSV directory.
+  #[test]
+  fn directory_price() {
+    let dir = temp_dir().join(format!("apcaledge-fallback-{}", process::id()));
+    let () = create_dir_all(&dir).unwrap();
+    let () = write(
+      dir.join("XYZ.csv"),
+      "date,price\n2021-06-14,9.10\n2021-06-16,9.50\n2021-06-15,9.33\n",
+    )
+    .unwrap();
+
+    let source = PriceSource::from_str(&dir.to_string_lossy()).unwrap();
+    let date = |day| NaiveDate::from_ymd_opt(2021, 6, day).unwrap();
+
+    let price = source.price("XYZ", date(15)).unwrap();
+    assert_eq!(price, Some((date(15), Num::new(933, 100))));
+    let price = source.price("XYZ", date(30)).unwrap();
+    assert_eq!(price, Some((date(16), Num::new(95, 10))));
+    let price = source.price("XYZ", date(1)).unwrap();
+    assert_eq!(price, None);
+    let price = source.price("ABC", date(15)).unwrap();
+    assert_eq!(price, None);
+  }
+}
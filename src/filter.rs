@@ -0,0 +1,74 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::Write as _;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde_json::from_slice as json_from_slice;
+use serde_json::to_vec as to_json;
+
+use crate::ledger::Transaction;
+
+
+/// An external command that generated transactions are piped through
+/// before being emitted.
+///
+/// The command is run by the shell for each transaction. It receives
+/// the transaction as a JSON object on stdin and is expected to print
+/// the (possibly modified) transaction in the same format to stdout.
+/// Printing nothing drops the transaction.
+#[derive(Debug)]
+pub struct Filter {
+  /// The command to run.
+  command: String,
+}
+
+impl Filter {
+  /// Create a filter running the provided shell command.
+  pub fn new(command: String) -> Self {
+    Self { command }
+  }
+
+  /// Pass a transaction through the filter.
+  pub fn apply(&self, transaction: &Transaction) -> Result<Option<Transaction>> {
+    let input = to_json(transaction).context("failed to serialize transaction")?;
+    let mut child = Command::new("sh")
+      .arg("-c")
+      .arg(&self.command)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::inherit())
+      .spawn()
+      .with_context(|| format!("failed to run filter `{}`", self.command))?;
+
+    // We know that stdin is piped.
+    let mut stdin = child.stdin.take().unwrap();
+    let () = stdin
+      .write_all(&input)
+      .with_context(|| format!("failed to pass transaction to filter `{}`", self.command))?;
+    drop(stdin);
+
+    let output = child
+      .wait_with_output()
+      .with_context(|| format!("failed to wait for filter `{}`", self.command))?;
+    ensure!(
+      output.status.success(),
+      "filter `{}` failed: {}",
+      self.command,
+      output.status
+    );
+
+    if output.stdout.iter().all(u8::is_ascii_whitespace) {
+      return Ok(None)
+    }
+
+    let transaction = json_from_slice(&output.stdout)
+      .with_context(|| format!("filter `{}` produced an invalid transaction", self.command))?;
+    Ok(Some(transaction))
+  }
+}
@@ -0,0 +1,82 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs::canonicalize;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+
+/// Run `git` with the provided arguments in the given directory,
+/// returning whether it succeeded.
+fn git<I, S>(dir: &Path, args: I) -> Result<bool>
+where
+  I: IntoIterator<Item = S>,
+  S: AsRef<OsStr>,
+{
+  let status = Command::new("git")
+    .arg("-C")
+    .arg(dir)
+    .args(args)
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .status()
+    .with_context(|| format!("failed to run git in {}", dir.display()))?;
+  Ok(status.success())
+}
+
+
+/// Stage the provided paths in the git repository containing `dir` and
+/// commit them with the given message.
+///
+/// Nothing is committed if none of the paths changed.
+pub fn commit(dir: &Path, paths: &[&Path], message: &str) -> Result<()> {
+  ensure!(
+    git(dir, ["rev-parse", "--is-inside-work-tree"])?,
+    "{} is not inside a git repository",
+    dir.display()
+  );
+
+  // We run git in `dir`, so relative paths would be interpreted
+  // relative to it.
+  let paths = paths
+    .iter()
+    .map(|path| {
+      canonicalize(path).with_context(|| format!("failed to canonicalize {}", path.display()))
+    })
+    .collect::<Result<Vec<_>>>()?;
+  let with_paths = |args: &[&str]| {
+    args
+      .iter()
+      .map(OsString::from)
+      .chain(paths.iter().map(OsString::from))
+      .collect::<Vec<_>>()
+  };
+
+  ensure!(
+    git(dir, with_paths(&["add", "--"]))?,
+    "failed to stage files in {}",
+    dir.display()
+  );
+
+  // `git diff --quiet` only succeeds if there are no differences.
+  if git(dir, with_paths(&["diff", "--cached", "--quiet", "--"]))? {
+    return Ok(())
+  }
+
+  ensure!(
+    git(
+      dir,
+      with_paths(&["commit", "--quiet", "--message", message, "--"])
+    )?,
+    "failed to create commit in {}",
+    dir.display()
+  );
+  Ok(())
+}
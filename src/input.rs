@@ -0,0 +1,86 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::File;
+use std::io::stdin;
+use std::io::Cursor;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use tokio::runtime::Builder;
+
+use crate::client;
+
+
+/// Whether an input has been read from stdin already.
+static STDIN_TAKEN: AtomicBool = AtomicBool::new(false);
+
+
+/// Retrieve the contents at the given URL, blocking until done.
+fn retrieve(url: &str) -> Result<Vec<u8>> {
+  // We may very well be called from within an asynchronous context,
+  // where we can't block on a future. So use a dedicated thread with
+  // its own runtime.
+  thread::scope(|scope| {
+    scope
+      .spawn(|| {
+        let rt = Builder::new_current_thread()
+          .enable_io()
+          .enable_time()
+          .build()
+          .context("failed to create runtime")?;
+        rt.block_on(client::get(url)).map(Vec::from)
+      })
+      .join()
+      // SANITY: Retrieval does not panic.
+      .unwrap()
+  })
+}
+
+
+/// Open an input for reading.
+///
+/// The input may be a path to a file, `-` for reading from stdin, or an
+/// `http://` or `https://` URL. Only a single input may be read from
+/// stdin.
+pub fn open(path: &Path) -> Result<Box<dyn Read>> {
+  let string = path.to_string_lossy();
+  if string == "-" {
+    ensure!(
+      !STDIN_TAKEN.swap(true, Ordering::Relaxed),
+      "only a single input can be read from stdin (-)"
+    );
+    Ok(Box::new(stdin()))
+  } else if string.starts_with("https://") || string.starts_with("http://") {
+    let data = retrieve(&string)?;
+    Ok(Box::new(Cursor::new(data)))
+  } else {
+    let file = File::open(path)?;
+    Ok(Box::new(file))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that only a single input can be read from stdin.
+  #[test]
+  fn open_stdin_once() {
+    let _stdin = open(Path::new("-")).unwrap();
+    let err = open(Path::new("-")).map(|_| ()).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "only a single input can be read from stdin (-)"
+    );
+  }
+}
@@ -0,0 +1,116 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Conversion of account activities back into the JSON representation
+//! Alpaca reports them in. apca only supports deserializing them.
+
+use apca::api::v2::account_activities::Activity;
+use apca::api::v2::account_activities::NonTradeActivity;
+use apca::api::v2::account_activities::Side;
+use apca::api::v2::account_activities::TradeActivity;
+
+use serde_json::json;
+use serde_json::to_value;
+use serde_json::Value;
+
+use crate::endpoints::is_crypto_transfer;
+
+
+/// Retrieve the string Alpaca uses to represent the given trade side.
+pub fn side(side: Side) -> &'static str {
+  match side {
+    Side::Buy => "buy",
+    Side::Sell => "sell",
+    Side::ShortSell => "sell_short",
+    _ => "unknown",
+  }
+}
+
+
+/// Convert a trade activity into its JSON representation.
+pub fn trade(trade: &TradeActivity) -> Value {
+  json!({
+    "id": trade.id,
+    "activity_type": "FILL",
+    "transaction_time": trade.transaction_time,
+    "symbol": trade.symbol,
+    "order_id": trade.order_id,
+    "side": side(trade.side),
+    "qty": trade.quantity,
+    "cum_qty": trade.cumulative_quantity,
+    "leaves_qty": trade.unfilled_quantity,
+    "price": trade.price,
+  })
+}
+
+
+/// Convert a non-trade activity into its JSON representation.
+pub fn non_trade(non_trade: &NonTradeActivity) -> Value {
+  let mut value = json!({
+    "id": non_trade.id,
+    "activity_type": to_value(non_trade.type_).unwrap_or_default(),
+    "date": non_trade.date.format("%Y-%m-%d").to_string(),
+    "net_amount": non_trade.net_amount,
+    "symbol": non_trade.symbol,
+    "qty": non_trade.quantity,
+    "price": non_trade.price,
+    "per_share_amount": non_trade.per_share_amount,
+    "description": non_trade.description,
+  });
+  if is_crypto_transfer(non_trade) {
+    // We carry the network fee of crypto transfers in the price, but
+    // Alpaca reports it separately.
+    value["network_fee"] = value["price"].take();
+  }
+  value
+}
+
+
+/// Convert an activity into its JSON representation.
+pub fn activity(activity: &Activity) -> Value {
+  match activity {
+    Activity::Trade(trade) => self::trade(trade),
+    Activity::NonTrade(non_trade) => self::non_trade(non_trade),
+  }
+}
+
+
+/// Convert a list of activities into their JSON representation.
+pub fn activities(activities: &[Activity]) -> Value {
+  Value::Array(activities.iter().map(activity).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use serde_json::from_str as from_json;
+  use serde_json::from_value;
+
+  use crate::endpoints::StatusActivity;
+
+
+  /// Check that activities survive a round trip through their JSON
+  /// representation.
+  #[test]
+  fn round_trip() {
+    let json = r#"[
+{"id":"44444444444444444::55555555-6666-7777-8888-999999999999","activity_type":"FILL","transaction_time":"2021-06-15T16:19:18.136Z","type":"fill","price":"9.33","qty":"54","side":"sell","symbol":"XYZ","leaves_qty":"0","order_id":"12345678-9012-3456-7890-123456789012","cum_qty":"56","order_status":"filled"},
+{"id":"22222222222222222::22222222-2222-2222-2222-222222222222","activity_type":"DIV","date":"2021-06-16","net_amount":"1.87","description":"Cash DIV @ 0.17, Pos QTY: 11.0, Rec Date: 2021-05-20","symbol":"EFG","qty":"11","per_share_amount":"0.17","status":"executed"},
+{"id":"33333333333333333::33333333-3333-3333-3333-333333333333","activity_type":"CSW","date":"2021-06-17","net_amount":"0","symbol":"BTC","qty":"-0.25","network_fee":"0.00015","status":"executed"}
+]"#;
+    let activities = from_json::<Vec<StatusActivity>>(json)
+      .unwrap()
+      .into_iter()
+      .map(|activity| activity.activity)
+      .collect::<Vec<Activity>>();
+    let value = super::activities(&activities);
+    let round_tripped = from_value::<Vec<StatusActivity>>(value)
+      .unwrap()
+      .into_iter()
+      .map(|activity| activity.activity)
+      .collect::<Vec<_>>();
+    assert_eq!(round_tripped, activities);
+  }
+}
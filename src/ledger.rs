@@ -0,0 +1,799 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! A model of the Ledger constructs we emit, taking care of formatting
+//! them consistently.
+
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::io::Result as IoResult;
+use std::io::Write;
+
+use chrono::NaiveDate;
+
+use num_decimal::Num;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+
+/// ANSI escape sequences used for rendering transactions in color.
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const BLUE: &str = "\x1b[34m";
+const RESET: &str = "\x1b[0m";
+
+
+/// Format a symbol as a Ledger commodity, quoting it if necessary.
+pub fn format_commodity(symbol: &str) -> Cow<'_, str> {
+  // Ledger requires commodities containing anything but letters (e.g.,
+  // digits, as is the case for option symbols) to be quoted.
+  if symbol.chars().all(char::is_alphabetic) {
+    Cow::from(symbol)
+  } else {
+    Cow::from(format!("\"{symbol}\""))
+  }
+}
+
+
+/// Format a price value.
+pub fn format_price(price: &Num, currency: &str) -> String {
+  // We would like to ensure emitting prices with at least two post
+  // decimal positions, for consistency.
+  format!("{} {}", price.display().min_precision(2), currency)
+}
+
+
+/// Format a Ledger price directive (`P`) for the given commodity at the
+/// end of the given day.
+pub fn format_price_directive(
+  date: NaiveDate,
+  commodity: &str,
+  price: &Num,
+  currency: &str,
+) -> String {
+  format!(
+    "P {date} 23:59:59 {commodity} {currency} {price}",
+    date = date.format("%Y-%m-%d"),
+    commodity = format_commodity(commodity),
+    price = price.display().min_precision(2),
+  )
+}
+
+
+/// Parse a Ledger price directive of the form `P <date> [<time>]
+/// <commodity> <currency> <price>`, returning the commodity and the
+/// price.
+pub fn parse_price_directive(line: &str) -> Option<(String, Num)> {
+  let mut tokens = line.strip_prefix("P ")?.split_whitespace();
+  let _date = tokens.next()?;
+  let mut commodity = tokens.next()?;
+  if commodity.contains(':') {
+    // The token was the time of day.
+    commodity = tokens.next()?;
+  }
+  let _currency = tokens.next()?;
+  let price = tokens.next()?.parse().ok()?;
+  Some((commodity.trim_matches('"').to_string(), price))
+}
+
+
+/// The amount of a posting.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Amount {
+  /// An amount of money, e.g., `12.34 USD`.
+  Cash {
+    /// The amount.
+    amount: Num,
+    /// The currency the amount is in.
+    currency: String,
+  },
+  /// A quantity of a commodity at a per-unit price, e.g., `10 ABC @
+  /// 422.50 USD`.
+  Price {
+    /// The quantity of the commodity.
+    quantity: Num,
+    /// The commodity, e.g., a ticker symbol.
+    commodity: String,
+    /// The price per unit.
+    price: Num,
+    /// The currency the price is in.
+    currency: String,
+    /// The balance of the commodity to assert after the posting, if
+    /// any.
+    #[serde(default)]
+    balance: Option<Num>,
+  },
+  /// A quantity of a commodity at a total cost, e.g., `10 ABC @@
+  /// 4225.00 USD`.
+  Cost {
+    /// The quantity of the commodity.
+    quantity: Num,
+    /// The commodity, e.g., a ticker symbol.
+    commodity: String,
+    /// The total cost.
+    cost: Num,
+    /// The currency the cost is in.
+    currency: String,
+  },
+  /// A quantity of a commodity without a price, e.g., `0.5 BTC`, for
+  /// moving it between accounts.
+  Quantity {
+    /// The quantity of the commodity.
+    quantity: Num,
+    /// The commodity, e.g., a ticker symbol.
+    commodity: String,
+  },
+  /// An assertion of the balance of a commodity without changing it,
+  /// e.g., `0 ABC = 10 ABC`.
+  Assertion {
+    /// The commodity, e.g., a ticker symbol.
+    commodity: String,
+    /// The balance to assert.
+    balance: Num,
+  },
+}
+
+impl Amount {
+  /// Create a cash amount.
+  pub fn cash(amount: Num, currency: &str) -> Self {
+    Self::Cash {
+      amount,
+      currency: currency.to_string(),
+    }
+  }
+
+  /// Retrieve the commodities the amount refers to.
+  fn commodities(&self) -> impl Iterator<Item = &str> {
+    let (commodity, currency) = match self {
+      Self::Cash { currency, .. } => (None, currency),
+      Self::Price {
+        commodity,
+        currency,
+        ..
+      }
+      | Self::Cost {
+        commodity,
+        currency,
+        ..
+      } => (Some(commodity), currency),
+      Self::Quantity { commodity, .. } | Self::Assertion { commodity, .. } => (None, commodity),
+    };
+    commodity.into_iter().chain([currency]).map(String::as_str)
+  }
+}
+
+
+/// A posting to an account.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Posting {
+  /// The account to post to.
+  pub account: String,
+  /// Whether the posting is virtual, i.e., does not have to balance.
+  #[serde(default)]
+  pub is_virtual: bool,
+  /// The amount, if any. Ledger infers a missing amount.
+  #[serde(default)]
+  pub amount: Option<Amount>,
+  /// Comments to attach to the posting.
+  #[serde(default)]
+  pub notes: Vec<String>,
+}
+
+impl Posting {
+  /// Create a posting to the given account.
+  pub fn new(account: impl Into<String>, amount: Option<Amount>) -> Self {
+    Self {
+      account: account.into(),
+      is_virtual: false,
+      amount,
+      notes: Vec::new(),
+    }
+  }
+
+  /// Check whether the posting's amount is negative.
+  fn is_negative(&self) -> bool {
+    match &self.amount {
+      None => false,
+      Some(Amount::Cash { amount, .. }) => amount.is_negative(),
+      Some(Amount::Price { quantity, .. })
+      | Some(Amount::Cost { quantity, .. })
+      | Some(Amount::Quantity { quantity, .. }) => quantity.is_negative(),
+      Some(Amount::Assertion { .. }) => false,
+    }
+  }
+}
+
+impl Display for Posting {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    Display::fmt(&Aligned(self, Widths::default()), f)
+  }
+}
+
+
+/// The widths of the columns postings get aligned at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Widths {
+  /// The width of the account column.
+  account: usize,
+  /// The width of the quantity column.
+  quantity: usize,
+  /// The width of the column of cash amounts, including the currency.
+  total: usize,
+}
+
+impl Widths {
+  /// Determine the column widths for aligning the given postings.
+  ///
+  /// The default widths are used unless an account or amount does not
+  /// fit, in which case the respective columns are widened. Quantity
+  /// and cash columns are widened by the same amount, to keep them in
+  /// line with each other.
+  fn of(postings: &[Posting]) -> Self {
+    let default = Self::default();
+    let mut account = default.account;
+    let mut excess = 0;
+
+    for posting in postings {
+      let width = posting.account.len() + if posting.is_virtual { 2 } else { 0 };
+      account = account.max(width);
+
+      let (width, default) = match &posting.amount {
+        None => continue,
+        Some(Amount::Cash { amount, currency }) => {
+          (format_price(amount, currency).len(), default.total)
+        },
+        Some(Amount::Price { quantity, .. })
+        | Some(Amount::Cost { quantity, .. })
+        | Some(Amount::Quantity { quantity, .. }) => (quantity.to_string().len(), default.quantity),
+        Some(Amount::Assertion { .. }) => continue,
+      };
+      excess = excess.max(width.saturating_sub(default));
+    }
+
+    Self {
+      account,
+      quantity: default.quantity + excess,
+      total: default.total + excess,
+    }
+  }
+}
+
+impl Default for Widths {
+  fn default() -> Self {
+    Self {
+      account: 51,
+      quantity: 13,
+      total: 15,
+    }
+  }
+}
+
+
+/// A posting formatted with the given column widths.
+struct Aligned<'p>(&'p Posting, Widths);
+
+impl Display for Aligned<'_> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    let Self(posting, widths) = self;
+    let Widths {
+      account: account_width,
+      quantity: quantity_width,
+      total: total_width,
+    } = *widths;
+
+    let account = if posting.is_virtual {
+      Cow::from(format!("({})", posting.account))
+    } else {
+      Cow::from(&posting.account)
+    };
+
+    match &posting.amount {
+      None => write!(f, "  {account}")?,
+      Some(Amount::Cash { amount, currency }) => write!(
+        f,
+        "  {account:<account_width$}    {total:>total_width$}",
+        total = format_price(amount, currency)
+      )?,
+      Some(Amount::Price {
+        quantity,
+        commodity,
+        price,
+        currency,
+        balance,
+      }) => {
+        let commodity = format_commodity(commodity);
+        write!(
+          f,
+          "  {account:<account_width$}  {quantity:>quantity_width$} {commodity} @ {price}",
+          price = format_price(price, currency)
+        )?;
+        if let Some(balance) = balance {
+          write!(f, " = {balance} {commodity}")?;
+        }
+      },
+      Some(Amount::Cost {
+        quantity,
+        commodity,
+        cost,
+        currency,
+      }) => write!(
+        f,
+        "  {account:<account_width$}  {quantity:>quantity_width$} {commodity} @@ {cost}",
+        commodity = format_commodity(commodity),
+        cost = format_price(cost, currency)
+      )?,
+      Some(Amount::Quantity {
+        quantity,
+        commodity,
+      }) => write!(
+        f,
+        "  {account:<account_width$}  {quantity:>quantity_width$} {commodity}",
+        commodity = format_commodity(commodity),
+      )?,
+      Some(Amount::Assertion { commodity, balance }) => {
+        let commodity = format_commodity(commodity);
+        write!(
+          f,
+          "  {account:<account_width$}  {zero:>quantity_width$} {commodity} = {balance} {commodity}",
+          zero = 0
+        )?
+      },
+    }
+
+    for note in &posting.notes {
+      write!(f, "\n  ; {note}")?;
+    }
+    Ok(())
+  }
+}
+
+
+/// A Ledger transaction.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Transaction {
+  /// Comments to emit before the transaction.
+  #[serde(default)]
+  pub comments: Vec<String>,
+  /// The date of the transaction.
+  pub date: NaiveDate,
+  /// The transaction code, if any.
+  #[serde(default)]
+  pub code: Option<String>,
+  /// The payee.
+  pub payee: String,
+  /// Comments to attach to the transaction as a whole.
+  #[serde(default)]
+  pub notes: Vec<String>,
+  /// The transaction's postings.
+  pub postings: Vec<Posting>,
+}
+
+impl Transaction {
+  /// Create a transaction without any postings.
+  pub fn new(date: NaiveDate, code: Option<String>, payee: impl Into<String>) -> Self {
+    Self {
+      comments: Vec::new(),
+      date,
+      code,
+      payee: payee.into(),
+      notes: Vec::new(),
+      postings: Vec::new(),
+    }
+  }
+}
+
+impl Display for Transaction {
+  /// Format the transaction, terminating each line with a newline.
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    for comment in &self.comments {
+      writeln!(f, "; {comment}")?;
+    }
+
+    write!(f, "{} *", self.date.format("%Y-%m-%d"))?;
+    if let Some(code) = &self.code {
+      write!(f, " ({code})")?;
+    }
+    writeln!(f, " {}", self.payee)?;
+
+    for note in &self.notes {
+      writeln!(f, "  ; {note}")?;
+    }
+    // Align all postings of the transaction at the same columns, even
+    // if some amounts don't fit the default widths.
+    let widths = Widths::of(&self.postings);
+    for posting in &self.postings {
+      writeln!(f, "{}", Aligned(posting, widths))?;
+    }
+    Ok(())
+  }
+}
+
+
+/// A Ledger periodic transaction, e.g., for budgeting.
+#[derive(Debug)]
+pub struct Periodic {
+  /// The period expression, e.g., `monthly`.
+  pub period: String,
+  /// Comments to attach to the transaction as a whole.
+  pub notes: Vec<String>,
+  /// The transaction's postings.
+  pub postings: Vec<Posting>,
+}
+
+impl Periodic {
+  /// Create a periodic transaction without any postings.
+  pub fn new(period: impl Into<String>) -> Self {
+    Self {
+      period: period.into(),
+      notes: Vec::new(),
+      postings: Vec::new(),
+    }
+  }
+}
+
+impl Display for Periodic {
+  /// Format the periodic transaction, terminating each line with a
+  /// newline.
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    writeln!(f, "~ {}", self.period)?;
+    for note in &self.notes {
+      writeln!(f, "  ; {note}")?;
+    }
+    let widths = Widths::of(&self.postings);
+    for posting in &self.postings {
+      writeln!(f, "{}", Aligned(posting, widths))?;
+    }
+    Ok(())
+  }
+}
+
+
+/// A transaction rendered with ANSI colors, for previewing it on a
+/// terminal.
+pub struct Colored<'t>(pub &'t Transaction);
+
+impl Display for Colored<'_> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    let Self(transaction) = self;
+
+    for comment in &transaction.comments {
+      writeln!(f, "{DIM}; {comment}{RESET}")?;
+    }
+
+    write!(
+      f,
+      "{YELLOW}{}{RESET} *",
+      transaction.date.format("%Y-%m-%d")
+    )?;
+    if let Some(code) = &transaction.code {
+      write!(f, " ({code})")?;
+    }
+    writeln!(f, " {BOLD}{}{RESET}", transaction.payee)?;
+
+    for note in &transaction.notes {
+      writeln!(f, "  {DIM}; {note}{RESET}")?;
+    }
+    let widths = Widths::of(&transaction.postings);
+    for posting in &transaction.postings {
+      // We color the already aligned plain text rendition, so that
+      // escape sequences don't throw off the column widths. The first
+      // line starts with the indented (and, for virtual postings,
+      // parenthesized) account, any subsequent ones are notes.
+      let text = Aligned(posting, widths).to_string();
+      let mut lines = text.lines();
+      if let Some(line) = lines.next() {
+        let width = posting.account.len() + if posting.is_virtual { 4 } else { 2 };
+        let (account, amount) = line.split_at(width.min(line.len()));
+        if posting.is_negative() {
+          writeln!(f, "{BLUE}{account}{RESET}{RED}{amount}{RESET}")?;
+        } else {
+          writeln!(f, "{BLUE}{account}{RESET}{amount}")?;
+        }
+      }
+      for line in lines {
+        writeln!(f, "{DIM}{line}{RESET}")?;
+      }
+    }
+    Ok(())
+  }
+}
+
+
+/// The accounts, commodities, and payees declared so far, for emitting
+/// declarations as required by Ledger's `--pedantic` mode ahead of
+/// their first use.
+#[derive(Debug, Default)]
+pub struct Declarations {
+  /// The declared accounts.
+  accounts: HashSet<String>,
+  /// The declared commodities.
+  commodities: HashSet<String>,
+  /// The declared payees.
+  payees: HashSet<String>,
+}
+
+impl Declarations {
+  /// Write declarations for everything used by the provided
+  /// transaction that has not been declared yet.
+  pub fn declare(&mut self, transaction: &Transaction, out: &mut dyn Write) -> IoResult<()> {
+    let mut declared = false;
+
+    for posting in &transaction.postings {
+      if self.accounts.insert(posting.account.clone()) {
+        writeln!(out, "account {}", posting.account)?;
+        declared = true;
+      }
+
+      for commodity in posting.amount.iter().flat_map(Amount::commodities) {
+        if self.commodities.insert(commodity.to_string()) {
+          writeln!(out, "commodity {}", format_commodity(commodity))?;
+          declared = true;
+        }
+      }
+    }
+
+    if self.payees.insert(transaction.payee.clone()) {
+      writeln!(out, "payee {}", transaction.payee)?;
+      declared = true;
+    }
+
+    if declared {
+      writeln!(out)?;
+    }
+    Ok(())
+  }
+}
+
+
+/// The accounts and commodities referenced by transactions.
+#[derive(Debug, Default)]
+pub struct References {
+  /// The referenced accounts.
+  pub accounts: BTreeSet<String>,
+  /// The referenced commodities.
+  pub commodities: BTreeSet<String>,
+}
+
+impl References {
+  /// Record everything referenced by the provided transaction.
+  pub fn record(&mut self, transaction: &Transaction) {
+    for posting in &transaction.postings {
+      self.accounts.insert(posting.account.clone());
+      self.commodities.extend(
+        posting
+          .amount
+          .iter()
+          .flat_map(Amount::commodities)
+          .map(str::to_string),
+      );
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that transactions are formatted as expected.
+  #[test]
+  fn format_transaction() {
+    let mut transaction = Transaction::new(
+      NaiveDate::from_ymd_opt(2021, 6, 17).unwrap(),
+      Some("BUY".to_string()),
+      "ABC Holdings",
+    );
+    transaction.notes.push("id: 1234".to_string());
+
+    let mut posting = Posting::new(
+      "Assets:Investments",
+      Some(Amount::Price {
+        quantity: Num::from(10),
+        commodity: "ABC".to_string(),
+        price: Num::new(845, 2),
+        currency: "USD".to_string(),
+        balance: None,
+      }),
+    );
+    posting.notes.push("isin: US0000000001".to_string());
+    transaction.postings.push(posting);
+    transaction
+      .postings
+      .push(Posting::new("Assets:Brokerage", None));
+
+    let expected = r#"2021-06-17 * (BUY) ABC Holdings
+  ; id: 1234
+  Assets:Investments                                              10 ABC @ 422.50 USD
+  ; isin: US0000000001
+  Assets:Brokerage
+"#;
+    assert_eq!(transaction.to_string(), expected);
+  }
+
+  /// Check that postings of a transaction stay aligned even if amounts
+  /// exceed the default column widths.
+  #[test]
+  fn format_transaction_wide() {
+    let mut transaction = Transaction::new(
+      NaiveDate::from_ymd_opt(2021, 6, 17).unwrap(),
+      None,
+      "ABC Holdings",
+    );
+    transaction.postings.push(Posting::new(
+      "Assets:Investments",
+      Some(Amount::Price {
+        quantity: Num::from(12345678901234567u64),
+        commodity: "ABC".to_string(),
+        price: Num::from(1),
+        currency: "USD".to_string(),
+        balance: None,
+      }),
+    ));
+    transaction.postings.push(Posting::new(
+      "Assets:Brokerage",
+      Some(Amount::cash(Num::from(-12345678901234567i64), "USD")),
+    ));
+
+    let expected = r#"2021-06-17 * ABC Holdings
+  Assets:Investments                                         12345678901234567 ABC @ 1.00 USD
+  Assets:Brokerage                                       -12345678901234567.00 USD
+"#;
+    assert_eq!(transaction.to_string(), expected);
+  }
+
+  /// Check that periodic transactions are formatted as expected, with
+  /// postings aligned just as for regular transactions.
+  #[test]
+  fn format_periodic() {
+    let mut periodic = Periodic::new("monthly");
+    periodic.notes.push("ADR fee".to_string());
+    periodic.postings.push(Posting::new(
+      "Expenses:Broker:Fee:With:A:Name:Exceeding:The:Default:Width",
+      Some(Amount::cash(Num::new(1999, 100), "USD")),
+    ));
+    periodic
+      .postings
+      .push(Posting::new("Assets:Brokerage", None));
+
+    let expected = r#"~ monthly
+  ; ADR fee
+  Expenses:Broker:Fee:With:A:Name:Exceeding:The:Default:Width          19.99 USD
+  Assets:Brokerage
+"#;
+    assert_eq!(periodic.to_string(), expected);
+  }
+
+  /// Check that balance assertions are formatted as expected.
+  #[test]
+  fn format_assertion() {
+    let amount = Amount::Assertion {
+      commodity: "SPY240419C00520000".to_string(),
+      balance: Num::from(-2),
+    };
+    let posting = Posting::new("Assets:Investments", Some(amount));
+    let expected = r#"  Assets:Investments                                               0 "SPY240419C00520000" = -2 "SPY240419C00520000""#;
+    assert_eq!(posting.to_string(), expected);
+  }
+
+  /// Check that colored transactions only differ from plain ones by
+  /// the escape sequences added.
+  #[test]
+  fn color_transaction() {
+    let mut transaction = Transaction::new(
+      NaiveDate::from_ymd_opt(2021, 6, 17).unwrap(),
+      None,
+      "ABC Holdings",
+    );
+    let mut posting = Posting::new(
+      "Assets:Investments",
+      Some(Amount::cash(Num::from(-10), "USD")),
+    );
+    posting.is_virtual = true;
+    posting.notes.push("isin: US0000000001".to_string());
+    transaction.postings.push(posting);
+    transaction
+      .postings
+      .push(Posting::new("Assets:Brokerage", None));
+
+    let colored = Colored(&transaction).to_string();
+    assert!(colored.contains(&format!("{BLUE}  (Assets:Investments){RESET}{RED}")));
+
+    let plain = [BOLD, DIM, RED, YELLOW, BLUE, RESET]
+      .iter()
+      .fold(colored, |text, code| text.replace(code, ""));
+    assert_eq!(plain, transaction.to_string());
+  }
+
+  /// Check that we can parse price directives.
+  #[test]
+  fn parse_price_directives() {
+    let directive = format_price_directive(
+      NaiveDate::from_ymd_opt(2021, 6, 15).unwrap(),
+      "FUND1",
+      &Num::new(933, 100),
+      "USD",
+    );
+    assert_eq!(
+      parse_price_directive(&directive),
+      Some(("FUND1".to_string(), Num::new(933, 100)))
+    );
+    assert_eq!(
+      parse_price_directive("P 2021-06-15 XYZ USD 10"),
+      Some(("XYZ".to_string(), Num::from(10)))
+    );
+    assert_eq!(parse_price_directive("; P 2021-06-15 XYZ USD 10"), None);
+  }
+
+  /// Check that we declare everything a transaction uses exactly once.
+  #[test]
+  fn declare_once() {
+    let mut transaction = Transaction::new(
+      NaiveDate::from_ymd_opt(2021, 6, 17).unwrap(),
+      None,
+      "Transfer",
+    );
+    transaction.postings.push(Posting::new(
+      "Assets:Brokerage",
+      Some(Amount::cash(Num::from(1000), "USD")),
+    ));
+    transaction.postings.push(Posting::new("XXX", None));
+
+    let mut declarations = Declarations::default();
+    let mut out = Vec::new();
+    let () = declarations.declare(&transaction, &mut out).unwrap();
+    let expected = r#"account Assets:Brokerage
+commodity USD
+account XXX
+payee Transfer
+
+"#;
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+    let mut out = Vec::new();
+    let () = declarations.declare(&transaction, &mut out).unwrap();
+    assert!(out.is_empty());
+  }
+
+  /// Check that we record the accounts and commodities transactions
+  /// reference.
+  #[test]
+  fn record_references() {
+    let mut transaction = Transaction::new(
+      NaiveDate::from_ymd_opt(2021, 6, 17).unwrap(),
+      None,
+      "ABC Holdings",
+    );
+    transaction.postings.push(Posting::new(
+      "Assets:Investments:ABC",
+      Some(Amount::Price {
+        quantity: Num::from(10),
+        commodity: "ABC".to_string(),
+        price: Num::from(5),
+        currency: "USD".to_string(),
+        balance: None,
+      }),
+    ));
+    transaction.postings.push(Posting::new("XXX", None));
+
+    let mut references = References::default();
+    let () = references.record(&transaction);
+    let () = references.record(&transaction);
+    assert_eq!(
+      references.accounts.into_iter().collect::<Vec<_>>(),
+      ["Assets:Investments:ABC", "XXX"]
+    );
+    assert_eq!(
+      references.commodities.into_iter().collect::<Vec<_>>(),
+      ["ABC", "USD"]
+    );
+  }
+}
@@ -0,0 +1,206 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use chrono::NaiveDate;
+
+use num_decimal::Num;
+
+
+/// An open lot of shares.
+#[derive(Debug)]
+struct Lot {
+  /// The date at which the shares were acquired.
+  acquired: NaiveDate,
+  /// The number of shares remaining in the lot.
+  quantity: Num,
+  /// The price per share paid.
+  price: Num,
+}
+
+
+/// The (partial) sale of a lot.
+#[derive(Debug, PartialEq)]
+pub struct Sale {
+  /// The symbol of the asset sold.
+  pub symbol: String,
+  /// The number of shares sold.
+  pub quantity: Num,
+  /// The date at which the shares were acquired.
+  pub acquired: NaiveDate,
+  /// The date at which the shares were sold.
+  pub sold: NaiveDate,
+  /// The proceeds of the sale.
+  pub proceeds: Num,
+  /// The cost basis of the shares sold.
+  pub basis: Num,
+}
+
+impl Sale {
+  /// Calculate the gain (or, if negative, loss) of the sale.
+  pub fn gain(&self) -> Num {
+    &self.proceeds - &self.basis
+  }
+
+  /// Calculate the number of days the shares were held for.
+  pub fn holding_period(&self) -> i64 {
+    (self.sold - self.acquired).num_days()
+  }
+
+  /// Check whether the sale is long-term, i.e., the shares were held
+  /// for more than the given number of days.
+  pub fn is_long_term(&self, long_term_days: i64) -> bool {
+    self.holding_period() > long_term_days
+  }
+}
+
+
+/// Open lots of all held assets, matched against sales on a first in,
+/// first out basis.
+///
+/// Prices are expected to include fees, if any.
+#[derive(Debug, Default)]
+pub struct Lots(HashMap<String, VecDeque<Lot>>);
+
+impl Lots {
+  /// Record the purchase of shares.
+  pub fn buy(&mut self, symbol: &str, date: NaiveDate, quantity: Num, price: Num) {
+    let lot = Lot {
+      acquired: date,
+      quantity,
+      price,
+    };
+    let () = self.0.entry(symbol.to_string()).or_default().push_back(lot);
+  }
+
+  /// Retrieve the number of shares of the asset with the given symbol
+  /// currently held.
+  pub fn held(&self, symbol: &str) -> Num {
+    self
+      .0
+      .get(symbol)
+      .map(|lots| {
+        lots
+          .iter()
+          .fold(Num::default(), |held, lot| held + &lot.quantity)
+      })
+      .unwrap_or_default()
+  }
+
+  /// Record the sale of shares, returning the sales of the individual
+  /// lots involved.
+  pub fn sell(
+    &mut self,
+    symbol: &str,
+    date: NaiveDate,
+    mut quantity: Num,
+    price: Num,
+  ) -> Result<Vec<Sale>> {
+    let mut sales = Vec::new();
+
+    while quantity.is_positive() {
+      let lots = self.0.get_mut(symbol);
+      let lot = match lots.and_then(|lots| lots.front_mut()) {
+        Some(lot) => lot,
+        None => bail!(
+          "sale of {} {} on {} exceeds shares held; short sales are not supported",
+          quantity,
+          symbol,
+          date
+        ),
+      };
+
+      let sold = if lot.quantity <= quantity {
+        lot.quantity.clone()
+      } else {
+        quantity.clone()
+      };
+
+      let sale = Sale {
+        symbol: symbol.to_string(),
+        quantity: sold.clone(),
+        acquired: lot.acquired,
+        sold: date,
+        proceeds: &sold * &price,
+        basis: &sold * &lot.price,
+      };
+      let () = sales.push(sale);
+
+      lot.quantity -= &sold;
+      quantity -= &sold;
+
+      if lot.quantity.is_zero() {
+        // The lot is exhausted. We know it exists.
+        let _lot = self.0.get_mut(symbol).unwrap().pop_front();
+      }
+    }
+    Ok(sales)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that sales get matched against lots on a first in, first out
+  /// basis.
+  #[test]
+  fn fifo_matching() {
+    let date = |day| NaiveDate::from_ymd_opt(2021, 6, day).unwrap();
+
+    let mut lots = Lots::default();
+    let () = lots.buy("XYZ", date(1), Num::from(10), Num::from(5));
+    let () = lots.buy("XYZ", date(2), Num::from(10), Num::from(7));
+    assert_eq!(lots.held("XYZ"), Num::from(20));
+    assert_eq!(lots.held("ABC"), Num::from(0));
+
+    let sales = lots
+      .sell("XYZ", date(3), Num::from(15), Num::from(8))
+      .unwrap();
+    assert_eq!(sales.len(), 2);
+    assert_eq!(sales[0].acquired, date(1));
+    assert_eq!(sales[0].quantity, Num::from(10));
+    assert_eq!(sales[0].gain(), Num::from(30));
+    assert_eq!(sales[1].acquired, date(2));
+    assert_eq!(sales[1].quantity, Num::from(5));
+    assert_eq!(sales[1].basis, Num::from(35));
+
+    let sales = lots
+      .sell("XYZ", date(4), Num::from(5), Num::from(6))
+      .unwrap();
+    assert_eq!(sales.len(), 1);
+    assert_eq!(sales[0].gain(), Num::from(-5));
+    assert_eq!(lots.held("XYZ"), Num::from(0));
+
+    let result = lots.sell("XYZ", date(5), Num::from(1), Num::from(6));
+    assert!(result.is_err());
+  }
+
+  /// Check that we correctly determine holding periods.
+  #[test]
+  fn holding_period() {
+    let date = |year, month, day| NaiveDate::from_ymd_opt(year, month, day).unwrap();
+
+    let mut lots = Lots::default();
+    let () = lots.buy("XYZ", date(2020, 6, 1), Num::from(10), Num::from(5));
+
+    let sales = lots
+      .sell("XYZ", date(2021, 6, 1), Num::from(5), Num::from(8))
+      .unwrap();
+    assert_eq!(sales[0].holding_period(), 365);
+    assert!(!sales[0].is_long_term(365));
+
+    let sales = lots
+      .sell("XYZ", date(2021, 6, 2), Num::from(5), Num::from(8))
+      .unwrap();
+    assert_eq!(sales[0].holding_period(), 366);
+    assert!(sales[0].is_long_term(365));
+  }
+}
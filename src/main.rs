@@ -9,32 +9,73 @@
 )]
 
 mod args;
+mod atomic;
+mod cache;
+mod client;
+mod config;
+mod context;
+mod descriptions;
+mod dump;
+mod endpoints;
+mod failure;
+mod fallback;
+mod filter;
+mod git;
+mod input;
+mod json;
+mod ledger;
+mod lots;
+mod notify;
+mod occ;
+mod output;
+mod pager;
+mod registry;
+mod state;
+mod warnings;
 
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::cmp::min;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::fs::read;
 use std::fs::File;
 use std::future::Future;
 use std::io::stderr;
 use std::io::stdout;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
 use std::io::Write;
+use std::iter::once;
+use std::ops::Add;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::exit;
-use std::str::FromStr as _;
+use std::slice;
 use std::sync::Arc;
+use std::time::Duration as StdDuration;
 
 use apca::api::v2::account;
 use apca::api::v2::account_activities;
+use apca::api::v2::asset;
+use apca::api::v2::calendar;
 use apca::api::v2::clock;
+use apca::api::v2::order;
+use apca::api::v2::positions;
+use apca::api::v2::watchlist;
+use apca::api::v2::watchlists;
 use apca::data::v2::bars;
 use apca::ApiInfo;
-use apca::Client;
 use apca::RequestError;
 
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::ensure;
-use anyhow::Context;
+use anyhow::Context as _;
 use anyhow::Result;
 
 use chrono::DateTime;
@@ -42,12 +83,15 @@ use chrono::Datelike as _;
 use chrono::Duration;
 use chrono::Local;
 use chrono::NaiveDate;
+use chrono::NaiveTime;
 use chrono::TimeZone as _;
 use chrono::Utc;
 use chrono_tz::America::New_York;
+use chrono_tz::Tz;
 
 use futures::future::join;
-use futures::future::ready;
+use futures::future::select;
+use futures::future::Either;
 use futures::future::Shared;
 use futures::stream::iter;
 use futures::FutureExt as _;
@@ -55,49 +99,345 @@ use futures::StreamExt as _;
 use futures::TryFutureExt as _;
 use futures::TryStreamExt as _;
 
-use num_decimal::Num;
+use http_endpoint::Endpoint;
 
-use once_cell::sync::Lazy;
+use num_decimal::Num;
 
 use regex::Regex;
 
+use serde::Serialize;
+
 use serde_json::from_reader as json_from_reader;
+use serde_json::to_string_pretty as to_json_pretty;
+use serde_json::to_value as to_json_value;
+use serde_json::to_writer_pretty as to_json_writer_pretty;
+use serde_json::Map as JsonMap;
+use serde_json::Value as JsonValue;
 
 use structopt::StructOpt as _;
 
+use tokio::pin;
 use tokio::runtime::Builder;
+use tokio::signal::ctrl_c;
+use tokio::spawn;
+use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
+use tokio::time::timeout as with_timeout;
 
+use tracing::debug;
+use tracing::info;
 use tracing::subscriber::set_global_default as set_global_subscriber;
 use tracing::warn;
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::time::SystemTime;
 use tracing_subscriber::FmtSubscriber;
 
+use crate::args::Activity as ActivityArgs;
+use crate::args::Adjustment;
 use crate::args::Args;
+use crate::args::Begin;
+use crate::args::Category;
 use crate::args::Command;
+use crate::args::Comments;
+use crate::args::Connection;
+use crate::args::DividendDate;
+use crate::args::PriceField;
+use crate::args::PriceOptions;
+use crate::args::RegistryCommand;
+use crate::args::SortOrder;
+use crate::args::TimeFrame;
+use crate::args::TradeSummary;
+use crate::args::DEFAULT_BROKERAGE_ACCOUNT;
+use crate::args::DEFAULT_BROKERAGE_FEE_ACCOUNT;
+use crate::args::DEFAULT_DIVIDEND_ACCOUNT;
+use crate::args::DEFAULT_FINRA_TAF_ACCOUNT;
+use crate::args::DEFAULT_INVESTMENT_ACCOUNT;
+use crate::args::DEFAULT_SEC_FEE_ACCOUNT;
+use crate::atomic::AtomicFile;
+use crate::cache::Cache;
+use crate::client::Client;
+use crate::client::Proxy;
+use crate::config::Config;
+use crate::config::Profile;
+use crate::context::Accounts;
+use crate::context::Context;
+use crate::context::TransferRule;
+use crate::context::VirtualPostings;
+use crate::descriptions::Fee;
+use crate::dump::Dump;
+use crate::endpoints::is_crypto_transfer;
+use crate::endpoints::CryptoBarReq;
+use crate::endpoints::GetActivities;
+use crate::endpoints::GetActivitiesError;
+use crate::endpoints::GetAssetName;
+use crate::endpoints::GetAssetNameError;
+use crate::endpoints::GetCryptoBar;
+use crate::endpoints::GetCryptoBarError;
+use crate::endpoints::GetPortfolioHistory;
+use crate::endpoints::GetPortfolioHistoryError;
+use crate::endpoints::PortfolioHistoryReq;
+use crate::endpoints::StatusActivity;
+use crate::failure::exit_code;
+use crate::failure::Classify as _;
+use crate::failure::Failure;
+use crate::failure::PartialFailure;
+use crate::fallback::PriceSource;
+use crate::filter::Filter;
+use crate::ledger::format_commodity;
+use crate::ledger::format_price;
+use crate::ledger::format_price_directive;
+use crate::ledger::parse_price_directive;
+use crate::ledger::Amount;
+use crate::ledger::Colored;
+use crate::ledger::Declarations;
+use crate::ledger::Periodic;
+use crate::ledger::Posting;
+use crate::ledger::References;
+use crate::ledger::Transaction;
+use crate::lots::Lots;
+use crate::notify::Summary;
+use crate::occ::contract_multiplier;
+use crate::occ::OptionContract;
+use crate::output::Output;
+use crate::registry::Registry;
+use crate::state::State;
+use crate::warnings::Kind as WarningKind;
 
 const ALPACA: &str = "Alpaca Securities LLC";
+const INTEREST_ACCOUNT: &str = "Income:Interest";
+/// The sub-account of the investment account to book return of capital
+/// distributions against when they reduce the cost basis.
+const BASIS_ADJUSTMENT_ACCOUNT: &str = "Basis Adjustment";
+/// The number of years to search backwards for the last price of an
+/// asset that is no longer active.
+const DELISTED_LOOKBACK_YEARS: usize = 5;
+/// The API base URL used for profiles that do not specify one. This is
+/// the same default that `ApiInfo::from_env` uses.
+const DEFAULT_API_BASE_URL: &str = "https://paper-api.alpaca.markets";
+/// The currency assumed when rendering fixtures.
+const FIXTURE_CURRENCY: &str = "USD";
+/// The number of consecutive months a charge has to occur in to be
+/// considered recurring.
+const RECURRING_MONTHS: usize = 3;
+/// The maximum number of times to retry a request that got rejected
+/// because of rate limiting.
+const RATE_LIMIT_RETRIES: u32 = 5;
+/// The maximum time to wait before issuing a request because of rate
+/// limiting. Alpaca's rate limit windows span a minute.
+const RATE_LIMIT_MAX_DELAY: StdDuration = StdDuration::from_secs(60);
+
+
+/// Look up the name to use as payee for the asset with the given
+/// symbol.
+fn lookup_name<'r>(registry: &'r Registry, symbol: &str) -> Result<Cow<'r, str>> {
+  if let Some(entry) = registry.get(symbol) {
+    Ok(Cow::from(entry.name()))
+  } else if let Some(contract) = OptionContract::parse(symbol) {
+    // Option contracts are unlikely to be present in the registry, so
+    // fall back to a readable representation of the contract.
+    Ok(Cow::from(contract.to_string()))
+  } else {
+    bail!("symbol {} not present in registry", symbol)
+  }
+}
+
+/// Retrieve the security identifiers known for the given symbol as
+/// metadata tags.
+fn security_ids(registry: &Registry, symbol: &str) -> Vec<String> {
+  let mut tags = Vec::new();
+  if let Some(entry) = registry.get(symbol) {
+    if let Some(isin) = entry.isin() {
+      tags.push(format!("isin: {isin}"));
+    }
+    if let Some(cusip) = entry.cusip() {
+      tags.push(format!("cusip: {cusip}"));
+    }
+  }
+  tags
+}
+
+/// Translate a generated piece of text using the configured
+/// translations, substituting the provided `{placeholder}` arguments.
+fn translate(ctx: &Context, text: &str, args: &[(&str, &str)]) -> String {
+  let mut text = ctx
+    .translations
+    .get(text)
+    .map(String::as_str)
+    .unwrap_or(text)
+    .to_string();
+  for (name, value) in args {
+    text = text.replace(&format!("{{{name}}}"), value);
+  }
+  text
+}
+
+/// Normalize a payee name using the provided alias map.
+fn payee<'n>(payees: &'n HashMap<String, String>, name: &'n str) -> &'n str {
+  payees.get(name).map(String::as_str).unwrap_or(name)
+}
+
+/// Sum up the provided numbers.
+fn sum<I, T>(nums: I) -> Num
+where
+  I: IntoIterator<Item = T>,
+  Num: Add<T, Output = Num>,
+{
+  nums.into_iter().fold(Num::default(), |sum, num| sum + num)
+}
+
+/// Calculate the absolute value of a number.
+fn abs(num: &Num) -> Num {
+  if num.is_negative() {
+    -num
+  } else {
+    num.clone()
+  }
+}
+
+/// Render a value as the string the Alpaca API uses to represent it.
+fn api_str<T>(value: &T) -> String
+where
+  T: Serialize,
+{
+  to_json_value(value)
+    .ok()
+    .and_then(|value| value.as_str().map(str::to_string))
+    .unwrap_or_default()
+}
+
+/// Retrieve the transaction code to emit for an activity of the given
+/// kind, if one is configured.
+fn transaction_code(ctx: &Context, kind: &str) -> Option<String> {
+  ctx.transaction_codes.get(&kind.to_uppercase()).cloned()
+}
 
+/// Retrieve the comments to emit for an activity with the given ID and
+/// description, according to the configured comment mode.
+fn comments(ctx: &Context, id: &str, description: Option<&str>) -> Vec<String> {
+  let mut comments = Vec::new();
+  if ctx.comments != Comments::None {
+    if let Some(description) = description {
+      comments.push(description.to_string());
+    }
+  }
+  if ctx.comments == Comments::Full {
+    comments.push(format!("id: {id}"));
+  }
+  comments
+}
+
+/// Record an explanation of a classification decision, if configured.
+fn explain<F>(ctx: &Context, notes: &mut Vec<String>, explanation: F)
+where
+  F: FnOnce() -> String,
+{
+  if ctx.explain {
+    notes.push(format!("explain: {}", explanation()));
+  }
+}
+
+/// Handle a non-trade activity that we are unable to represent,
+/// warning about it and, if configured, emitting it as a commented out
+/// block for manual entry.
+fn ignore_non_trade(
+  out: &mut dyn Write,
+  non_trade: &account_activities::NonTradeActivity,
+  reason: &str,
+  ctx: &Context,
+) -> Result<()> {
+  if ctx.strict {
+    let () = ctx.ignored.borrow_mut().push(format!(
+      "{reason} {} on {}",
+      non_trade.id,
+      format_date(non_trade.date)
+    ));
+  } else {
+    warn!("ignoring {reason}: {non_trade:#?}");
+  }
+  let () = ctx.record_warning(
+    WarningKind::IgnoredActivity,
+    Some(&non_trade.id),
+    format!("ignored {reason} on {}", format_date(non_trade.date)),
+  );
+
+  if ctx.emit_unknown {
+    let json = to_json_pretty(&json::non_trade(non_trade))
+      .context("failed to serialize account activity")?;
+    writeln!(out, "; ignored {reason}:")?;
+    for line in json.lines() {
+      writeln!(out, "; {line}")?;
+    }
+    writeln!(out)?;
+  }
+  Ok(())
+}
+
+/// Fail if running in strict mode and any activities got ignored,
+/// listing all of them.
+fn check_ignored(ctx: &Context) -> Result<()> {
+  let ignored = ctx.ignored.borrow();
+  if !ignored.is_empty() {
+    let error = anyhow!(
+      "encountered {} activities that can't be represented:\n  {}",
+      ignored.len(),
+      ignored.join("\n  ")
+    );
+    return Err(error).classify(Failure::Classification)
+  }
+  Ok(())
+}
+
+/// Retrieve the date of a trade in the given time zone.
+fn trade_date(trade: &account_activities::TradeActivity, timezone: Tz) -> NaiveDate {
+  trade.transaction_time.with_timezone(&timezone).date_naive()
+}
+
+/// Retrieve the date of an Alpaca provided activity, deriving that of
+/// trades in the given time zone.
+///
+/// Non-trade activities are reported with a date only, which is taken
+/// as is.
+fn activity_date(activity: &account_activities::Activity, timezone: Tz) -> NaiveDate {
+  match activity {
+    account_activities::Activity::Trade(trade) => trade_date(trade, timezone),
+    account_activities::Activity::NonTrade(non_trade) => non_trade.date.date_naive(),
+  }
+}
 
-// TODO: Presumably, with fractional shares being supported by the API
-//       we need to support a floating point value here. But that likely
-//       needs adjustments in `apca` as well.
-static TAF_RE: Lazy<Regex> =
-  Lazy::new(|| Regex::new(r"TAF fee for proceed of (?P<shares>\d+) shares").unwrap());
-// TODO: It is unclear whether we can always assume a floating point
-//       representation like we do here.
-static REG_RE: Lazy<Regex> =
-  Lazy::new(|| Regex::new(r"REG fee for proceed of \$(?P<proceeds>\d+\.\d+)").unwrap());
-static ADR_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^ADR Fees").unwrap());
-static ACQ_PRICE_RE: Lazy<Regex> =
-  Lazy::new(|| Regex::new(r"Cash Merger \$(?P<price>\d+\.\d+)").unwrap());
+/// Retrieve the time at which the given day starts in the given time
+/// zone.
+fn day_start(date: NaiveDate, timezone: Tz) -> DateTime<Utc> {
+  let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+  timezone
+    .from_local_datetime(&midnight)
+    .earliest()
+    // Midnight may be skipped when daylight saving time starts. Falling
+    // back to UTC may get us a few hours early, which is harmless, as
+    // activities are filtered by date later on anyway.
+    .map(|time| time.with_timezone(&Utc))
+    .unwrap_or_else(|| Utc.from_utc_datetime(&midnight))
+}
 
+/// Emit a transaction, passing it through the configured filter first.
+fn emit_transaction(out: &mut dyn Write, transaction: Transaction, ctx: &Context) -> Result<()> {
+  let transaction = match &ctx.filter {
+    Some(filter) => filter.apply(&transaction)?,
+    None => Some(transaction),
+  };
 
-/// Format a price value.
-fn format_price(price: &Num, currency: &str) -> String {
-  // We would like to ensure emitting prices with at least two post
-  // decimal positions, for consistency.
-  format!("{} {}", price.display().min_precision(2), currency)
+  if let Some(transaction) = transaction {
+    let () = ctx.references.borrow_mut().record(&transaction);
+    if let Some(declarations) = ctx.declarations.borrow_mut().as_mut() {
+      let () = declarations.declare(&transaction, out)?;
+    }
+    if ctx.preview {
+      writeln!(out, "{}", Colored(&transaction))?;
+    } else {
+      writeln!(out, "{transaction}")?;
+    }
+  }
+  Ok(())
 }
 
 /// Format a date time as a date.
@@ -105,95 +445,388 @@ fn format_date(time: DateTime<Utc>) -> String {
   time.date_naive().format("%Y-%m-%d").to_string()
 }
 
+/// Describe the premium received for selling an option contract on the
+/// given date, amortized over the days until the contract expires.
+/// `premium` is the total amount received, i.e., it already accounts
+/// for the contract multiplier. Returns `None` if the symbol does not
+/// denote an option contract.
+fn premium_note(symbol: &str, date: NaiveDate, premium: &Num, currency: &str) -> Option<String> {
+  let contract = OptionContract::parse(symbol)?;
+  let days = (contract.expiration - date).num_days();
+  let note = if days > 0 {
+    let per_day = (premium / Num::from(days)).round_with(2);
+    format!(
+      "premium: {} over {days} days to expiry ({}/day)",
+      format_price(premium, currency),
+      format_price(&per_day, currency),
+    )
+  } else {
+    format!("premium: {} at expiry", format_price(premium, currency))
+  };
+  Some(note)
+}
+
+/// Print a transaction for the provided trades, which are all expected
+/// to belong to the same order.
 fn print_trade(
-  trade: &account_activities::TradeActivity,
+  out: &mut dyn Write,
+  trades: &[account_activities::TradeActivity],
   fees: &[account_activities::NonTradeActivity],
-  investment_account: &str,
-  brokerage_account: &str,
-  brokerage_fee_account: &str,
-  sec_fee_account: &str,
-  finra_taf_account: &str,
-  registry: &HashMap<String, String>,
-  currency: &str,
+  order: Option<&order::Order>,
+  summarize: bool,
+  ctx: &Context,
 ) -> Result<()> {
-  let name = registry
-    .get(&trade.symbol)
-    .ok_or_else(|| anyhow!("symbol {} not present in registry", trade.symbol))?;
+  let trade = trades
+    .first()
+    .ok_or_else(|| anyhow!("no trades provided"))?;
+  // The legs of a multi-leg order (e.g., an options spread) share the
+  // order but are for different symbols, so name all of them.
+  let mut names = Vec::<Cow<'_, str>>::new();
+  for trade in trades {
+    let name = lookup_name(&ctx.registry, &trade.symbol)?;
+    if !names.contains(&name) {
+      names.push(name);
+    }
+  }
+  let name = names.join(", ");
+  let strategy = order.and_then(|order| {
+    ctx
+      .strategies
+      .iter()
+      .find(|strategy| order.client_order_id.starts_with(&strategy.prefix))
+  });
+
+  let mut transaction = Transaction::new(
+    trade_date(trade, ctx.timezone),
+    transaction_code(ctx, json::side(trade.side)),
+    payee(&ctx.payees, &name),
+  );
+  if let Some(strategy) = strategy {
+    transaction
+      .notes
+      .push(format!("strategy: {}", strategy.name));
+    explain(ctx, &mut transaction.notes, || {
+      format!(
+        "strategy selected by client order ID prefix '{}'",
+        strategy.prefix
+      )
+    });
+  }
+  if ctx.order_details {
+    if let Some(order) = order {
+      transaction
+        .notes
+        .push(format!("order-type: {}", api_str(&order.type_)));
+      transaction
+        .notes
+        .push(format!("time-in-force: {}", api_str(&order.time_in_force)));
+      if let Some(submitted_at) = order.submitted_at {
+        transaction
+          .notes
+          .push(format!("submitted: {}", submitted_at.to_rfc3339()));
+      }
+    }
+  }
 
-  let multiplier = match trade.side {
+  let multiplier = |trade: &account_activities::TradeActivity| match trade.side {
     account_activities::Side::Buy => 1,
     account_activities::Side::Sell => -1,
     account_activities::Side::ShortSell => -1,
     _ => panic!("encountered unexpected trade side: {:?}", trade.side),
   };
 
-  println!(
-    r#"{date} * {name}
-  {from:<51}  {qty:>13} {sym} @ {price}"#,
-    date = format_date(trade.transaction_time),
-    name = name,
-    from = investment_account,
-    qty = &trade.quantity * multiplier,
-    sym = trade.symbol,
-    price = format_price(&trade.price, currency),
-  );
+  // Classify all fees before printing anything, so that we do not emit
+  // a partial transaction should classification fail.
+  let fees = fees
+    .iter()
+    .map(|fee| {
+      classify_fee(fee)
+        .map(|kind| (fee, fee_account(&kind, &ctx.accounts), kind))
+        .with_context(|| format!("failed to classify fee {}", fee.id))
+    })
+    .collect::<Result<Vec<_>>>()?;
 
-  let mut total_fees = Num::from(0);
-  for fee in fees {
-    let net_amount = &-&fee.net_amount;
-    let (to, description) = classify_fee(
-      fee,
-      brokerage_fee_account,
-      sec_fee_account,
-      finra_taf_account,
-    )?;
-    println!(
-      r#"  ; {desc}
-  {to:<51}    {total:>15}"#,
-      desc = description,
-      to = to,
-      total = format_price(net_amount, currency),
-    );
+  for trade in trades {
+    transaction.notes.extend(comments(ctx, &trade.id, None));
+  }
+
+  // Alpaca reports the prices of option contracts per share of the
+  // underlying, but we book whole contracts.
+  let price =
+    |trade: &account_activities::TradeActivity| &trade.price * contract_multiplier(&trade.symbol);
+
+  // Fractional quantities are the result of notional orders, for which
+  // the broker settles each fill at the cent-rounded amount. Balance
+  // against that instead of the exact product, which may be off by a
+  // fraction of a cent.
+  let settled = |trade: &account_activities::TradeActivity| {
+    let amount = price(trade) * &trade.quantity;
+    if trade.quantity.fract().is_zero() {
+      amount
+    } else {
+      amount.round_with(2)
+    }
+  };
+
+  // The cash flowing into the brokerage account as a result of the
+  // trades, not accounting for fees.
+  let cash_flow = if summarize {
+    let quantity = sum(trades.iter().map(|trade| &trade.quantity));
+    let total = sum(trades.iter().map(settled));
+    let multiplier = multiplier(trade);
+
+    // Using a total cost makes Ledger infer the weighted average price
+    // without us having to round it.
+    let amount = Amount::Cost {
+      quantity: &quantity * multiplier,
+      commodity: trade.symbol.clone(),
+      cost: total.clone(),
+      currency: ctx.currency.clone(),
+    };
+    let investment_account = ctx.investment_account(&trade.symbol, strategy);
+    let mut posting = Posting::new(investment_account.as_ref(), Some(amount));
+    if ctx.security_ids {
+      posting.notes = security_ids(&ctx.registry, &trade.symbol);
+    }
+    if ctx.option_premium && multiplier < 0 {
+      let date = trade_date(trade, ctx.timezone);
+      posting
+        .notes
+        .extend(premium_note(&trade.symbol, date, &total, &ctx.currency));
+    }
+    transaction.postings.push(posting);
+    total * -multiplier
+  } else {
+    let mut cash_flow = Num::from(0);
+    for trade in trades {
+      debug_assert_eq!(trade.order_id, trades[0].order_id);
+
+      let multiplier = multiplier(trade);
+      let investment_account = ctx.investment_account(&trade.symbol, strategy);
+      let amount = Amount::Price {
+        quantity: &trade.quantity * multiplier,
+        commodity: trade.symbol.clone(),
+        price: price(trade),
+        currency: ctx.currency.clone(),
+        balance: None,
+      };
+      let mut posting = Posting::new(investment_account.as_ref(), Some(amount));
+      if ctx.security_ids {
+        posting.notes = security_ids(&ctx.registry, &trade.symbol);
+      }
+      if ctx.fill_times {
+        let time = trade.transaction_time.with_timezone(&New_York);
+        posting
+          .notes
+          .push(format!("time: {}", time.format("%H:%M:%S")));
+      }
+      if ctx.option_premium && multiplier < 0 {
+        let date = trade_date(trade, ctx.timezone);
+        let premium = settled(trade);
+        posting
+          .notes
+          .extend(premium_note(&trade.symbol, date, &premium, &ctx.currency));
+      }
+      transaction.postings.push(posting);
+
+      cash_flow += settled(trade) * -multiplier;
+    }
+    cash_flow
+  };
 
-    total_fees += net_amount;
+  let total_fees = -sum(fees.iter().map(|(fee, ..)| &fee.net_amount));
+  if ctx.fee_totals && !fees.is_empty() {
+    let total_fees = format_price(&total_fees, &ctx.currency);
+    // The summary precedes the fee postings, so attach it to the last
+    // trade posting.
+    if let Some(posting) = transaction.postings.last_mut() {
+      posting.notes.push(translate(
+        ctx,
+        "total fees: {amount}",
+        &[("amount", &total_fees)],
+      ));
+    }
   }
 
-  println!(
-    "  {to:<51}    {total:>15}\n",
-    to = brokerage_account,
-    total = format_price(
-      &(&(&trade.price * &trade.quantity * -multiplier) - total_fees),
-      currency
-    ),
-  );
-  Ok(())
+  for (fee, to, kind) in fees {
+    let amount = Amount::cash(-&fee.net_amount, &ctx.currency);
+    let mut posting = Posting::new(to, Some(amount));
+    let notes = comments(ctx, &fee.id, fee.description.as_deref());
+    if ctx.comments == Comments::Full {
+      posting.notes = notes;
+    } else if let Some(previous) = transaction.postings.last_mut() {
+      // Traditionally, a fee's description precedes its posting.
+      previous.notes.extend(notes);
+    }
+    explain(ctx, &mut posting.notes, || explain_fee(&kind));
+    explain(ctx, &mut posting.notes, || {
+      explain_fee_association(&kind, trades)
+    });
+    transaction.postings.push(posting);
+  }
+
+  let amount = Amount::cash(cash_flow - total_fees, &ctx.currency);
+  transaction
+    .postings
+    .push(Posting::new(&ctx.accounts.brokerage, Some(amount)));
+
+  emit_transaction(out, transaction, ctx)
 }
 
 
-/// Classify a non-trade fee activity according to its description.
-fn classify_fee<'act, 'acc>(
-  non_trade: &'act account_activities::NonTradeActivity,
-  brokerage_fee_account: &'acc str,
-  sec_fee_account: &'acc str,
-  finra_taf_account: &'acc str,
-) -> Result<(&'acc str, &'act str)> {
+/// Classify a non-trade fee activity according to its description,
+/// returning `None` if the description is not understood.
+fn classify_fee(non_trade: &account_activities::NonTradeActivity) -> Option<Fee> {
   debug_assert_eq!(non_trade.type_, account_activities::ActivityType::Fee);
 
-  if let Some(description) = &non_trade.description {
-    if TAF_RE.is_match(description) {
-      Ok((finra_taf_account, description))
-    } else if REG_RE.is_match(description) {
-      Ok((sec_fee_account, description))
-    } else if ADR_RE.find(description).is_some() {
-      Ok((brokerage_fee_account, description))
-    } else {
-      bail!(
-        "failed to classify fee account activity with description: {}",
-        description
-      )
-    }
+  non_trade
+    .description
+    .as_deref()
+    .and_then(descriptions::parse_fee)
+}
+
+
+/// Retrieve the account to book a fee of the given kind against.
+fn fee_account<'acc>(fee: &Fee, accounts: &'acc Accounts) -> &'acc str {
+  match fee {
+    Fee::Taf { .. } => &accounts.finra_taf,
+    Fee::Reg { .. } => &accounts.sec_fee,
+    Fee::Adr { .. } => &accounts.brokerage_fee,
+    Fee::Reorg => &accounts.reorg_fee,
+    Fee::Transfer => &accounts.transfer_fee,
+  }
+}
+
+
+/// Explain the classification of a fee.
+fn explain_fee(fee: &Fee) -> String {
+  match fee {
+    Fee::Taf { shares } => format!("description matches TAF fee pattern for {shares} shares"),
+    Fee::Reg { proceeds } => {
+      format!("description matches REG fee pattern for proceeds of {proceeds}")
+    },
+    Fee::Adr {
+      symbol: Some(symbol),
+    } => {
+      format!("description matches ADR fee pattern for {symbol}")
+    },
+    Fee::Adr { symbol: None } => "description matches ADR fee pattern".to_string(),
+    Fee::Reorg => "description matches reorganization fee pattern".to_string(),
+    Fee::Transfer => "description matches transfer fee pattern".to_string(),
+  }
+}
+
+/// Explain which of the provided trades a fee got associated with.
+fn explain_fee_association(fee: &Fee, trades: &[account_activities::TradeActivity]) -> String {
+  let association = trades.iter().find_map(|trade| match fee {
+    Fee::Taf { shares } if &trade.quantity == shares => Some((trade, "share quantity")),
+    Fee::Reg { proceeds } if &(&trade.price * &trade.quantity) == proceeds => {
+      Some((trade, "proceeds"))
+    },
+    _ => None,
+  });
+
+  match association {
+    Some((trade, basis)) => format!("associated with trade {} by {basis}", trade.id),
+    None => "not associated with any trade".to_string(),
+  }
+}
+
+
+/// Check whether a dividend activity is actually a return of capital
+/// distribution.
+fn is_return_of_capital(non_trade: &account_activities::NonTradeActivity) -> bool {
+  non_trade.type_ == account_activities::ActivityType::DividendReturnOfCapital
+    || non_trade
+      .description
+      .as_ref()
+      .map(|description| descriptions::is_return_of_capital(description))
+      .unwrap_or(false)
+}
+
+/// Classify a dividend activity as either qualified or non-qualified,
+/// returning the account to use for it.
+fn classify_dividend<'acc>(
+  non_trade: &account_activities::NonTradeActivity,
+  accounts: &'acc Accounts,
+) -> &'acc str {
+  let non_qualified = is_return_of_capital(non_trade)
+    || non_trade
+      .description
+      .as_ref()
+      .map(|description| descriptions::is_non_qualified(description))
+      .unwrap_or(false);
+
+  if non_qualified {
+    &accounts.non_qualified_dividend
+  } else {
+    &accounts.dividend
+  }
+}
+
+
+/// Classify an interest activity as either stemming from the cash
+/// program or not, returning the account to use for it.
+fn classify_interest<'acc>(
+  non_trade: &account_activities::NonTradeActivity,
+  accounts: &'acc Accounts,
+) -> &'acc str {
+  let cash_program = non_trade
+    .description
+    .as_ref()
+    .map(|description| descriptions::is_cash_program_interest(description))
+    .unwrap_or(false);
+
+  if cash_program {
+    &accounts.cash_program_interest
+  } else {
+    &accounts.interest
+  }
+}
+
+/// Retrieve the last day of the given month.
+fn month_end(year: i32, month: u32) -> NaiveDate {
+  let (year, month) = if month == 12 {
+    (year + 1, 1)
+  } else {
+    (year, month + 1)
+  };
+  NaiveDate::from_ymd_opt(year, month, 1)
+    .and_then(|date| date.pred_opt())
+    .unwrap()
+}
+
+/// Determine the date to accrue interest paid on the given date at:
+/// the end of the month the payment's description names or, if it does
+/// not name one, the end of the month preceding the payment.
+fn interest_accrual_date(description: Option<&str>, paid: NaiveDate) -> NaiveDate {
+  match description.and_then(descriptions::interest_month) {
+    Some(month) => {
+      // Interest is paid for a past (or the current) month, so pick
+      // the most recent month of the given name.
+      let month = month.number_from_month();
+      let year = if month <= paid.month() {
+        paid.year()
+      } else {
+        paid.year() - 1
+      };
+      month_end(year, month).min(paid)
+    },
+    None => paid.with_day(1).and_then(|date| date.pred_opt()).unwrap(),
+  }
+}
+
+/// Explain the classification of a dividend activity.
+fn explain_dividend(non_trade: &account_activities::NonTradeActivity) -> String {
+  let description = non_trade.description.as_deref().unwrap_or_default();
+  if non_trade.type_ == account_activities::ActivityType::DividendReturnOfCapital {
+    "activity type denotes return of capital, booked as non-qualified".to_string()
+  } else if descriptions::is_return_of_capital(description) {
+    "description denotes return of capital, booked as non-qualified".to_string()
+  } else if descriptions::is_non_qualified(description) {
+    "description denotes non-qualified dividend".to_string()
   } else {
-    bail!("fee activity does not have a description")
+    "description has no non-qualified marker, booked as qualified".to_string()
   }
 }
 
@@ -212,126 +845,298 @@ fn extract_acquisition_share_price(
     .description
     .as_ref()
     .context("acquisition activity does not have a description")?;
-  let captures = ACQ_PRICE_RE
-    .captures(description)
+  let share_price = descriptions::acquisition_price(description)
     .with_context(|| "acquisition non-trade activity description could not be parsed")?;
-  let share_price = &captures["price"];
-  let share_price = Num::from_str(share_price)
-    .with_context(|| format!("failed to parse price string '{}' as number", share_price))?;
-
   Ok(share_price)
 }
 
 
+/// Check whether a non-trade activity is a reward credited in crypto
+/// coins.
+fn is_crypto_reward(non_trade: &account_activities::NonTradeActivity) -> bool {
+  non_trade.type_ == account_activities::ActivityType::Unknown
+    && non_trade.symbol.is_some()
+    && non_trade.quantity.is_some()
+    && non_trade
+      .description
+      .as_ref()
+      .map(|description| descriptions::is_reward(description))
+      .unwrap_or(false)
+}
+
+
+/// Determine the crypto currency pair for quoting the coin with the
+/// given symbol in the given currency, e.g., `BTC/USD`.
+fn crypto_pair(symbol: &str, currency: &str) -> String {
+  if symbol.contains('/') {
+    symbol.to_string()
+  } else if let Some(coin) = symbol
+    .strip_suffix(currency)
+    .filter(|coin| !coin.is_empty())
+  {
+    format!("{coin}/{currency}")
+  } else {
+    format!("{symbol}/{currency}")
+  }
+}
+
+
 fn print_non_trade(
+  out: &mut dyn Write,
   non_trade: &account_activities::NonTradeActivity,
-  investment_account: &str,
-  brokerage_account: &str,
-  brokerage_fee_account: &str,
-  dividend_account: &str,
-  sec_fee_account: &str,
-  finra_taf_account: &str,
-  registry: &HashMap<String, String>,
-  currency: &str,
+  reward_price: Option<&Num>,
+  ctx: &Context,
 ) -> Result<()> {
-  match non_trade.type_ {
+  let date = non_trade.date.date_naive();
+  let code = transaction_code(ctx, &api_str(&non_trade.type_));
+  let cash = |amount: &Num| Some(Amount::cash(amount.clone(), &ctx.currency));
+
+  let transaction = match non_trade.type_ {
+    account_activities::ActivityType::CashDeposit
+    | account_activities::ActivityType::CashWithdrawal
+      if is_crypto_transfer(non_trade) =>
+    {
+      // SANITY: Crypto transfers always have a symbol and a quantity.
+      let symbol = non_trade.symbol.as_deref().unwrap();
+      let quantity = abs(non_trade.quantity.as_ref().unwrap());
+      // The network fee is paid by the sending side, on top of what
+      // arrives at the receiving one.
+      let fee = non_trade.price.clone().unwrap_or_default();
+      let investment = ctx.investment_account(symbol, None);
+      let wallet = ctx.accounts.crypto_wallet.as_str();
+      let (from, to) = if non_trade.type_ == account_activities::ActivityType::CashDeposit {
+        (wallet, investment.as_ref())
+      } else {
+        (investment.as_ref(), wallet)
+      };
+      let coins = |quantity: Num| {
+        Some(Amount::Quantity {
+          quantity,
+          commodity: symbol.to_string(),
+        })
+      };
+
+      let name = translate(ctx, "Transfer", &[]);
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, &name));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      transaction
+        .postings
+        .push(Posting::new(to, coins(quantity.clone())));
+      if !fee.is_zero() {
+        transaction
+          .postings
+          .push(Posting::new(&ctx.accounts.network_fee, coins(fee.clone())));
+      }
+      transaction
+        .postings
+        .push(Posting::new(from, coins(-(quantity + fee))));
+      transaction
+    },
     account_activities::ActivityType::CashDeposit
     | account_activities::ActivityType::CashWithdrawal => {
-      let desc = non_trade
-        .description
-        .as_ref()
-        .map(|desc| format!("\n  ; {}", desc).into())
-        .unwrap_or_else(|| Cow::from(""));
-
-      println!(
-        r#"{date} * Transfer{desc}
-  {from:<51}    {total:>15}
-  XXX
-"#,
-        date = format_date(non_trade.date),
-        from = brokerage_account,
-        total = format_price(&non_trade.net_amount, currency),
-      );
+      let name = translate(ctx, "Transfer", &[]);
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, &name));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      if ctx.transfer_tags {
+        // The key only depends on what the other leg of the transfer
+        // knows about as well, so that it can be derived there, too.
+        let amount = format_price(&abs(&non_trade.net_amount), &ctx.currency);
+        transaction.notes.push(format!("transfer: {date} {amount}"));
+      }
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&non_trade.net_amount),
+      ));
+      let account = ctx
+        .transfer_rules
+        .iter()
+        .find(|rule| rule.matches(non_trade.description.as_deref(), &non_trade.net_amount))
+        .map_or("XXX", |rule| rule.account.as_str());
+      if account == "XXX" {
+        let () = ctx.record_warning(
+          WarningKind::Fallback,
+          Some(&non_trade.id),
+          format!("transfer on {date} booked against placeholder account XXX"),
+        );
+      }
+      transaction.postings.push(Posting::new(account, None));
+
+      let virtual_account = if non_trade.type_ == account_activities::ActivityType::CashDeposit {
+        &ctx.virtual_postings.deposit
+      } else {
+        &ctx.virtual_postings.withdrawal
+      };
+      if let Some(account) = virtual_account {
+        let mut posting = Posting::new(account, cash(&non_trade.net_amount));
+        posting.is_virtual = true;
+        transaction.postings.push(posting);
+      }
+      transaction
+    },
+    account_activities::ActivityType::Interest if ctx.interest_receivable.is_some() => {
+      // We only learn about interest once it is paid out, so book the
+      // accrual at the end of the period it was earned in and settle
+      // the receivable with the payment.
+      let receivable = ctx.interest_receivable.as_deref().unwrap_or_default();
+      let accrual_date = interest_accrual_date(non_trade.description.as_deref(), date);
+      let name = payee(&ctx.payees, ALPACA);
+
+      let mut accrual = Transaction::new(accrual_date, code.clone(), name);
+      accrual.notes.push(translate(ctx, "Interest accrual", &[]));
+      accrual
+        .postings
+        .push(Posting::new(receivable, cash(&non_trade.net_amount)));
+      accrual.postings.push(Posting::new(
+        classify_interest(non_trade, &ctx.accounts),
+        None,
+      ));
+      let () = ctx.accruals.borrow_mut().push(accrual);
+
+      let mut transaction = Transaction::new(date, code, name);
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      transaction.postings.push(Posting::new(receivable, None));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&non_trade.net_amount),
+      ));
+      transaction
     },
     account_activities::ActivityType::Interest => {
-      let desc = non_trade
-        .description
-        .as_ref()
-        .map(|desc| format!("\n  ; {}", desc).into())
-        .unwrap_or_else(|| Cow::from(""));
-
-      println!(
-        r#"{date} * {name}{desc}
-  Income:Interest
-  {to:<51}    {total:>15}
-"#,
-        date = format_date(non_trade.date),
-        name = ALPACA,
-        to = brokerage_account,
-        total = format_price(&non_trade.net_amount, currency),
-      );
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, ALPACA));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      transaction.postings.push(Posting::new(
+        classify_interest(non_trade, &ctx.accounts),
+        None,
+      ));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&non_trade.net_amount),
+      ));
+      transaction
     },
-    account_activities::ActivityType::Dividend => {
+    account_activities::ActivityType::Dividend
+    | account_activities::ActivityType::DividendReturnOfCapital => {
       let symbol = non_trade
         .symbol
         .as_ref()
         .ok_or_else(|| anyhow!("dividend entry does not have an associated symbol"))?;
-      let name = registry
-        .get(symbol)
-        .ok_or_else(|| anyhow!("symbol {} not present in registry", symbol))?;
-
-      println!(
-        r#"{date} * {name}
-  {from}
-  {to:<51}    {total:>15}
-"#,
-        date = format_date(non_trade.date),
-        name = name,
-        from = dividend_account,
-        to = brokerage_account,
-        total = format_price(&non_trade.net_amount, currency),
-      );
-    },
-    account_activities::ActivityType::PassThruCharge => {
-      let desc = non_trade
+      let name = lookup_name(&ctx.registry, symbol)?;
+
+      let return_of_capital = is_return_of_capital(non_trade);
+      let record_date = non_trade
         .description
         .as_ref()
-        .map(|desc| format!("\n  ; {}", desc).into())
-        .unwrap_or_else(|| Cow::from(""));
-
-      println!(
-        r#"{date} * {name}{desc}
-  {from}
-  {to:<51}    {total:>15}
-"#,
-        date = format_date(non_trade.date),
-        name = ALPACA,
-        desc = desc,
-        from = brokerage_fee_account,
-        to = brokerage_account,
-        total = format_price(&non_trade.net_amount, currency),
-      );
+        .and_then(|description| descriptions::record_date(description));
+      let booking_date = match ctx.dividend_date {
+        DividendDate::Pay => date,
+        DividendDate::Record => record_date.unwrap_or_else(|| {
+          let message =
+            format!("no record date found for dividend on {date}; booking it at the pay date");
+          warn!("{message}");
+          let () = ctx.record_warning(WarningKind::Fallback, Some(&non_trade.id), message);
+          date
+        }),
+      };
+
+      let mut transaction = Transaction::new(booking_date, code, payee(&ctx.payees, &name));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      if return_of_capital {
+        transaction
+          .notes
+          .push(translate(ctx, "Return of capital", &[]));
+      }
+      if let (Some(per_share), Some(quantity)) = (&non_trade.per_share_amount, &non_trade.quantity)
+      {
+        let per_share = per_share.display().min_precision(2).to_string();
+        let quantity = quantity.to_string();
+        transaction.notes.push(translate(
+          ctx,
+          "{per_share}/share on {quantity} shares",
+          &[("per_share", &per_share), ("quantity", &quantity)],
+        ));
+      }
+      if let Some(record_date) = record_date {
+        transaction
+          .notes
+          .push(format!("record-date: {}", record_date));
+      }
+      if booking_date != date {
+        transaction.notes.push(format!("pay-date: {}", date));
+      }
+      explain(ctx, &mut transaction.notes, || explain_dividend(non_trade));
+
+      let from = if return_of_capital && ctx.roc_reduces_basis {
+        // A return of capital reduces the cost basis of the holding. We
+        // don't track lots and so can't adjust their cost, but we can
+        // book it against a cash sub-account of the investment account,
+        // keeping the account holding the shares free of cash.
+        Cow::from(format!(
+          "{}:{BASIS_ADJUSTMENT_ACCOUNT}",
+          ctx.investment_account(symbol, None)
+        ))
+      } else {
+        Cow::from(classify_dividend(non_trade, &ctx.accounts))
+      };
+      transaction.postings.push(Posting::new(from, None));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&non_trade.net_amount),
+      ));
+      transaction
+    },
+    account_activities::ActivityType::PassThruCharge => {
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, ALPACA));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      transaction
+        .postings
+        .push(Posting::new(&ctx.accounts.brokerage_fee, None));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&non_trade.net_amount),
+      ));
+      transaction
     },
     account_activities::ActivityType::Fee => {
-      let (from, desc) = classify_fee(
-        non_trade,
-        brokerage_fee_account,
-        sec_fee_account,
-        finra_taf_account,
-      )?;
-      println!(
-        r#"{date} * {name}
-  ; {desc}
-  {from:<51}    {total:>15}
-  {to}
-"#,
-        date = format_date(non_trade.date),
-        name = ALPACA,
-        desc = desc,
-        from = from,
-        to = brokerage_account,
-        total = format_price(&-&non_trade.net_amount, currency),
-      );
+      let fee = match classify_fee(non_trade) {
+        Some(fee) => fee,
+        None => {
+          return ignore_non_trade(out, non_trade, "fee activity with unknown description", ctx)
+        },
+      };
+      let from = fee_account(&fee, &ctx.accounts);
+
+      // ADR fees are charged for a specific holding, so attribute them
+      // to it if we can tell which one it is.
+      let symbol = match &fee {
+        Fee::Adr { symbol } => non_trade.symbol.clone().or_else(|| symbol.clone()),
+        _ => None,
+      };
+      let (name, from) = if let Some(symbol) = symbol.as_deref() {
+        let name = ctx
+          .registry
+          .get(symbol)
+          .map(|entry| Cow::from(entry.name()))
+          .unwrap_or_else(|| Cow::from(symbol));
+        let from = if ctx.per_symbol_adr_fee_accounts {
+          Cow::from(format!("{from}:{symbol}"))
+        } else {
+          Cow::from(from)
+        };
+        (name, from)
+      } else {
+        (Cow::from(ALPACA), Cow::from(from))
+      };
+
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, &name));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      explain(ctx, &mut transaction.notes, || explain_fee(&fee));
+      transaction
+        .postings
+        .push(Posting::new(from, cash(&-&non_trade.net_amount)));
+      transaction
+        .postings
+        .push(Posting::new(&ctx.accounts.brokerage, None));
+      transaction
     },
     account_activities::ActivityType::Acquisition => {
       // Note that we have seen "acquisition" activities that have a
@@ -348,35 +1153,39 @@ fn print_non_trade(
         .symbol
         .as_ref()
         .ok_or_else(|| anyhow!("acquisition entry does not have an associated symbol"))?;
-      let name = registry
-        .get(symbol)
-        .ok_or_else(|| anyhow!("symbol {} not present in registry", symbol))?;
+      let name = lookup_name(&ctx.registry, symbol)?;
+      let name = payee(&ctx.payees, &name);
       let quantity = &non_trade.net_amount / &share_price;
 
-      println!(
-        r#"; {name} got acquired
-{date} * {name}
-  {from:<51}  {qty:>13} {symbol} @ {price} = 0 {symbol}
-  {to:<51}    {total:>15}
-"#,
-        date = format_date(non_trade.date),
-        name = name,
-        symbol = symbol,
-        qty = quantity,
-        price = format_price(&share_price, currency),
-        from = investment_account,
-        to = brokerage_account,
-        total = format_price(&non_trade.net_amount, currency),
-      );
+      let mut transaction = Transaction::new(date, code, name);
+      transaction
+        .comments
+        .push(translate(ctx, "{name} got acquired", &[("name", name)]));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+
+      let amount = Amount::Price {
+        quantity,
+        commodity: symbol.clone(),
+        price: share_price,
+        currency: ctx.currency.clone(),
+        balance: Some(Num::from(0)),
+      };
+      transaction.postings.push(Posting::new(
+        ctx.investment_account(symbol, None),
+        Some(amount),
+      ));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&non_trade.net_amount),
+      ));
+      transaction
     },
     account_activities::ActivityType::StockSplit => {
       let symbol = non_trade
         .symbol
         .as_ref()
         .ok_or_else(|| anyhow!("stock split entry does not have an associated symbol"))?;
-      let name = registry
-        .get(symbol)
-        .ok_or_else(|| anyhow!("symbol {} not present in registry", symbol))?;
+      let name = lookup_name(&ctx.registry, symbol)?;
       let price = non_trade.price.as_ref().ok_or_else(|| {
         anyhow!(
           "stock split entry for {} does not have an associated price",
@@ -389,52 +1198,395 @@ fn print_non_trade(
           symbol
         )
       })?;
-      let description = non_trade
+
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, &name));
+      transaction.notes.push(translate(ctx, "Stock split", &[]));
+      transaction.notes.extend(comments(
+        ctx,
+        &non_trade.id,
+        non_trade.description.as_deref(),
+      ));
+
+      let amount = Amount::Price {
+        quantity: quantity.clone(),
+        commodity: symbol.clone(),
+        price: price.clone(),
+        currency: ctx.currency.clone(),
+        balance: None,
+      };
+      transaction.postings.push(Posting::new(
+        ctx.investment_account(symbol, None),
+        Some(amount),
+      ));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&(quantity * price)),
+      ));
+      transaction
+    },
+    account_activities::ActivityType::Reorg => {
+      // Reorganizations that do not involve any cash can't be
+      // expressed by us in a meaningful way, because we lack the
+      // details of what happened to the position.
+      if non_trade.net_amount.is_zero() {
+        return ignore_non_trade(
+          out,
+          non_trade,
+          "reorganization activity without cash amount",
+          ctx,
+        )
+      }
+
+      let name = if let Some(symbol) = &non_trade.symbol {
+        lookup_name(&ctx.registry, symbol)?
+      } else {
+        Cow::from(ALPACA)
+      };
+
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, &name));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      transaction
+        .postings
+        .push(Posting::new(&ctx.accounts.reorg, None));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&non_trade.net_amount),
+      ));
+      transaction
+    },
+    // apca does not know about crypto rewards either, which we book as
+    // income in the coin at the day's price.
+    account_activities::ActivityType::Unknown if is_crypto_reward(non_trade) => {
+      // SANITY: Crypto rewards always have a symbol and a quantity.
+      let symbol = non_trade.symbol.as_ref().unwrap();
+      let quantity = non_trade.quantity.clone().unwrap();
+      // Prefer the price Alpaca reported, if any.
+      let price = non_trade
+        .price
+        .as_ref()
+        .or(reward_price)
+        .with_context(|| format!("no price found for {symbol} reward on {date}"))?;
+
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, ALPACA));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      explain(ctx, &mut transaction.notes, || {
+        "description denotes crypto reward".to_string()
+      });
+      let amount = Amount::Price {
+        quantity,
+        commodity: symbol.clone(),
+        price: price.clone(),
+        currency: ctx.currency.clone(),
+        balance: None,
+      };
+      transaction.postings.push(Posting::new(
+        ctx.investment_account(symbol, None),
+        Some(amount),
+      ));
+      transaction
+        .postings
+        .push(Posting::new(&ctx.accounts.crypto_reward, None));
+      transaction
+    },
+    // apca does not know about cash-in-lieu activities, so we have to
+    // identify them based on their description.
+    account_activities::ActivityType::Unknown
+      if non_trade
+        .description
+        .as_ref()
+        .map(|description| descriptions::is_cash_in_lieu(description))
+        .unwrap_or(false) =>
+    {
+      let symbol = non_trade
+        .symbol
+        .as_ref()
+        .ok_or_else(|| anyhow!("cash-in-lieu entry does not have an associated symbol"))?;
+      let name = lookup_name(&ctx.registry, symbol)?;
+
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, &name));
+      transaction.notes.push(translate(
+        ctx,
+        "Cash in lieu of fractional {symbol} shares",
+        &[("symbol", symbol)],
+      ));
+      transaction.notes.extend(comments(
+        ctx,
+        &non_trade.id,
+        non_trade.description.as_deref(),
+      ));
+      explain(ctx, &mut transaction.notes, || {
+        "description denotes cash in lieu".to_string()
+      });
+      transaction
+        .postings
+        .push(Posting::new(&ctx.accounts.capital_gains, None));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&non_trade.net_amount),
+      ));
+      transaction
+    },
+    // Neither does it know about transfers between the brokerage cash
+    // and a cash sweep program.
+    account_activities::ActivityType::Unknown
+      if non_trade
         .description
         .as_ref()
-        .map(|description| format!("\n  ; {}", description).into())
-        .unwrap_or_else(|| Cow::from(""));
-
-      println!(
-        r#"{date} * {name}
-  ; Stock split{desc}
-  {from:<51}  {qty:>13} {symbol} @ {price}
-  {to:<51}    {total:>15}
-"#,
-        date = format_date(non_trade.date),
-        name = name,
-        desc = description,
-        symbol = symbol,
-        qty = quantity,
-        price = format_price(price, currency),
-        from = investment_account,
-        to = brokerage_account,
-        total = format_price(&(quantity * price), currency),
+        .map(|description| descriptions::is_sweep(description))
+        .unwrap_or(false) =>
+    {
+      let name = translate(ctx, "Cash sweep", &[]);
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, &name));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      explain(ctx, &mut transaction.notes, || {
+        "description denotes cash sweep".to_string()
+      });
+      transaction
+        .postings
+        .push(Posting::new(&ctx.accounts.sweep, None));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.brokerage,
+        cash(&non_trade.net_amount),
+      ));
+      transaction
+    },
+    account_activities::ActivityType::OptionExpiration if ctx.option_premium => {
+      let symbol = non_trade
+        .symbol
+        .as_deref()
+        .context("option expiration activity does not have a symbol")?;
+      let quantity = abs(
+        non_trade
+          .quantity
+          .as_ref()
+          .context("option expiration activity does not have a quantity")?,
       );
+      let premium = match expired_premium(symbol, &quantity, ctx) {
+        Some(premium) => premium,
+        None => {
+          return ignore_non_trade(out, non_trade, "expiration of option not sold before", ctx)
+        },
+      };
+
+      let name = lookup_name(&ctx.registry, symbol)?;
+      let name = translate(ctx, "{name} expired", &[("name", &name)]);
+      let mut transaction = Transaction::new(date, code, payee(&ctx.payees, &name));
+      transaction.notes = comments(ctx, &non_trade.id, non_trade.description.as_deref());
+      // Close out the position at the price it was sold at, so that the
+      // premium ends up as income.
+      let amount = Amount::Price {
+        quantity: quantity.clone(),
+        commodity: symbol.to_string(),
+        price: &premium / &quantity,
+        currency: ctx.currency.clone(),
+        balance: None,
+      };
+      let investment_account = ctx.investment_account(symbol, None);
+      transaction
+        .postings
+        .push(Posting::new(investment_account.as_ref(), Some(amount)));
+      transaction.postings.push(Posting::new(
+        &ctx.accounts.option_premium,
+        Some(Amount::cash(-premium, &ctx.currency)),
+      ));
+      transaction
     },
-    _ => warn!("ignoring unsupported non-trade activity type: {non_trade:#?}"),
-  }
-  Ok(())
+    _ => return ignore_non_trade(out, non_trade, "unsupported non-trade activity type", ctx),
+  };
+
+  emit_transaction(out, transaction, ctx)
 }
 
 
-/// Retrieve account activities spanning at least one day.
-async fn activites_for_a_day(
-  client: &mut Client,
-  mut activities: VecDeque<account_activities::Activity>,
-  mut request: account_activities::ActivityReq,
-) -> Result<(
-  account_activities::ActivityReq,
-  VecDeque<account_activities::Activity>,
-  VecDeque<account_activities::Activity>,
-)> {
-  loop {
-    if let Some(last) = activities.back() {
+/// Await the provided future, failing if it does not complete within
+/// the given timeout (if any).
+async fn timed<F, T, E>(timeout: Option<StdDuration>, future: F) -> Result<T>
+where
+  F: Future<Output = Result<T, E>>,
+  E: Into<anyhow::Error>,
+{
+  let result = if let Some(timeout) = timeout {
+    with_timeout(timeout, future)
+      .await
+      .map_err(|_| anyhow!("request timed out after {}s", timeout.as_secs()))
+      .classify(Failure::Api)?
+  } else {
+    future.await
+  };
+  result.map_err(Into::into)
+}
+
+
+/// An endpoint error that may indicate that a request got rejected
+/// because the API's rate limit was exceeded.
+trait RateLimited {
+  /// Check whether the error reports the rate limit being exceeded.
+  fn rate_limited(&self) -> bool;
+}
+
+macro_rules! impl_rate_limited {
+  ($($err:ty),* $(,)?) => {
+    $(
+      impl RateLimited for $err {
+        fn rate_limited(&self) -> bool {
+          matches!(self, Self::RateLimitExceeded(..))
+        }
+      }
+    )*
+  };
+}
+
+impl_rate_limited!(
+  account::GetError,
+  asset::GetError,
+  bars::ListError,
+  calendar::ListError,
+  clock::GetError,
+  order::GetError,
+  positions::ListError,
+  watchlist::GetError,
+  watchlists::GetError,
+  GetActivitiesError,
+  GetAssetNameError,
+  GetCryptoBarError,
+  GetPortfolioHistoryError,
+);
+
+
+/// Issue a request to the given endpoint, pacing it according to the
+/// rate limit information reported alongside previous responses and
+/// retrying it if it got rejected because of rate limiting.
+async fn issue<E>(client: &Client, input: &E::Input) -> Result<E::Output, RequestError<E::Error>>
+where
+  E: Endpoint,
+  E::Error: RateLimited,
+{
+  let rate_limit_delay = || {
+    client
+      .rate_limit()
+      .and_then(|rate_limit| rate_limit.delay(Utc::now()))
+      .map(|delay| delay.min(RATE_LIMIT_MAX_DELAY))
+  };
+
+  if let Some(delay) = rate_limit_delay() {
+    debug!(
+      "rate limit budget exhausted; waiting {}s before issuing request",
+      delay.as_secs()
+    );
+    let () = sleep(delay).await;
+  }
+
+  let mut retries = 0;
+  loop {
+    match client.issue::<E>(input).await {
+      Err(RequestError::Endpoint(err)) if retries < RATE_LIMIT_RETRIES && err.rate_limited() => {
+        // Wait for as long as the server asked us to or, if it did not
+        // tell, increasingly longer.
+        let delay = rate_limit_delay().unwrap_or_else(|| {
+          StdDuration::from_secs(2u64.saturating_pow(retries)).min(RATE_LIMIT_MAX_DELAY)
+        });
+        debug!(
+          "rate limit exceeded; retrying in {}s ({} retries left)",
+          delay.as_secs(),
+          RATE_LIMIT_RETRIES - retries
+        );
+        let () = sleep(delay).await;
+        retries += 1;
+      },
+      result => break result,
+    }
+  }
+}
+
+
+/// Retrieve a single page of account activities.
+async fn fetch_activities(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  request: &account_activities::ActivityReq,
+) -> Result<Vec<StatusActivity>> {
+  timed(timeout, issue::<GetActivities>(client, request))
+    .await
+    .classify(Failure::Api)
+    .with_context(|| "failed to retrieve account activities")
+}
+
+
+/// Retrieve account activities day by day, sending each day's
+/// activities through the provided channel until all of them have been
+/// retrieved, an error occurred, or the receiving end got closed.
+async fn fetch_days(
+  client: Arc<Client>,
+  timeout: Option<StdDuration>,
+  mut activities: VecDeque<account_activities::Activity>,
+  mut request: account_activities::ActivityReq,
+  chunk: Option<Duration>,
+  timezone: Tz,
+  exclude_status: Vec<String>,
+  mut cache: Option<Cache>,
+  mut dump: Option<Dump>,
+  sender: Sender<Result<VecDeque<account_activities::Activity>>>,
+) {
+  loop {
+    let result = activites_for_a_day(
+      &client,
+      timeout,
+      activities,
+      request,
+      chunk,
+      timezone,
+      &exclude_status,
+      cache.as_mut(),
+      dump.as_mut(),
+    )
+    .await;
+
+    let (next, day, remainder) = match result {
+      Ok(result) => result,
+      Err(err) => {
+        // The receiving end reports the error, if it is still around.
+        let _ = sender.send(Err(err)).await;
+        break
+      },
+    };
+    if day.is_empty() {
+      assert!(remainder.is_empty());
+      break
+    }
+    if sender.send(Ok(day)).await.is_err() {
+      break
+    }
+    request = next;
+    activities = remainder;
+  }
+}
+
+
+/// Retrieve account activities spanning at least one day, with days
+/// delimited in the given time zone, stopping before the first activity
+/// with one of the given statuses.
+async fn activites_for_a_day(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  mut activities: VecDeque<account_activities::Activity>,
+  mut request: account_activities::ActivityReq,
+  chunk: Option<Duration>,
+  timezone: Tz,
+  exclude_status: &[String],
+  mut cache: Option<&mut Cache>,
+  mut dump: Option<&mut Dump>,
+) -> Result<(
+  account_activities::ActivityReq,
+  VecDeque<account_activities::Activity>,
+  VecDeque<account_activities::Activity>,
+)> {
+  loop {
+    if let Some(last) = activities.back() {
       // If we have a last element we must have a first one, so it's
       // fine to unwrap.
       let first = activities.front().unwrap();
-      let start = first.time().date_naive();
-      let end = last.time().date_naive();
+      let start = activity_date(first, timezone);
+      let end = activity_date(last, timezone);
 
       if start != end {
         // The date changed between the first and the last activity,
@@ -442,16 +1594,35 @@ async fn activites_for_a_day(
         // such, report the activities collected so far.
         let (same_day, other_day) = activities
           .into_iter()
-          .partition(|activity| activity.time().date_naive() == start);
+          .partition(|activity| activity_date(activity, timezone) == start);
 
         break Ok((request, same_day, other_day))
       }
     }
 
-    let fetched = client
-      .issue::<account_activities::Get>(&request)
-      .await
-      .with_context(|| "failed to retrieve account activities")?;
+    let mut fetched = fetch_activities(client, timeout, &request).await?;
+    let excluded = fetched.iter().position(|activity| {
+      activity
+        .status
+        .as_ref()
+        .map(|status| exclude_status.contains(status))
+        .unwrap_or(false)
+    });
+    if let Some(index) = excluded {
+      let () = fetched.truncate(index);
+    }
+    let exhausted = fetched.is_empty();
+    let fetched = fetched
+      .into_iter()
+      .map(|activity| activity.activity)
+      .collect::<Vec<_>>();
+
+    if let Some(cache) = &mut cache {
+      let () = cache.append(&fetched)?;
+    }
+    if let Some(dump) = &mut dump {
+      let () = dump.write("activities", &request, &json::activities(&fetched))?;
+    }
 
     if let Some(last) = fetched.last() {
       // If we retrieved some data make sure to update the page token
@@ -459,6 +1630,34 @@ async fn activites_for_a_day(
       // got.
       request.page_token = Some(last.id().to_string());
       activities.append(&mut VecDeque::from(fetched));
+    }
+
+    if excluded.is_some() {
+      // We encountered an activity with an excluded status. Stop right
+      // before it, so that it is retrieved again by a later run, once
+      // its status changed.
+      info!("encountered activity with excluded status; stopping");
+      // What we got may still span multiple days, which have to be
+      // reported one by one.
+      let start = activities
+        .front()
+        .map(|first| activity_date(first, timezone));
+      let (same_day, other_day) = activities
+        .into_iter()
+        .partition(|activity| Some(activity_date(activity, timezone)) == start);
+
+      break Ok((request, same_day, other_day))
+    } else if !exhausted {
+      continue
+    } else if let Some((chunk, until)) = chunk
+      .zip(request.until)
+      .filter(|(_, until)| *until < Utc::now())
+    {
+      // The current window is exhausted, so move on to the next one.
+      // The page token stays in place, preventing us from reporting
+      // activities at the boundary twice.
+      request.after = Some(until);
+      request.until = Some(until + chunk);
     } else {
       // We reached the end of the activity "stream", as nothing else
       // was reported.
@@ -468,68 +1667,107 @@ async fn activites_for_a_day(
 }
 
 
+/// Record warnings for partial fills that could not be merged with the
+/// fill completing their order, because that one was not reported in
+/// the same batch, and for regulatory fees that could not be associated
+/// with a trade.
+fn check_unmatched(activities: &VecDeque<Activity>, ctx: &Context) {
+  let completed = activities
+    .iter()
+    .filter_map(|activity| match activity {
+      Activity::Trade(trade, _) if trade.unfilled_quantity.is_zero() => Some(trade.order_id),
+      _ => None,
+    })
+    .collect::<HashSet<_>>();
+
+  for activity in activities {
+    match activity {
+      Activity::Trade(trade, _)
+        if !trade.unfilled_quantity.is_zero() && !completed.contains(&trade.order_id) =>
+      {
+        let () = ctx.record_warning(
+          WarningKind::PartialFill,
+          Some(&trade.id),
+          format!(
+            "partial fill of {} {} not merged with the fill completing its order",
+            trade.quantity, trade.symbol
+          ),
+        );
+      },
+      Activity::NonTrade(non_trade) if non_trade.type_ == account_activities::ActivityType::Fee => {
+        if let Some(Fee::Taf { .. }) | Some(Fee::Reg { .. }) = classify_fee(non_trade) {
+          let () = ctx.record_warning(
+            WarningKind::Fallback,
+            Some(&non_trade.id),
+            "regulatory fee not associated with any trade".to_string(),
+          );
+        }
+      },
+      _ => (),
+    }
+  }
+}
+
 /// Merge partial fills for the same order at the same price.
 fn merge_partial_fills(
-  mut activities: VecDeque<account_activities::Activity>,
+  activities: VecDeque<account_activities::Activity>,
 ) -> VecDeque<account_activities::Activity> {
-  let mut i = 0;
-  'outer: while i < activities.len() {
-    if let account_activities::Activity::Trade(trade) = &activities[i] {
+  let mut activities = activities.into_iter().map(Some).collect::<Vec<_>>();
+
+  // Index the "final" fills, i.e., the ones that complete an order and
+  // have an `unfilled_quantity` of 0, by order ID and price. Note that
+  // Alpaca may send activities in any order, really, and so a partial
+  // fill may be reported before or after the fill it is to be merged
+  // with.
+  let mut completing = HashMap::new();
+  for (index, activity) in activities.iter().enumerate() {
+    if let Some(account_activities::Activity::Trade(trade)) = activity {
+      if trade.unfilled_quantity.is_zero() {
+        let _index = completing
+          .entry((trade.order_id, trade.price.clone()))
+          .or_insert(index);
+      }
+    }
+  }
+
+  for index in 0..activities.len() {
+    let target = match &activities[index] {
       // If we have a trade that has unfilled quantity left (i.e., does
-      // not complete an order), then we search for the matching "final"
+      // not complete an order), then we look up the matching "final"
       // fill to merge with.
-      if !trade.unfilled_quantity.is_zero() {
-        // See if we can merge the trade with another one. Note that
-        // Alpaca may send activities in any order, really, and so we
-        // cannot just look at later ones but actually have to scan the
-        // entire array.
-        for j in 0..activities.len() {
-          if j == i {
-            // We do not want to merge an activity with itself.
-            continue
-          }
+      // Note that it is possible there there is no such fill in the
+      // list of activities. That is because we process them in batches
+      // and it is conceivable that not all partial fills for an order
+      // happened in the same batch. So we may end up missing out
+      // merging partial fills even, pushing the burden on the user.
+      // That should be a rare occurrence and it won't be too much work,
+      // though.
+      Some(account_activities::Activity::Trade(trade)) if !trade.unfilled_quantity.is_zero() => {
+        completing
+          .get(&(trade.order_id, trade.price.clone()))
+          .copied()
+      },
+      _ => None,
+    };
 
-          if let account_activities::Activity::Trade(candidate) = &activities[j] {
-            // We are looking for the "final" fill, i.e., the one that
-            // completes the order. It will have an `unfilled_quantity`
-            // of 0.
-            // Note that it is possible there there is no such fill in
-            // the list of activities. That is because we process them
-            // in batches and it is conceivable that not all partial
-            // fills for an order happened in the same batch. So we may
-            // end up missing out merging partial fills even, pushing
-            // the burden on the user. That should be a rare occurrence
-            // and it won't be too much work, though.
-            if candidate.order_id == trade.order_id
-              && candidate.price == trade.price
-              && candidate.unfilled_quantity.is_zero()
-            {
-              debug_assert_eq!(candidate.side, trade.side);
-              debug_assert_eq!(candidate.symbol, trade.symbol);
-
-              let quantity = trade.quantity.clone();
-
-              if let account_activities::Activity::Trade(candidate) = &mut activities[j] {
-                candidate.quantity += quantity;
-                debug_assert!(candidate.quantity <= candidate.cumulative_quantity);
-
-                // Remove the outer trade activity. We do not increment
-                // `i` on this path, so we handle the removal correctly.
-                activities.remove(i);
-                continue 'outer
-              } else {
-                unreachable!()
-              }
-            }
-          }
-        }
+    if let Some(target) = target {
+      if let (
+        Some(account_activities::Activity::Trade(trade)),
+        Some(account_activities::Activity::Trade(candidate)),
+      ) = (activities[index].take(), &mut activities[target])
+      {
+        debug_assert_eq!(candidate.side, trade.side);
+        debug_assert_eq!(candidate.symbol, trade.symbol);
+
+        candidate.quantity += trade.quantity;
+        debug_assert!(candidate.quantity <= candidate.cumulative_quantity);
+      } else {
+        unreachable!()
       }
     }
-
-    i += 1;
   }
 
-  activities
+  activities.into_iter().flatten().collect()
 }
 
 
@@ -541,10 +1779,115 @@ enum Activity {
     account_activities::TradeActivity,
     Vec<account_activities::NonTradeActivity>,
   ),
+  /// All trade activities belonging to a single order, along with the
+  /// associated regulatory fees.
+  Order(
+    Vec<account_activities::TradeActivity>,
+    Vec<account_activities::NonTradeActivity>,
+  ),
+  /// All trade activities of the same symbol and side on a single
+  /// day, along with the associated regulatory fees.
+  Summary(
+    Vec<account_activities::TradeActivity>,
+    Vec<account_activities::NonTradeActivity>,
+  ),
   /// A non-trade activity (e.g., a dividend payment).
   NonTrade(account_activities::NonTradeActivity),
 }
 
+impl Activity {
+  /// Retrieve the date of the activity.
+  fn date(&self, timezone: Tz) -> NaiveDate {
+    match self {
+      Self::Trade(trade, _) => trade_date(trade, timezone),
+      Self::Order(trades, _) | Self::Summary(trades, _) => trades
+        .first()
+        .map(|trade| trade_date(trade, timezone))
+        .unwrap_or_default(),
+      Self::NonTrade(non_trade) => non_trade.date.date_naive(),
+    }
+  }
+
+  /// Calculate the (approximate) effect the activity has on the cash
+  /// balance.
+  fn cash_flow(&self) -> Num {
+    let trade_flow = |trade: &account_activities::TradeActivity| {
+      let amount = &trade.price * &trade.quantity * contract_multiplier(&trade.symbol);
+      match trade.side {
+        account_activities::Side::Buy => -amount,
+        _ => amount,
+      }
+    };
+    let fee_flow =
+      |fees: &[account_activities::NonTradeActivity]| sum(fees.iter().map(|fee| &fee.net_amount));
+
+    match self {
+      Self::Trade(trade, fees) => trade_flow(trade) + fee_flow(fees),
+      Self::Order(trades, fees) | Self::Summary(trades, fees) => {
+        sum(trades.iter().map(trade_flow)) + fee_flow(fees)
+      },
+      Self::NonTrade(non_trade) => non_trade.net_amount.clone(),
+    }
+  }
+
+  /// Retrieve the descriptions and symbols of the Alpaca provided
+  /// activities the activity was created from.
+  fn texts(&self) -> Vec<&str> {
+    let (trades, non_trades) = match self {
+      Self::Trade(trade, fees) => (slice::from_ref(trade), fees.as_slice()),
+      Self::Order(trades, fees) | Self::Summary(trades, fees) => {
+        (trades.as_slice(), fees.as_slice())
+      },
+      Self::NonTrade(non_trade) => (&[][..], slice::from_ref(non_trade)),
+    };
+    trades
+      .iter()
+      .map(|trade| trade.symbol.as_str())
+      .chain(non_trades.iter().flat_map(|non_trade| {
+        non_trade
+          .description
+          .as_deref()
+          .into_iter()
+          .chain(non_trade.symbol.as_deref())
+      }))
+      .collect()
+  }
+
+  /// Retrieve the IDs of the Alpaca provided activities the activity
+  /// was created from.
+  fn ids(&self) -> Vec<&str> {
+    let (trades, fees) = match self {
+      Self::Trade(trade, fees) => (slice::from_ref(trade), fees.as_slice()),
+      Self::Order(trades, fees) | Self::Summary(trades, fees) => {
+        (trades.as_slice(), fees.as_slice())
+      },
+      Self::NonTrade(non_trade) => (&[][..], slice::from_ref(non_trade)),
+    };
+    trades
+      .iter()
+      .map(|trade| trade.id.as_str())
+      .chain(fees.iter().map(|fee| fee.id.as_str()))
+      .collect()
+  }
+
+  /// Convert the activity back into the Alpaca provided ones it was
+  /// created from.
+  fn into_raw(self) -> Vec<account_activities::Activity> {
+    match self {
+      Self::Trade(trade, fees) => Some(account_activities::Activity::Trade(trade))
+        .into_iter()
+        .chain(fees.into_iter().map(account_activities::Activity::NonTrade))
+        .collect(),
+      Self::Order(trades, fees) | Self::Summary(trades, fees) => trades
+        .into_iter()
+        .map(account_activities::Activity::Trade)
+        .chain(fees.into_iter().map(account_activities::Activity::NonTrade))
+        .collect(),
+      Self::NonTrade(non_trade) => vec![account_activities::Activity::NonTrade(non_trade)],
+    }
+  }
+}
+
 impl From<account_activities::Activity> for Activity {
   fn from(other: account_activities::Activity) -> Self {
     match other {
@@ -554,259 +1897,2512 @@ impl From<account_activities::Activity> for Activity {
   }
 }
 
+/// Group all trade activities belonging to the same order into a single
+/// activity.
+fn group_fills(activities: VecDeque<Activity>) -> VecDeque<Activity> {
+  let mut grouped = VecDeque::with_capacity(activities.len());
+  let mut orders = HashMap::new();
+
+  for activity in activities {
+    match activity {
+      Activity::Trade(trade, fees) => {
+        if let Some(index) = orders.get(&trade.order_id) {
+          if let Activity::Order(trades, order_fees) = &mut grouped[*index] {
+            trades.push(trade);
+            order_fees.extend(fees);
+          } else {
+            unreachable!()
+          }
+        } else {
+          orders.insert(trade.order_id, grouped.len());
+          grouped.push_back(Activity::Order(vec![trade], fees));
+        }
+      },
+      activity => grouped.push_back(activity),
+    }
+  }
+
+  grouped
+}
+
+/// Combine all trade activities of the same symbol and side on the same
+/// day into a single activity.
+fn summarize_trades(activities: VecDeque<Activity>, timezone: Tz) -> VecDeque<Activity> {
+  let mut summarized = VecDeque::<Activity>::with_capacity(activities.len());
+
+  for activity in activities {
+    let (trades, fees) = match activity {
+      Activity::Trade(trade, fees) => (vec![trade], fees),
+      Activity::Order(trades, fees) | Activity::Summary(trades, fees) => (trades, fees),
+      activity @ Activity::NonTrade(..) => {
+        summarized.push_back(activity);
+        continue
+      },
+    };
+
+    let summary = trades.first().and_then(|trade| {
+      summarized.iter_mut().find_map(|activity| match activity {
+        Activity::Summary(summary, summary_fees)
+          if summary[0].symbol == trade.symbol
+            && summary[0].side == trade.side
+            && trade_date(&summary[0], timezone) == trade_date(trade, timezone) =>
+        {
+          Some((summary, summary_fees))
+        },
+        _ => None,
+      })
+    });
+
+    if let Some((summary, summary_fees)) = summary {
+      summary.extend(trades);
+      summary_fees.extend(fees);
+    } else if !trades.is_empty() {
+      summarized.push_back(Activity::Summary(trades, fees));
+    }
+  }
+
+  summarized
+}
+
 /// Try to associate (or merge) all non-trade fee activity with the
 /// corresponding trades.
 fn associate_fees_with_trades(
   activities: VecDeque<account_activities::Activity>,
-) -> Result<VecDeque<Activity>> {
+) -> VecDeque<Activity> {
   let mut activities = activities
     .into_iter()
-    .map(Activity::from)
-    .collect::<VecDeque<_>>();
-
-  let mut i = 0;
-  'outer: while i < activities.len() {
-    if let Activity::NonTrade(non_trade) = &activities[i] {
-      if non_trade.type_ == account_activities::ActivityType::Fee {
-        if let Some(description) = &non_trade.description {
-          let (shares, proceeds) = if let Some(captures) = TAF_RE.captures(description) {
-            let shares = &captures["shares"];
-            let shares = Num::from_str(shares)
-              .with_context(|| format!("failed to parse shares string '{}' as number", shares))?;
-            (Some(shares), None)
-          } else if let Some(captures) = REG_RE.captures(description) {
-            let proceeds = &captures["proceeds"];
-            let proceeds = Num::from_str(proceeds).with_context(|| {
-              format!("failed to parse proceeds string '{}' as number", proceeds)
-            })?;
-            (None, Some(proceeds))
-          } else if ADR_RE.find(description).is_some() {
-            // ADR fees aren't associated with a trade, so just skip it
-            // here.
-            i += 1;
-            continue 'outer
-          } else {
-            bail!("description string could not be parsed: {}", description)
-          };
-
-          let non_trade = non_trade.clone();
-
-          // Note that we actually have to scan the entire list of
-          // activities, because there is no guarantee that a fee is
-          // reported strictly after the corresponding trade, apparently.
-          for j in 0..activities.len() {
-            if let Activity::Trade(trade, fees) = &mut activities[j] {
-              if Some(&trade.quantity) == shares.as_ref()
-                || Some(&trade.price * &trade.quantity) == proceeds
-              {
-                fees.push(non_trade);
-                activities.remove(i);
-                continue 'outer
-              }
-            }
-          }
-        } else {
-          bail!("fee activity does not have a description")
+    .map(|activity| Some(Activity::from(activity)))
+    .collect::<Vec<_>>();
+
+  // Index trades by share count and by proceeds, remembering the first
+  // trade for each. Note that we have to consider all activities,
+  // because there is no guarantee that a fee is reported strictly after
+  // the corresponding trade, apparently.
+  let mut by_shares = HashMap::new();
+  let mut by_proceeds = HashMap::new();
+  for (index, activity) in activities.iter().enumerate() {
+    if let Some(Activity::Trade(trade, _)) = activity {
+      let _index = by_shares.entry(trade.quantity.clone()).or_insert(index);
+      let _index = by_proceeds
+        .entry(&trade.price * &trade.quantity)
+        .or_insert(index);
+    }
+  }
+
+  for index in 0..activities.len() {
+    let target = match &activities[index] {
+      Some(Activity::NonTrade(non_trade))
+        if non_trade.type_ == account_activities::ActivityType::Fee =>
+      {
+        match classify_fee(non_trade) {
+          Some(Fee::Taf { shares }) => by_shares.get(&shares).copied(),
+          Some(Fee::Reg { proceeds }) => by_proceeds.get(&proceeds).copied(),
+          // ADR, reorganization, and transfer fees aren't associated
+          // with a trade and fees we don't understand get reported when
+          // printing them, so just skip them here.
+          Some(Fee::Adr { .. }) | Some(Fee::Reorg) | Some(Fee::Transfer) | None => None,
         }
+      },
+      _ => None,
+    };
+
+    if let Some(target) = target {
+      if let (Some(Activity::NonTrade(non_trade)), Some(Activity::Trade(_, fees))) =
+        (activities[index].take(), &mut activities[target])
+      {
+        fees.push(non_trade);
+      } else {
+        unreachable!()
       }
     }
+  }
+
+  activities.into_iter().flatten().collect()
+}
 
-    i += 1;
+/// Write `account` and `commodity` declarations for the provided
+/// referenced accounts and commodities to the file at the given path,
+/// tagging commodities with the security identifiers from the registry.
+fn write_declarations(
+  path: &Path,
+  references: &References,
+  backup: bool,
+  registry: &Registry,
+) -> Result<()> {
+  let mut file = AtomicFile::create(path, false)?;
+  for account in &references.accounts {
+    writeln!(file, "account {account}")?;
+  }
+  for commodity in &references.commodities {
+    writeln!(file, "commodity {}", format_commodity(commodity))?;
+    for id in security_ids(registry, commodity) {
+      writeln!(file, "  ; {id}")?;
+    }
   }
+  file.commit(backup)
+}
 
-  Ok(activities)
+/// Parse the names of all accounts declared via `account` directives
+/// in a Ledger journal.
+fn parse_account_declarations<R>(reader: R) -> Result<HashSet<String>>
+where
+  R: BufRead,
+{
+  reader
+    .lines()
+    .filter_map(|line| match line {
+      Ok(line) => {
+        let account = line.strip_prefix("account ")?;
+        // Strip any trailing comment or note.
+        let account = account.split(';').next().unwrap_or_default();
+        let account = account.split("  ").next().unwrap_or_default();
+        Some(Ok(account.trim().to_string()))
+      },
+      Err(err) => Some(Err(err.into())),
+    })
+    .collect()
 }
 
-async fn activities_list(
-  client: &mut Client,
-  begin: Option<NaiveDate>,
-  force_separate_fees: bool,
-  investment_account: &str,
-  brokerage_account: &str,
-  brokerage_fee_account: &str,
-  dividend_account: &str,
-  sec_fee_account: &str,
-  finra_taf_account: &str,
-  registry: &HashMap<String, String>,
+/// Check that all accounts activities may get booked against are
+/// declared in the Ledger journal at the given path.
+fn validate_accounts(path: &Path, ctx: &Context) -> Result<()> {
+  let file =
+    input::open(path).with_context(|| format!("failed to open journal {}", path.display()))?;
+  let declared = parse_account_declarations(BufReader::new(file))
+    .with_context(|| format!("failed to read journal {}", path.display()))?;
+
+  let missing = ctx
+    .all_accounts()
+    .into_iter()
+    .filter(|account| !declared.contains(account))
+    .collect::<Vec<_>>();
+
+  ensure!(
+    missing.is_empty(),
+    "the following accounts are not declared in {}: {}",
+    path.display(),
+    missing.join(", ")
+  );
+  Ok(())
+}
+
+/// Find pass-thru charges that recur monthly with the same amount,
+/// returning the most recent occurrence of each.
+///
+/// A charge is considered recurring if it occurred in each of the last
+/// `RECURRING_MONTHS` months it was charged in.
+fn recurring_charges(
+  charges: &[account_activities::NonTradeActivity],
+) -> Vec<&account_activities::NonTradeActivity> {
+  let mut by_amount = BTreeMap::<_, Vec<_>>::new();
+  for charge in charges {
+    let () = by_amount
+      .entry(&charge.net_amount)
+      .or_default()
+      .push(charge);
+  }
+
+  by_amount
+    .into_values()
+    .filter_map(|charges| {
+      let months = charges
+        .iter()
+        .map(|charge| {
+          let date = charge.date.date_naive();
+          date.year() * 12 + date.month0() as i32
+        })
+        .collect::<BTreeSet<_>>();
+
+      let consecutive = months
+        .iter()
+        .rev()
+        .zip(months.iter().rev().skip(1))
+        .take_while(|(month, previous)| **month == **previous + 1)
+        .count()
+        + 1;
+
+      if consecutive >= RECURRING_MONTHS {
+        charges.into_iter().max_by_key(|charge| charge.date)
+      } else {
+        None
+      }
+    })
+    .collect()
+}
+
+/// Write Ledger periodic transactions for the provided recurring
+/// charges to the file at the given path.
+fn write_periodic(
+  path: &Path,
+  charges: &[&account_activities::NonTradeActivity],
+  backup: bool,
+  ctx: &Context,
 ) -> Result<()> {
-  let mut unprocessed = VecDeque::new();
-  let mut request = account_activities::ActivityReq {
-    direction: account_activities::Direction::Ascending,
-    after: begin.map(|begin| Utc.from_utc_datetime(&begin.and_hms_opt(0, 0, 0).unwrap())),
-    ..Default::default()
+  let mut file = AtomicFile::create(path, false)?;
+
+  for charge in charges {
+    let mut periodic = Periodic::new("monthly");
+    periodic.notes.extend(charge.description.clone());
+    periodic.postings.push(Posting::new(
+      &ctx.accounts.brokerage_fee,
+      Some(Amount::cash(-&charge.net_amount, &ctx.currency)),
+    ));
+    periodic
+      .postings
+      .push(Posting::new(&ctx.accounts.brokerage, None));
+
+    writeln!(file, "{periodic}").with_context(|| format!("failed to write {}", path.display()))?;
+  }
+  file.commit(backup)
+}
+
+/// Write the interest accruals booked while processing activities, in
+/// date order, to the file at the given path or, if it is `-`, to
+/// stdout.
+fn write_accruals(path: &Path, append: bool, backup: bool, ctx: &Context) -> Result<()> {
+  let mut accruals = ctx.accruals.take();
+  let () = accruals.sort_by_key(|accrual| accrual.date);
+
+  if path == Path::new("-") {
+    let mut out = stdout().lock();
+    for accrual in accruals {
+      let () = emit_transaction(&mut out, accrual, ctx)?;
+    }
+    Ok(())
+  } else {
+    let mut file = AtomicFile::create(path, append)?;
+    for accrual in accruals {
+      let () = emit_transaction(&mut file, accrual, ctx)
+        .with_context(|| format!("failed to write interest accruals to {}", path.display()))?;
+    }
+    file.commit(backup)
+  }
+}
+
+
+/// Retrieve the orders that the provided trades belong to.
+async fn orders_for_trades(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  activities: &VecDeque<Activity>,
+) -> Result<HashMap<order::Id, order::Order>> {
+  let ids = activities
+    .iter()
+    .filter_map(|activity| match activity {
+      Activity::Trade(trade, _) => Some(trade.order_id),
+      Activity::Order(trades, _) => trades.first().map(|trade| trade.order_id),
+      // Summaries may combine trades of different orders.
+      Activity::Summary(..) | Activity::NonTrade(..) => None,
+    })
+    .collect::<HashSet<_>>();
+
+  let orders = iter(ids)
+    .map(|id| async move {
+      timed(timeout, issue::<order::Get>(client, &id))
+        .await
+        .classify(Failure::Api)
+        .with_context(|| format!("failed to retrieve order {:?}", id))
+    })
+    .buffer_unordered(32)
+    .try_collect::<Vec<_>>()
+    .await?;
+
+  let orders = orders.into_iter().map(|order| (order.id, order)).collect();
+  Ok(orders)
+}
+
+/// Retrieve the prices of crypto rewards that Alpaca did not report a
+/// price for, keyed by activity ID.
+async fn reward_prices(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  activities: &VecDeque<Activity>,
+  currency: &str,
+) -> Result<HashMap<String, Num>> {
+  let rewards = activities.iter().filter_map(|activity| match activity {
+    Activity::NonTrade(non_trade) if is_crypto_reward(non_trade) && non_trade.price.is_none() => {
+      Some(non_trade)
+    },
+    _ => None,
+  });
+
+  let prices = iter(rewards)
+    .map(|reward| async move {
+      let request = CryptoBarReq {
+        // SANITY: Crypto rewards always have a symbol.
+        symbol: crypto_pair(reward.symbol.as_ref().unwrap(), currency),
+        date: reward.date.date_naive(),
+      };
+      timed(timeout, issue::<GetCryptoBar>(client, &request))
+        .await
+        .classify(Failure::Api)
+        .with_context(|| {
+          format!(
+            "failed to retrieve {} price for {}",
+            request.symbol, request.date
+          )
+        })
+        .map(|mut bars| {
+          bars
+            .bars
+            .remove(&request.symbol)
+            .and_then(|bars| bars.into_iter().next())
+            .map(|bar| (reward.id.clone(), bar.close))
+        })
+    })
+    .buffer_unordered(32)
+    .try_collect::<Vec<_>>()
+    .await?;
+
+  let prices = prices.into_iter().flatten().collect();
+  Ok(prices)
+}
+
+/// Check whether an activity is selected for emitting, by ID as well as
+/// description.
+fn is_selected(activity: &Activity, ctx: &Context) -> bool {
+  if !ctx.ids.is_empty() && !activity.ids().iter().any(|id| ctx.ids.contains(*id)) {
+    return false
+  }
+
+  let texts = activity.texts();
+  let matches = |regex: &Regex| texts.iter().any(|text| regex.is_match(text));
+  match (&ctx.filter_description, &ctx.exclude_description) {
+    (Some(filter), _) if !matches(filter) => false,
+    (_, Some(exclude)) if matches(exclude) => false,
+    _ => true,
+  }
+}
+
+/// Print a single activity.
+fn print_activity(
+  output: &mut Output,
+  activity: &Activity,
+  orders: &HashMap<order::Id, order::Order>,
+  reward_prices: &HashMap<String, Num>,
+  ctx: &Context,
+) -> Result<()> {
+  if !is_selected(activity, ctx) {
+    return Ok(())
+  }
+
+  let out = output.writer(activity.date(ctx.timezone))?;
+  let result = match activity {
+    Activity::Trade(trade, fees) => print_trade(
+      out,
+      slice::from_ref(trade),
+      fees,
+      orders.get(&trade.order_id),
+      false,
+      ctx,
+    ),
+    Activity::Order(trades, fees) => print_trade(
+      out,
+      trades,
+      fees,
+      trades.first().and_then(|trade| orders.get(&trade.order_id)),
+      false,
+      ctx,
+    ),
+    Activity::Summary(trades, fees) => print_trade(out, trades, fees, None, true, ctx),
+    Activity::NonTrade(non_trade) => {
+      print_non_trade(out, non_trade, reward_prices.get(&non_trade.id), ctx)
+    },
   };
+  let () = result.classify(Failure::Classification)?;
+  let () = track_option_premiums(activity, ctx);
+  let () = summarize_activity(
+    activity,
+    activity.date(ctx.timezone),
+    &mut ctx.summary.borrow_mut(),
+  );
+  track_cash_balance(activity, ctx)
+}
+
+/// Account for a processed activity, dated as provided, in the
+/// provided summary.
+fn summarize_activity(activity: &Activity, date: NaiveDate, summary: &mut Summary) {
+  summary.activities += 1;
+  summary.first = Some(summary.first.map_or(date, |first| first.min(date)));
+  summary.last = Some(summary.last.map_or(date, |last| last.max(date)));
+
+  match activity {
+    Activity::Trade(_, fees) | Activity::Order(_, fees) | Activity::Summary(_, fees) => {
+      summary.fees -= sum(fees.iter().map(|fee| &fee.net_amount));
+    },
+    Activity::NonTrade(non_trade) => match non_trade.type_ {
+      account_activities::ActivityType::Fee | account_activities::ActivityType::PassThruCharge => {
+        summary.fees -= &non_trade.net_amount;
+      },
+      account_activities::ActivityType::Dividend
+      | account_activities::ActivityType::DividendReturnOfCapital => {
+        summary.dividends += &non_trade.net_amount;
+      },
+      _ => (),
+    },
+  }
+}
+
+/// Record the premium received for option contracts sold as part of
+/// the provided activity, if option premiums are being tracked.
+fn track_option_premiums(activity: &Activity, ctx: &Context) {
+  if !ctx.option_premium {
+    return
+  }
+
+  let trades = match activity {
+    Activity::Trade(trade, _) => slice::from_ref(trade),
+    Activity::Order(trades, _) | Activity::Summary(trades, _) => trades.as_slice(),
+    Activity::NonTrade(..) => return,
+  };
+
+  let mut premiums = ctx.premiums.borrow_mut();
+  for trade in trades {
+    let sold = matches!(
+      trade.side,
+      account_activities::Side::Sell | account_activities::Side::ShortSell
+    );
+    if sold && OptionContract::parse(&trade.symbol).is_some() {
+      let (quantity, premium) = premiums.entry(trade.symbol.clone()).or_default();
+      *quantity += &trade.quantity;
+      *premium += &trade.price * &trade.quantity * contract_multiplier(&trade.symbol);
+    }
+  }
+}
+
+/// Retrieve the premium received for the given quantity of an expired
+/// option contract, removing it from the tracked premiums.
+fn expired_premium(symbol: &str, quantity: &Num, ctx: &Context) -> Option<Num> {
+  let mut premiums = ctx.premiums.borrow_mut();
+  let (sold, premium) = premiums.get_mut(symbol)?;
+  if !sold.is_positive() {
+    return None
+  }
+
+  let quantity = quantity.min(sold).clone();
+  let expired = &*premium * &quantity / &*sold;
+  *premium -= &expired;
+  *sold -= &quantity;
+  Some(expired)
+}
+
+/// Update the running cash balance, if it is being tracked, with the
+/// cash flow of the provided activity, warning (or, in strict mode,
+/// failing) if it turns negative.
+fn track_cash_balance(activity: &Activity, ctx: &Context) -> Result<()> {
+  let mut cash_balance = ctx.cash_balance.borrow_mut();
+  if let Some(balance) = cash_balance.as_mut() {
+    let was_negative = balance.is_negative();
+    *balance += activity.cash_flow();
+
+    if balance.is_negative() && !was_negative {
+      let message = format!(
+        "cash balance turned negative ({}) on {}; activities may be missing or mis-ordered",
+        format_price(balance, &ctx.currency),
+        activity.date(ctx.timezone),
+      );
+      if ctx.strict {
+        return Err(anyhow!(message)).classify(Failure::Classification)
+      }
+      warn!("{message}");
+      let () = ctx.record_warning(WarningKind::NegativeCash, None, message);
+    }
+  }
+  Ok(())
+}
+
+/// Options controlling how account activities are processed.
+struct ProcessOptions {
+  /// The date of the first activity to process.
+  begin: Option<NaiveDate>,
+  /// The category of activities to process.
+  category: Option<Category>,
+  /// Whether to report fees separately instead of associating them
+  /// with trades.
+  force_separate_fees: bool,
+  /// Whether to group the fills of an order into a single transaction.
+  group_fills_per_order: bool,
+  /// How to summarize trades, if at all.
+  summary: Option<TradeSummary>,
+  /// Whether to collect activities that failed to process instead of
+  /// bailing out.
+  collect_failed: bool,
+}
+
+
+/// Information gathered while processing account activities.
+#[derive(Default)]
+struct Processed {
+  /// The commodities referenced by the activities.
+  commodities: BTreeSet<String>,
+  /// The symbols referenced by the activities, by date.
+  days: BTreeMap<NaiveDate, BTreeSet<String>>,
+  /// The pass-through charges encountered.
+  charges: Vec<account_activities::NonTradeActivity>,
+  /// Activities we failed to process, if we are to report them.
+  failed: Vec<account_activities::Activity>,
+}
+
+
+/// Process a batch of account activities, usually all those of a
+/// single day, printing the resulting transactions.
+///
+/// Orders are retrieved via the provided client when needed. Without
+/// a client, anything depending on them won't be rendered.
+async fn process_activities(
+  client: Option<&Client>,
+  timeout: Option<StdDuration>,
+  mut activities: VecDeque<account_activities::Activity>,
+  options: &ProcessOptions,
+  processed: &mut Processed,
+  output: &mut Output,
+  ctx: &Context,
+) -> Result<()> {
+  if let Some(begin) = options.begin {
+    activities.retain(|activity| activity_date(activity, ctx.timezone) >= begin);
+  }
+  for activity in &activities {
+    let symbol = match activity {
+      account_activities::Activity::Trade(trade) => Some(&trade.symbol),
+      account_activities::Activity::NonTrade(non_trade) => non_trade.symbol.as_ref(),
+    };
+    if let Some(symbol) = symbol {
+      processed.commodities.insert(symbol.clone());
+      processed
+        .days
+        .entry(activity_date(activity, ctx.timezone))
+        .or_default()
+        .insert(symbol.clone());
+    }
+
+    if let account_activities::Activity::NonTrade(non_trade) = activity {
+      if non_trade.type_ == account_activities::ActivityType::PassThruCharge {
+        let () = processed.charges.push(non_trade.clone());
+      }
+    }
+  }
+
+  let activities = merge_partial_fills(activities);
+  let activities = if options.force_separate_fees {
+    activities
+      .into_iter()
+      .map(Activity::from)
+      .collect::<VecDeque<_>>()
+  } else {
+    associate_fees_with_trades(activities)
+  };
+  if !options.force_separate_fees {
+    let () = check_unmatched(&activities, ctx);
+  }
+
+  let activities = match (options.group_fills_per_order, options.summary) {
+    (_, Some(TradeSummary::Daily)) => summarize_trades(activities, ctx.timezone),
+    (true, None) => group_fills(activities),
+    (false, None) => activities,
+  };
+  // Filter by category only once fees got associated with trades, so
+  // that those are reported along with the trades they belong to.
+  let activities = if let Some(category) = options.category {
+    activities
+      .into_iter()
+      .filter(|activity| match activity {
+        Activity::NonTrade(..) => category == Category::NonTrade,
+        _ => category == Category::Trade,
+      })
+      .collect()
+  } else {
+    activities
+  };
+
+  // Orders are only needed for mapping trades to strategies and for
+  // reporting order details, so don't bother retrieving them
+  // otherwise.
+  let orders = match client {
+    Some(client) if !ctx.strategies.is_empty() || ctx.order_details => {
+      orders_for_trades(client, timeout, &activities).await?
+    },
+    _ => HashMap::new(),
+  };
+  let reward_prices = match client {
+    Some(client) => reward_prices(client, timeout, &activities, &ctx.currency).await?,
+    None => HashMap::new(),
+  };
+
+  for activity in activities {
+    if let Err(err) = print_activity(output, &activity, &orders, &reward_prices, ctx) {
+      if options.collect_failed {
+        warn!("failed to process activity: {err:#}");
+        let () = ctx.record_warning(
+          WarningKind::Failure,
+          activity.ids().first().copied(),
+          format!("failed to process activity: {err:#}"),
+        );
+        processed.failed.extend(activity.into_raw());
+      } else {
+        return Err(err)
+      }
+    }
+  }
+  Ok(())
+}
+
+
+async fn activities_list(
+  client: &Arc<Client>,
+  timeout: Option<StdDuration>,
+  options: &ProcessOptions,
+  order: SortOrder,
+  exclude_status: &[String],
+  declarations: Option<&Path>,
+  periodic: Option<&Path>,
+  accruals: Option<&Path>,
+  error_report: Option<&Path>,
+  state: Option<&Path>,
+  cache: Option<&Path>,
+  refresh_after: Option<NaiveDate>,
+  limit: Option<usize>,
+  chunk_days: Option<u32>,
+  dump: Option<&Path>,
+  prices: Option<&Path>,
+  backup: bool,
+  mut output: Output,
+  ctx: &Context,
+) -> Result<()> {
+  ensure!(
+    state.is_none() || order == SortOrder::Ascending,
+    "resuming from a state file is only supported in ascending order"
+  );
+  ensure!(
+    cache.is_none() || order == SortOrder::Ascending,
+    "caching activities is only supported in ascending order"
+  );
+  ensure!(
+    chunk_days.is_none() || order == SortOrder::Ascending,
+    "retrieving activities in chunks is only supported in ascending order"
+  );
+
+  let (cache, cached) = if let Some(path) = cache {
+    let (cache, cached) = Cache::open(path, refresh_after)?;
+    (Some(cache), cached)
+  } else {
+    (None, Vec::new())
+  };
+
+  let chunk = chunk_days.map(|days| Duration::days(days.into()));
+  let dump = dump.map(Dump::new).transpose()?;
+  let direction = match order {
+    SortOrder::Ascending => account_activities::Direction::Ascending,
+    SortOrder::Descending => account_activities::Direction::Descending,
+  };
+  let mut request = account_activities::ActivityReq {
+    // Fees are needed for associating them with trades, so retrieve
+    // them along with fills. As for the begin date, we can't restrict
+    // the request when using a cache.
+    types: match options.category.filter(|_| cache.is_none()) {
+      Some(Category::Trade) => vec![
+        account_activities::ActivityType::Fill,
+        account_activities::ActivityType::Fee,
+      ],
+      _ => Vec::new(),
+    },
+    direction,
+    // The cache has to cover the entire account history, so we can't
+    // restrict the request when using it but have to filter later on.
+    after: options
+      .begin
+      .filter(|_| cache.is_none())
+      .map(|begin| day_start(begin, ctx.timezone)),
+    ..Default::default()
+  };
+  // The date of the last activity we know about already, if any.
+  let mut resume = None;
+  if let Some(last) = cached.last() {
+    // Only retrieve what is not yet cached.
+    request.page_token = Some(last.id().to_string());
+    resume = Some(activity_date(last, ctx.timezone));
+  }
+
+  if let Some(path) = state {
+    if let Some(state) = State::load(path)? {
+      // Resume right after the last activity we emitted previously.
+      request.page_token = Some(state.last_id);
+      resume = Some(state.date);
+    }
+  }
+
+  if let Some(chunk) = chunk {
+    // When resuming, the first window starts on the day of the last
+    // activity we know about, as nothing before it is of interest.
+    let after = resume
+      .map(|date| day_start(date, ctx.timezone))
+      .or(request.after)
+      .context("retrieving activities in chunks requires a begin date, a cache, or a state")?;
+    request.after = Some(after);
+    request.until = Some(after + chunk);
+  }
+
+  let mut processed = Processed::default();
+  processed.commodities.insert(ctx.currency.clone());
+
+  // Listen for Ctrl-C, so that we can stop on a day boundary instead
+  // of getting killed in the middle of emitting a transaction. Once
+  // polled for the first time, the signal no longer terminates us.
+  let interrupt = ctrl_c();
+  pin!(interrupt);
+  let mut interrupted = false;
+
+  // Retrieve activities on a separate task, so that fetching the next
+  // days overlaps with processing the current one.
+  let (sender, mut receiver) = channel(1);
+  let fetcher = spawn(fetch_days(
+    client.clone(),
+    timeout,
+    VecDeque::from(cached),
+    request,
+    chunk,
+    ctx.timezone,
+    exclude_status.to_vec(),
+    cache,
+    dump,
+    sender,
+  ));
+
+  // The number of activities retrieved so far.
+  let mut count = 0;
+  loop {
+    // Don't wait for the next day to be retrieved when interrupted,
+    // which may take a while when rate limited.
+    let recv = receiver.recv();
+    pin!(recv);
+    let activities = match select(&mut interrupt, recv).await {
+      Either::Left((result, _)) => {
+        let () = result.context("failed to listen for Ctrl-C")?;
+        warn!("interrupted; stopping after the last complete day");
+        interrupted = true;
+        break
+      },
+      Either::Right((Some(activities), _)) => activities,
+      Either::Right((None, _)) => break,
+    };
+
+    let mut activities = activities?;
+
+    // Only process as many of the day's activities as the limit
+    // permits. Because we record the last activity processed in the
+    // state, a later run resumes right after it.
+    let limited = if let Some(limit) = limit {
+      let () = activities.truncate(limit - count);
+      count + activities.len() >= limit
+    } else {
+      false
+    };
+    count += activities.len();
+
+    // Remember the last activity reported, which is the most recent one
+    // in ascending order. Note that we have to do so before any
+    // filtering takes place.
+    let last = activities
+      .back()
+      .map(|last| (last.id().to_string(), activity_date(last, ctx.timezone)));
+
+    let () = process_activities(
+      Some(client),
+      timeout,
+      activities,
+      options,
+      &mut processed,
+      &mut output,
+      ctx,
+    )
+    .await?;
+    // Bail out before recording any progress, so that ignored
+    // activities are retried on the next run.
+    let () = check_ignored(ctx)?;
+
+    if let (Some(path), Some((last_id, date))) = (state, last) {
+      // Make sure that everything we printed has actually been written
+      // out before recording our progress.
+      let () = output.flush()?;
+      let () = State { last_id, date }.save(path)?;
+    }
+
+    if limited {
+      info!("reached limit of {count} activities; stopping");
+      break
+    }
+  }
+  // We may have stopped early, in which case there is no point in
+  // retrieving further activities.
+  let () = fetcher.abort();
+  // Everything up to the last complete day has been processed at this
+  // point. Make sure it got written out, even if writing any of the
+  // auxiliary files below fails.
+  let () = output.flush()?;
+
+  if let Some(path) = periodic {
+    let () = write_periodic(path, &recurring_charges(&processed.charges), backup, ctx)?;
+  }
+
+  let () = output.finish()?;
+
+  if let Some(path) = accruals {
+    // When resuming from a previous run we have to append to the
+    // accruals written before.
+    let () = write_accruals(path, state.is_some(), backup, ctx)?;
+  }
+
+  if let Some(path) = declarations {
+    // Declare everything that got referenced, including the accruals
+    // written just now, along with all configured accounts.
+    let mut references = ctx.references.take();
+    references.accounts.extend(ctx.all_accounts());
+    let () = write_declarations(path, &references, backup, &ctx.registry)?;
+  }
+
+  ensure!(!interrupted, "account activity export got interrupted");
+
+  if let Some(path) = prices {
+    // Alpaca does not provide historical bars for option contracts,
+    // so we can't report prices for them.
+    let priced =
+      |symbol: &&String| **symbol != ctx.currency && OptionContract::parse(symbol).is_none();
+    let today = ctx.today;
+    let options = PriceOptions::default();
+    let mut file = AtomicFile::create(path, true)?;
+
+    // Record the prices of the assets involved on each day with
+    // activity, followed by the current prices of all of them.
+    let days = processed
+      .days
+      .range(..today)
+      .map(|(date, symbols)| (*date, symbols.iter().filter(priced).cloned().collect()))
+      .chain(once((
+        today,
+        processed
+          .commodities
+          .iter()
+          .filter(priced)
+          .cloned()
+          .collect(),
+      )));
+    for (date, symbols) in days {
+      let () = prices_get(client, timeout, symbols, date, today, &options, &mut file)
+        .await
+        .with_context(|| format!("failed to update price database {}", path.display()))?;
+    }
+    let () = file.commit(backup)?;
+  }
+
+  if let Some(path) = error_report {
+    if !processed.failed.is_empty() {
+      let mut file = AtomicFile::create(path, false)?;
+      let () = to_json_writer_pretty(&mut file, &json::activities(&processed.failed))
+        .with_context(|| format!("failed to write error report {}", path.display()))?;
+      let () = file.commit(false)?;
+      bail!(
+        "failed to process {} account activities; see {} for details",
+        processed.failed.len(),
+        path.display()
+      )
+    }
+  }
+  Ok(())
+}
+
+
+/// Render the account activities contained in the JSON file at the
+/// given path, without any interaction with Alpaca.
+async fn render_fixture(
+  path: &Path,
+  options: &ProcessOptions,
+  accruals: Option<&Path>,
+  mut output: Output,
+  ctx: &Context,
+) -> Result<()> {
+  let file =
+    File::open(path).with_context(|| format!("failed to open fixture {}", path.display()))?;
+  // Fixtures are parsed just like responses of the activities
+  // endpoint, including what apca does not cover.
+  let activities = json_from_reader::<_, Vec<StatusActivity>>(file)
+    .with_context(|| format!("failed to read fixture {}", path.display()))?
+    .into_iter()
+    .map(|activity| activity.activity)
+    .collect();
+
+  // Fixtures don't come with orders, so anything depending on them
+  // won't be rendered.
+  let mut processed = Processed::default();
+  let () = process_activities(
+    None,
+    None,
+    activities,
+    options,
+    &mut processed,
+    &mut output,
+    ctx,
+  )
+  .await?;
+  ensure!(
+    processed.failed.is_empty(),
+    "failed to process {} account activities",
+    processed.failed.len()
+  );
+  let () = check_ignored(ctx)?;
+  let () = output.finish()?;
+
+  if let Some(path) = accruals {
+    let () = write_accruals(path, false, false, ctx)?;
+  }
+  Ok(())
+}
+
+
+/// Retrieve the price of the asset with the given symbol, formatted as
+/// a Ledger price directive.
+async fn price_get<F>(
+  client: &Client,
+  symbol: String,
+  date: NaiveDate,
+  today: NaiveDate,
+  options: &PriceOptions,
+  clock: Shared<F>,
+) -> Result<String>
+where
+  F: Future<Output = Result<clock::Clock, Arc<RequestError<clock::GetError>>>>,
+{
+  ensure!(date <= today, "the provided date needs to be in the past");
+
+  // Make sure to cover the averaging window, accounting for weekends
+  // and holidays.
+  let window = i64::try_from(options.price_window).unwrap_or(i64::MAX / 2);
+  let start = date - Duration::weeks(2) - Duration::days(window.saturating_mul(2));
+  let start = New_York
+    .with_ymd_and_hms(start.year(), start.month(), start.day(), 0, 0, 0)
+    .unwrap()
+    .with_timezone(&Utc);
+  let end = min(date + Duration::weeks(1), today);
+  let end = New_York
+    .with_ymd_and_hms(end.year(), end.month(), end.day(), 0, 0, 0)
+    .unwrap()
+    .with_timezone(&Utc);
+
+  let request = bars::ListReqInit {
+    adjustment: Some(adjustment(options.adjustment)),
+    ..Default::default()
+  }
+  .init(symbol.clone(), start, end, bars::TimeFrame::OneDay);
+
+  let bars = issue::<bars::List>(client, &request);
+
+  let (response1, response2) = join(bars, clock).await;
+  let mut bars = response1
+    .classify(Failure::Api)
+    .with_context(|| {
+      format!(
+        "failed to retrieve historical aggregate bars for {}",
+        symbol
+      )
+    })?
+    .bars;
+  let clock = response2
+    .classify(Failure::Api)
+    .context("failed to retrieve current market clock")?;
+
+  let key_fn = |bar: &bars::Bar| bar.time;
+  // Alpaca does not document a specific order in which the bars are
+  // reported, so sort them to be sure they are ascending.
+  bars.sort_unstable_by_key(key_fn);
+
+  let mut utc_date = New_York
+    .with_ymd_and_hms(date.year(), date.month(), date.day(), 0, 0, 0)
+    .unwrap()
+    .with_timezone(&Utc);
+
+  // If the market is currently open (or opens later today) then we are
+  // interested in yesterday's date. The reason being that Alpaca
+  // would report bars for the ongoing day, and those will change until
+  // we reached the end of the trading day.
+  if clock.open || clock.next_open.date_naive() == utc_date.date_naive() {
+    utc_date = utc_date - Duration::days(1);
+  }
+
+  if bars.is_empty() {
+    if let Some(price) = delisted_price_get(client, &symbol, date, options).await? {
+      return Ok(price)
+    }
+    bail!("no historical bars found for {}", symbol)
+  }
+
+  let index = match bars.binary_search_by_key(&utc_date, key_fn) {
+    Ok(index) => index,
+    // The index reported here is where we would insert. But given that
+    // we do not insert we have to subtract one in order to get the
+    // previous bar. If we are past the last bar that we received, we
+    // just pick the last one.
+    Err(index) => min(index.saturating_sub(1), bars.len() - 1),
+  };
+  let bar = &bars[index];
+
+  let price = format!(
+    "P {date} 23:59:59 {sym} USD {price}",
+    date = New_York
+      .from_utc_datetime(&bar.time.naive_utc())
+      .date_naive(),
+    sym = symbol,
+    price = bar_price(&bars[..=index], options)
+      .display()
+      .min_precision(2),
+  );
+  Ok(price)
+}
+
+
+/// Convert an `Adjustment` into its apca counterpart.
+fn adjustment(adjustment: Adjustment) -> bars::Adjustment {
+  match adjustment {
+    Adjustment::Raw => bars::Adjustment::Raw,
+    Adjustment::Split => bars::Adjustment::Split,
+    Adjustment::Dividend => bars::Adjustment::Dividend,
+    Adjustment::All => bars::Adjustment::All,
+  }
+}
+
+
+/// Calculate the price to report from the provided bars, the last of
+/// which is the one the price is reported for.
+fn bar_price(bars: &[bars::Bar], options: &PriceOptions) -> Num {
+  let window = &bars[bars.len().saturating_sub(options.price_window.max(1))..];
+  let count = Num::from(window.len());
+
+  match options.price_field {
+    PriceField::Close => sum(window.iter().map(|bar| &bar.close)) / count,
+    PriceField::Vwap => {
+      let volume = sum(window.iter().map(|bar| Num::from(bar.volume)));
+      if volume.is_zero() {
+        sum(window.iter().map(|bar| &bar.weighted_average)) / count
+      } else {
+        sum(
+          window
+            .iter()
+            .map(|bar| &bar.weighted_average * Num::from(bar.volume)),
+        ) / volume
+      }
+    },
+  }
+}
+
+
+/// Retrieve the price of the asset with the given symbol at the given
+/// time of day, formatted as a Ledger price directive.
+async fn intraday_price_get(
+  client: &Client,
+  symbol: String,
+  date: NaiveDate,
+  time: NaiveTime,
+  options: &PriceOptions,
+) -> Result<String> {
+  let at = New_York
+    .from_local_datetime(&date.and_time(time))
+    .earliest()
+    .with_context(|| format!("{date} {time} is not a valid time in New York"))?
+    .with_timezone(&Utc);
+  ensure!(
+    at <= Utc::now(),
+    "the provided time needs to be in the past"
+  );
+
+  let (timeframe, length) = match options.timeframe {
+    TimeFrame::Minute => (bars::TimeFrame::OneMinute, Duration::minutes(1)),
+    TimeFrame::Hour => (bars::TimeFrame::OneHour, Duration::hours(1)),
+  };
+  let start = New_York
+    .with_ymd_and_hms(date.year(), date.month(), date.day(), 0, 0, 0)
+    .unwrap()
+    .with_timezone(&Utc);
+
+  let request = bars::ListReqInit {
+    adjustment: Some(adjustment(options.adjustment)),
+    ..Default::default()
+  }
+  .init(symbol.clone(), start, at, timeframe);
+
+  let mut bars = issue::<bars::List>(client, &request)
+    .await
+    .classify(Failure::Api)
+    .with_context(|| format!("failed to retrieve intraday bars for {}", symbol))?
+    .bars;
+  bars.sort_unstable_by_key(|bar| bar.time);
+
+  // A bar's close is the price at the end of the bar, so pick the last
+  // bar that ended no later than the requested time.
+  let index = bars
+    .iter()
+    .rposition(|bar| bar.time + length <= at)
+    .ok_or_else(|| anyhow!("no intraday bars found for {} on {}", symbol, date))?;
+  let bar = &bars[index];
+
+  let price = format!(
+    "P {time} {sym} USD {price}",
+    time = (bar.time + length)
+      .with_timezone(&New_York)
+      .format("%Y-%m-%d %H:%M:%S"),
+    sym = symbol,
+    price = bar_price(&bars[..=index], options)
+      .display()
+      .min_precision(2),
+  );
+  Ok(price)
+}
+
+
+/// Retrieve the price the given list of assets and write them to the
+/// provided writer. `today` is the current date, which `date` must not
+/// lie after.
+async fn prices_get(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  symbols: Vec<String>,
+  date: NaiveDate,
+  today: NaiveDate,
+  options: &PriceOptions,
+  out: &mut dyn Write,
+) -> Result<()> {
+  // We need the current market clock to decide which price exactly to
+  // report. But we only want to make one market clock request. So we
+  // have to `Arc` up the error here in order for us to be able to share
+  // the future.
+  let clock = issue::<clock::Get>(client, &()).map_err(Arc::new).shared();
+
+  let count = symbols.len();
+  let mut results = iter(symbols)
+    .map(|symbol| {
+      let clock = clock.clone();
+      async move {
+        let result = timed(timeout, async {
+          if let Some(time) = options.time {
+            intraday_price_get(client, symbol.clone(), date, time, options).await
+          } else {
+            price_get(client, symbol.clone(), date, today, options, clock).await
+          }
+        })
+        .await;
+        (symbol, result)
+      }
+    })
+    .buffer_unordered(32);
+
+  // Make sure to process all items, such that all successfully
+  // retrieved prices are printed, and collect the failures.
+  let mut failed = Vec::new();
+  while let Some((symbol, result)) = results.next().await {
+    match result {
+      Ok(price) => writeln!(out, "{price}")?,
+      Err(err) => failed.push((symbol, err)),
+    }
+  }
+
+  // Try the fallback sources, in order, for all symbols we failed to
+  // retrieve a price for from Alpaca.
+  let mut failed = failed
+    .into_iter()
+    .filter_map(
+      |(symbol, err)| match fallback_price_get(&symbol, date, &options.fallback) {
+        Ok(Some(price)) => match writeln!(out, "{price}") {
+          Ok(()) => None,
+          Err(err) => Some((symbol, err.into())),
+        },
+        Ok(None) => Some((symbol, err)),
+        Err(fallback_err) => Some((symbol, err.context(format!("{fallback_err:#}")))),
+      },
+    )
+    .collect::<Vec<_>>();
+
+  if !failed.is_empty() {
+    failed.sort_by(|(symbol1, _), (symbol2, _)| symbol1.cmp(symbol2));
+    let failure = PartialFailure {
+      what: "failed to retrieve prices",
+      count,
+      failed,
+    };
+    return Err(failure.into())
+  }
+  Ok(())
+}
+
+
+/// Retrieve the last price of the asset with the given symbol, if it
+/// is no longer active (e.g., because it got delisted), formatted as a
+/// Ledger price directive preceded by a comment.
+///
+/// Alpaca does not report when an asset got delisted, so we search
+/// backwards from the given date, a year at a time, for the last bar.
+async fn delisted_price_get(
+  client: &Client,
+  symbol: &str,
+  date: NaiveDate,
+  options: &PriceOptions,
+) -> Result<Option<String>> {
+  let request = asset::Symbol::Sym(symbol.to_string());
+  let asset = issue::<asset::Get>(client, &request)
+    .await
+    .classify(Failure::Api)
+    .with_context(|| format!("failed to retrieve asset information for {}", symbol))?;
+  if asset.status != asset::Status::Inactive {
+    return Ok(None)
+  }
+
+  let midnight = |date: NaiveDate| {
+    New_York
+      .with_ymd_and_hms(date.year(), date.month(), date.day(), 0, 0, 0)
+      .unwrap()
+      .with_timezone(&Utc)
+  };
+
+  let mut end = date;
+  for _ in 0..DELISTED_LOOKBACK_YEARS {
+    let start = end - Duration::days(365);
+    let request = bars::ListReqInit {
+      adjustment: Some(adjustment(options.adjustment)),
+      ..Default::default()
+    }
+    .init(
+      symbol.to_string(),
+      midnight(start),
+      midnight(end),
+      bars::TimeFrame::OneDay,
+    );
+    let mut bars = issue::<bars::List>(client, &request)
+      .await
+      .classify(Failure::Api)
+      .with_context(|| {
+        format!(
+          "failed to retrieve historical aggregate bars for {}",
+          symbol
+        )
+      })?
+      .bars;
+
+    if let Some(last) = bars.iter().map(|bar| bar.time).max() {
+      bars.sort_unstable_by_key(|bar| bar.time);
+      let last = New_York.from_utc_datetime(&last.naive_utc()).date_naive();
+      let price = format!(
+        "; {sym} is no longer active; last price as of {last}\nP {last} 23:59:59 {sym} USD {price}",
+        sym = symbol,
+        price = bar_price(&bars, options).display().min_precision(2),
+      );
+      return Ok(Some(price))
+    }
+    end = start;
+  }
+  Ok(None)
+}
+
+
+/// Read the most recent price of each commodity from a Ledger price
+/// database.
+fn read_price_db<R>(reader: R) -> Result<HashMap<String, Num>>
+where
+  R: BufRead,
+{
+  let mut prices = HashMap::new();
+  for line in reader.lines() {
+    if let Some((commodity, price)) = parse_price_directive(&line?) {
+      prices.insert(commodity, price);
+    }
+  }
+  Ok(prices)
+}
+
+/// Check whether the change from one price to another looks like the
+/// result of a (reverse) split, returning the split ratio if so.
+///
+/// A ratio is considered if it is within two percent of a whole number.
+fn split_ratio(previous: &Num, price: &Num) -> Option<(Num, Num)> {
+  if !previous.is_positive() || !price.is_positive() {
+    return None
+  }
+
+  let near_integer = |ratio: Num| {
+    let rounded = ratio.round();
+    if rounded <= Num::from(1) {
+      return None
+    }
+
+    let deviation = (&ratio - &rounded) / &rounded;
+    let deviation = if deviation.is_negative() {
+      -deviation
+    } else {
+      deviation
+    };
+    (deviation <= Num::new(2, 100)).then_some(rounded)
+  };
+
+  if let Some(ratio) = near_integer(previous / price) {
+    Some((ratio, Num::from(1)))
+  } else {
+    near_integer(price / previous).map(|ratio| (Num::from(1), ratio))
+  }
+}
+
+/// Warn about prices in the provided price directives that deviate
+/// from the previous ones by more than the given percentage.
+fn check_prices(directives: &str, previous: &HashMap<String, Num>, max_change: &Num) {
+  for (commodity, price) in directives.lines().filter_map(parse_price_directive) {
+    if let Some(previous) = previous.get(&commodity) {
+      if previous.is_zero() {
+        continue
+      }
+
+      let change = (&price - previous) * 100 / previous;
+      let change = if change.is_negative() {
+        -change
+      } else {
+        change
+      };
+      if &change > max_change {
+        let cause = match split_ratio(previous, &price) {
+          Some((from, to)) => format!("likely a {from}:{to} split"),
+          None => "possible split or bad data".to_string(),
+        };
+        warn!(
+          "price of {commodity} changed by {}% from {previous} to {price}; {cause}",
+          change.round_with(2)
+        );
+      }
+    }
+  }
+}
+
+
+/// Retrieve the price of the asset with the given symbol, formatted as
+/// a Ledger price directive, from the first of the provided fallback
+/// sources that knows about it.
+fn fallback_price_get(
+  symbol: &str,
+  date: NaiveDate,
+  sources: &[PriceSource],
+) -> Result<Option<String>> {
+  for source in sources {
+    if let Some((date, price)) = source.price(symbol, date)? {
+      return Ok(Some(format_price_directive(date, symbol, &price, "USD")))
+    }
+  }
+  Ok(None)
+}
+
+
+/// Convert prices from CSV lines of the form
+/// `<symbol>,<yyyy-mm-dd>,<price>[,<currency>]` into price directives.
+fn prices_from_csv<R>(reader: R, out: &mut dyn Write) -> Result<()>
+where
+  R: BufRead,
+{
+  for (index, line) in reader.lines().enumerate() {
+    let line = line?;
+    let line = line.trim();
+    if line.is_empty() || (index == 0 && line.to_lowercase().starts_with("symbol,")) {
+      continue
+    }
+
+    let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+    let (symbol, date, price, currency) = match fields.as_slice() {
+      [symbol, date, price] => (symbol, date, price, "USD"),
+      [symbol, date, price, currency] => (symbol, date, price, *currency),
+      _ => bail!(
+        "line {}: expected <symbol>,<date>,<price>[,<currency>] but found '{}'",
+        index + 1,
+        line
+      ),
+    };
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+      .with_context(|| format!("line {}: failed to parse date '{}'", index + 1, date))?;
+    let price = price
+      .parse::<Num>()
+      .with_context(|| format!("line {}: failed to parse price '{}'", index + 1, price))?;
+
+    let directive = format_price_directive(date, symbol, &price, currency);
+    let () = writeln!(out, "{directive}")?;
+  }
+  Ok(())
+}
+
+
+/// Print an opening balance transaction for the currently held
+/// positions and cash.
+async fn opening_balances(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  date: NaiveDate,
+  accounts: &OpeningAccounts<'_>,
+  out: &mut dyn Write,
+) -> Result<()> {
+  let (account, positions) = join(
+    timed(timeout, issue::<account::Get>(client, &())),
+    timed(timeout, issue::<positions::List>(client, &())),
+  )
+  .await;
+  let account = account
+    .classify(Failure::Api)
+    .context("failed to retrieve account information")?;
+  let mut positions = positions
+    .classify(Failure::Api)
+    .context("failed to retrieve open positions")?;
+  positions.sort_by(|position1, position2| position1.symbol.cmp(&position2.symbol));
+
+  let mut transaction = Transaction::new(date, None, "Opening Balances");
+  transaction
+    .notes
+    .push("Cost basis derived from average entry prices, not individual lots".to_string());
+
+  for position in positions {
+    // Alpaca reports the average entry price of option contracts per
+    // share of the underlying.
+    let price = position.average_entry_price * contract_multiplier(&position.symbol);
+    let investment = accounts.investment_account(&position.symbol).into_owned();
+    let amount = Amount::Price {
+      quantity: position.quantity,
+      commodity: position.symbol,
+      price,
+      currency: account.currency.clone(),
+      balance: None,
+    };
+    transaction
+      .postings
+      .push(Posting::new(investment, Some(amount)));
+  }
+
+  let cash = Amount::cash(account.cash, &account.currency);
+  transaction
+    .postings
+    .push(Posting::new(accounts.brokerage, Some(cash)));
+  transaction
+    .postings
+    .push(Posting::new(accounts.equity, None));
+
+  writeln!(out, "{transaction}")?;
+  Ok(())
+}
+
+
+/// The accounts to book opening balances against.
+struct OpeningAccounts<'s> {
+  /// The account holding the shares.
+  investment: &'s str,
+  /// Whether to hold shares in per-symbol sub-accounts of the
+  /// investment account.
+  per_symbol: bool,
+  /// The account holding any uninvested cash.
+  brokerage: &'s str,
+  /// The account to balance against.
+  equity: &'s str,
+}
+
+impl OpeningAccounts<'_> {
+  /// Retrieve the account holding shares of the asset with the given
+  /// symbol.
+  fn investment_account(&self, symbol: &str) -> Cow<'_, str> {
+    if self.per_symbol {
+      Cow::from(format!("{}:{symbol}", self.investment))
+    } else {
+      Cow::from(self.investment)
+    }
+  }
+}
+
+
+/// Print the market days in the given range, along with their opening
+/// and closing times in New York time.
+async fn calendar_list(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  begin: NaiveDate,
+  end: NaiveDate,
+  json: bool,
+  out: &mut dyn Write,
+) -> Result<()> {
+  // The end date is exclusive as far as Alpaca is concerned.
+  let request = calendar::ListReqInit::default().init(begin, end + Duration::days(1));
+  let days = timed(timeout, issue::<calendar::List>(client, &request))
+    .await
+    .classify(Failure::Api)
+    .with_context(|| format!("failed to retrieve market calendar for {begin} to {end}"))?;
+
+  if json {
+    let () =
+      to_json_writer_pretty(&mut *out, &days).with_context(|| "failed to write market calendar")?;
+    let () = writeln!(out)?;
+  } else {
+    for day in days {
+      let () = writeln!(
+        out,
+        "{} {} {}",
+        day.date,
+        day.open.format("%H:%M"),
+        day.close.format("%H:%M")
+      )?;
+    }
+  }
+  Ok(())
+}
+
+
+/// The account's equity at the end of a day.
+#[derive(Clone, Debug, PartialEq)]
+struct DailyEquity {
+  /// The day, in New York time.
+  date: NaiveDate,
+  /// The account's equity at the end of the day.
+  equity: Num,
+  /// The profit or loss relative to the history's base value.
+  profit_loss: Num,
+  /// The profit or loss as a fraction of the history's base value.
+  profit_loss_pct: Num,
+}
+
+
+/// Retrieve the account's equity at the end of each day in the given
+/// range, skipping days for which Alpaca has no data.
+async fn portfolio_history(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  begin: NaiveDate,
+  end: NaiveDate,
+) -> Result<Vec<DailyEquity>> {
+  let request = PortfolioHistoryReq { start: begin, end };
+  let history = timed(timeout, issue::<GetPortfolioHistory>(client, &request))
+    .await
+    .classify(Failure::Api)
+    .with_context(|| format!("failed to retrieve portfolio history for {begin} to {end}"))?;
+
+  let days = history
+    .timestamps
+    .into_iter()
+    .zip(history.equity)
+    .zip(history.profit_loss)
+    .zip(history.profit_loss_pct)
+    .filter_map(|(((timestamp, equity), profit_loss), profit_loss_pct)| {
+      let date = DateTime::from_timestamp(timestamp, 0)?
+        .with_timezone(&New_York)
+        .date_naive();
+      Some(DailyEquity {
+        date,
+        equity: equity?,
+        profit_loss: profit_loss.unwrap_or_default(),
+        profit_loss_pct: profit_loss_pct.unwrap_or_default(),
+      })
+    })
+    .filter(|day| day.date >= begin && day.date <= end)
+    .collect();
+  Ok(days)
+}
+
+
+/// Print the account's daily equity, either as CSV or as Ledger price
+/// directives for the given pseudo-commodity.
+fn report_history(
+  days: &[DailyEquity],
+  ledger: Option<(&str, &str)>,
+  out: &mut dyn Write,
+) -> Result<()> {
+  if let Some((commodity, currency)) = ledger {
+    for day in days {
+      let directive = format_price_directive(day.date, commodity, &day.equity, currency);
+      let () = writeln!(out, "{directive}")?;
+    }
+  } else {
+    let () = writeln!(out, "Date,Equity,Profit or Loss,Profit or Loss (%)")?;
+    for day in days {
+      let () = writeln!(
+        out,
+        "{date},{equity},{profit_loss},{profit_loss_pct}",
+        date = day.date,
+        equity = day.equity.round_with(2).display().min_precision(2),
+        profit_loss = day.profit_loss.round_with(2).display().min_precision(2),
+        profit_loss_pct = (&day.profit_loss_pct * 100)
+          .round_with(2)
+          .display()
+          .min_precision(2),
+      )?;
+    }
+  }
+  Ok(())
+}
+
+
+/// Print the account's equity at the end of each day in the given
+/// range.
+async fn history(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  begin: NaiveDate,
+  end: NaiveDate,
+  ledger: Option<&str>,
+  out: &mut dyn Write,
+) -> Result<()> {
+  let days = portfolio_history(client, timeout, begin, end).await?;
+  if let Some(commodity) = ledger {
+    let account = timed(timeout, issue::<account::Get>(client, &()))
+      .await
+      .classify(Failure::Api)
+      .context("failed to retrieve account information")?;
+    report_history(&days, Some((commodity, &account.currency)), out)
+  } else {
+    report_history(&days, None, out)
+  }
+}
+
+
+/// Report the account's total equity at the end of the given day as the
+/// price of the given pseudo-commodity.
+async fn equity_get(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  commodity: &str,
+  date: NaiveDate,
+  out: &mut dyn Write,
+) -> Result<()> {
+  // There is no data point for days the market is closed, on which
+  // the equity is unchanged from the last day it was open. So look a
+  // few days back to cover weekends and holidays.
+  let (history, account) = join(
+    portfolio_history(client, timeout, date - Duration::days(7), date),
+    timed(timeout, issue::<account::Get>(client, &())),
+  )
+  .await;
+  let account = account
+    .classify(Failure::Api)
+    .context("failed to retrieve account information")?;
+  let day = history?
+    .pop()
+    .ok_or_else(|| anyhow!("no account equity reported for {date}"))?;
+
+  let directive = format_price_directive(date, commodity, &day.equity, &account.currency);
+  let () = writeln!(out, "{directive}")?;
+  Ok(())
+}
+
+
+/// Report the outcome of a single check performed by the `doctor`
+/// command, along with a hint on how to fix it if it failed.
+fn report_check(out: &mut dyn Write, check: &str, result: &Result<()>, hint: &str) -> Result<()> {
+  match result {
+    Ok(()) => writeln!(out, "ok      {check}")?,
+    Err(err) => {
+      let () = writeln!(out, "FAILED  {check}: {err:#}")?;
+      let () = writeln!(out, "        hint: {hint}")?;
+    },
+  }
+  Ok(())
+}
+
+
+/// Check that credentials are valid and that the account, the market
+/// clock, and market data can be accessed.
+async fn doctor(
+  profile: &Profile,
+  connection: &Connection,
+  timeout: Option<StdDuration>,
+  out: &mut dyn Write,
+) -> Result<()> {
+  let mut failed = 0;
+  let mut report = |out: &mut dyn Write, check: &str, result: Result<()>, hint: &str| {
+    if result.is_err() {
+      failed += 1;
+    }
+    report_check(out, check, &result, hint)
+  };
+
+  let hint = "provide a key ID and secret via the APCA_API_KEY_ID and APCA_API_SECRET_KEY \
+              environment variables or the selected profile";
+  let client = match new_client(profile, connection) {
+    Ok(client) => {
+      let () = report(out, "credentials", Ok(()), hint)?;
+      client
+    },
+    Err(err) => {
+      let () = report(out, "credentials", Err(err), hint)?;
+      return Err(anyhow!("credentials are misconfigured")).classify(Failure::Config)
+    },
+  };
+
+  let result = timed(timeout, issue::<account::Get>(&client, &()))
+    .await
+    .with_context(|| "failed to retrieve account information");
+  let hint = "check that key ID and secret are valid and that they belong to the API \
+              (paper or live) selected via the API base URL";
+  let account = match result {
+    Ok(account) => {
+      let () = report(out, "authentication", Ok(()), hint)?;
+      Some(account)
+    },
+    Err(err) => {
+      let () = report(out, "authentication", Err(err), hint)?;
+      None
+    },
+  };
+
+  if let Some(account) = account {
+    let result = if account.status != account::Status::Active {
+      Err(anyhow!("account status is {:?}", account.status))
+    } else if account.account_blocked {
+      Err(anyhow!("account is blocked"))
+    } else {
+      Ok(())
+    };
+    let hint = "check the account's status in the Alpaca dashboard";
+    let () = report(out, "account status", result, hint)?;
+  }
+
+  let result = timed(timeout, issue::<clock::Get>(&client, &()))
+    .await
+    .map(|_clock| ())
+    .with_context(|| "failed to retrieve current market clock");
+  let hint = "check that the API base URL is correct";
+  let () = report(out, "market clock", result, hint)?;
+
+  let end = Utc::now();
+  let request = bars::ListReqInit::default().init(
+    "SPY",
+    end - Duration::weeks(1),
+    end - Duration::days(1),
+    bars::TimeFrame::OneDay,
+  );
+  let result = timed(timeout, issue::<bars::List>(&client, &request))
+    .await
+    .map(|_bars| ())
+    .with_context(|| "failed to retrieve historical aggregate bars for SPY");
+  let hint = "check that the data base URL is correct and that the account is entitled \
+              to the market data feed";
+  let () = report(out, "market data", result, hint)?;
+
+  if failed > 0 {
+    return Err(anyhow!("{failed} check(s) failed")).classify(Failure::Api)
+  }
+  Ok(())
+}
+
+
+/// Retrieve the symbols of the assets on the watchlist with the given
+/// name.
+async fn watchlist_symbols(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  name: &str,
+) -> Result<Vec<String>> {
+  let watchlists = timed(timeout, issue::<watchlists::Get>(client, &()))
+    .await
+    .classify(Failure::Api)
+    .with_context(|| "failed to list watchlists")?;
+  let id = watchlists
+    .into_iter()
+    .find(|watchlist| watchlist.name == name)
+    .map(|watchlist| watchlist.id)
+    .with_context(|| format!("watchlist {} not found", name))
+    .classify(Failure::Config)?;
+
+  let watchlist = timed(timeout, issue::<watchlist::Get>(client, &id))
+    .await
+    .classify(Failure::Api)
+    .with_context(|| format!("failed to retrieve watchlist {}", name))?;
+  let symbols = watchlist
+    .assets
+    .into_iter()
+    .map(|asset| asset.symbol)
+    .collect();
+  Ok(symbols)
+}
+
+
+/// Retrieve all account activities, in ascending order.
+async fn all_activities(
+  client: &Client,
+  timeout: Option<StdDuration>,
+) -> Result<Vec<account_activities::Activity>> {
+  let mut request = account_activities::ActivityReq {
+    direction: account_activities::Direction::Ascending,
+    ..Default::default()
+  };
+  let mut activities = Vec::new();
+
+  loop {
+    let fetched = fetch_activities(client, timeout, &request).await?;
+
+    if let Some(last) = fetched.last() {
+      request.page_token = Some(last.activity.id().to_string());
+      activities.extend(fetched.into_iter().map(|activity| activity.activity));
+    } else {
+      break Ok(activities)
+    }
+  }
+}
+
+
+/// Report the sales among the provided account activities, which have
+/// to be in ascending order, as CSV for IRS Form 8949. Holding periods
+/// are reported as well if a long-term threshold is provided.
+fn report_sales(
+  activities: Vec<account_activities::Activity>,
+  year: Option<i32>,
+  long_term_days: Option<i64>,
+  out: &mut dyn Write,
+) -> Result<()> {
+  let mut lots = Lots::default();
+
+  write!(
+    out,
+    "Description,Date Acquired,Date Sold,Proceeds,Cost Basis,Gain or Loss"
+  )?;
+  if long_term_days.is_some() {
+    write!(out, ",Holding Period,Term")?;
+  }
+  writeln!(out)?;
+
+  let mut activities = activities.into_iter().peekable();
+  while let Some(first) = activities.next() {
+    // Fees are associated with the trades of the same day, just as
+    // when exporting account activity.
+    let date = activity_date(&first, New_York);
+    let mut day = VecDeque::from([first]);
+    while let Some(activity) =
+      activities.next_if(|activity| activity_date(activity, New_York) == date)
+    {
+      let () = day.push_back(activity);
+    }
+
+    for activity in associate_fees_with_trades(merge_partial_fills(day)) {
+      let (trade, fees) = match activity {
+        Activity::Trade(trade, fees) => (trade, fees),
+        _ => continue,
+      };
+      // Tax forms report the trade date, which is the date at the
+      // exchange.
+      let date = trade_date(&trade, New_York);
+      // Fees are reported as negative amounts.
+      let fees = -sum(fees.iter().map(|fee| &fee.net_amount));
+      let total = &trade.price * contract_multiplier(&trade.symbol) * &trade.quantity;
+
+      match trade.side {
+        account_activities::Side::Buy => {
+          // Fees paid on a purchase are part of the cost basis.
+          let price = (total + fees) / &trade.quantity;
+          let () = lots.buy(&trade.symbol, date, trade.quantity, price);
+        },
+        account_activities::Side::Sell if lots.held(&trade.symbol) >= trade.quantity => {
+          // Fees paid on a sale reduce the proceeds.
+          let price = (total - fees) / &trade.quantity;
+          let sales = lots.sell(&trade.symbol, date, trade.quantity, price)?;
+          for sale in sales {
+            if year.map(|year| sale.sold.year() != year).unwrap_or(false) {
+              continue
+            }
+
+            write!(
+              out,
+              "{qty} {symbol},{acquired},{sold},{proceeds},{basis},{gain}",
+              qty = sale.quantity,
+              symbol = sale.symbol,
+              acquired = sale.acquired.format("%m/%d/%Y"),
+              sold = sale.sold.format("%m/%d/%Y"),
+              proceeds = sale.proceeds.round_with(2).display().min_precision(2),
+              basis = sale.basis.round_with(2).display().min_precision(2),
+              gain = sale.gain().round_with(2).display().min_precision(2),
+            )?;
+            if let Some(long_term_days) = long_term_days {
+              write!(
+                out,
+                ",{days},{term}",
+                days = sale.holding_period(),
+                term = if sale.is_long_term(long_term_days) {
+                  "long"
+                } else {
+                  "short"
+                },
+              )?;
+            }
+            writeln!(out)?;
+          }
+        },
+        _ => warn!(
+          "skipping trade {} of {} {} on {date}: short sales are not supported",
+          trade.id, trade.quantity, trade.symbol
+        ),
+      }
+    }
+  }
+  Ok(())
+}
+
+
+/// Print all sales, optionally restricted to the given year, in a CSV
+/// format suitable for IRS Form 8949.
+async fn form_8949(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  year: Option<i32>,
+  long_term_days: Option<i64>,
+  out: &mut dyn Write,
+) -> Result<()> {
+  let activities = all_activities(client, timeout).await?;
+  report_sales(activities, year, long_term_days, out)
+}
+
+
+/// Retrieve all symbols the account ever held or traded.
+async fn all_symbols(client: &Client, timeout: Option<StdDuration>) -> Result<BTreeSet<String>> {
+  let (activities, positions) = join(
+    all_activities(client, timeout),
+    timed(timeout, issue::<positions::List>(client, &())),
+  )
+  .await;
+  let positions = positions
+    .classify(Failure::Api)
+    .with_context(|| "failed to retrieve open positions")?;
+
+  let symbols = activities?
+    .into_iter()
+    .filter_map(|activity| match activity {
+      account_activities::Activity::Trade(trade) => Some(trade.symbol),
+      account_activities::Activity::NonTrade(non_trade) => non_trade.symbol,
+    })
+    .chain(positions.into_iter().map(|position| position.symbol))
+    .collect();
+  Ok(symbols)
+}
+
+
+/// Create or complete the registry at the given path with the names of
+/// all symbols the account ever held or traded.
+async fn registry_init(client: &Client, timeout: Option<StdDuration>, path: &Path) -> Result<()> {
+  let mut registry = match File::open(path) {
+    Ok(file) => json_from_reader::<_, JsonMap<String, JsonValue>>(file)
+      .with_context(|| format!("failed to read registry {}", path.display()))?,
+    Err(err) if err.kind() == ErrorKind::NotFound => JsonMap::new(),
+    Err(err) => {
+      return Err(err).with_context(|| format!("failed to open registry file {}", path.display()))
+    },
+  };
+
+  let symbols = all_symbols(client, timeout)
+    .await?
+    .into_iter()
+    // Option contracts get decoded on the fly and so don't need an
+    // entry.
+    .filter(|symbol| !registry.contains_key(symbol) && OptionContract::parse(symbol).is_none())
+    .collect::<Vec<_>>();
+
+  let assets = iter(symbols)
+    .map(|symbol| async move {
+      let result = timed(timeout, issue::<GetAssetName>(client, &symbol))
+        .await
+        .classify(Failure::Api)
+        .with_context(|| format!("failed to retrieve asset information for {}", symbol));
+      (symbol, result)
+    })
+    .buffer_unordered(32)
+    .collect::<Vec<_>>()
+    .await;
+
+  for (symbol, result) in assets {
+    match result {
+      Ok(asset) => {
+        let _prev = registry.insert(symbol, JsonValue::String(asset.name));
+      },
+      // Assets may have been delisted or renamed since, in which case
+      // Alpaca no longer knows about them. Users will have to fill in
+      // the name manually.
+      Err(err) => warn!("{err:#}"),
+    }
+  }
+
+  // Write the registry atomically, so that a failure can never destroy
+  // an existing one.
+  let mut file = AtomicFile::create(path, false)?;
+  let () = to_json_writer_pretty(&mut file, &registry)
+    .with_context(|| format!("failed to write registry {}", path.display()))?;
+  let () =
+    writeln!(file).with_context(|| format!("failed to write registry {}", path.display()))?;
+  file.commit(false)
+}
+
+
+/// Report the symbols missing from and the entries unused in the
+/// provided registry, given all symbols the account ever held or
+/// traded, returning the number of missing symbols.
+fn report_registry(
+  registry: &Registry,
+  symbols: &BTreeSet<String>,
+  out: &mut dyn Write,
+) -> Result<usize> {
+  let missing = symbols
+    .iter()
+    .filter(|symbol| registry.get(symbol).is_none() && OptionContract::parse(symbol).is_none())
+    .collect::<Vec<_>>();
+  let unused = registry
+    .symbols()
+    .filter(|symbol| !symbols.contains(*symbol))
+    .collect::<BTreeSet<_>>();
+
+  for symbol in &missing {
+    writeln!(out, "missing: {symbol}")?;
+  }
+  for symbol in &unused {
+    writeln!(out, "unused: {symbol}")?;
+  }
+  Ok(missing.len())
+}
+
+
+/// Check the registry at the given path against the symbols the
+/// account ever held or traded.
+async fn registry_check(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  path: &Path,
+  out: &mut dyn Write,
+) -> Result<()> {
+  let registry = Registry::load(path)?;
+  let symbols = all_symbols(client, timeout).await?;
+
+  let missing = report_registry(&registry, &symbols, out)?;
+  ensure!(
+    missing == 0,
+    "{missing} symbols are missing from registry {}",
+    path.display()
+  );
+  Ok(())
+}
+
+
+/// Create an Alpaca client, using the credentials from the given
+/// profile or, if it does not contain any, the environment.
+fn new_client(profile: &Profile, connection: &Connection) -> Result<Client> {
+  let mut api_info = match (profile.key_id()?, profile.secret()?) {
+    (Some(key_id), Some(secret)) => {
+      let api_base_url = profile
+        .api_base_url
+        .as_deref()
+        .unwrap_or(DEFAULT_API_BASE_URL);
+      ApiInfo::from_parts(api_base_url, key_id, secret)
+        .with_context(|| "failed to create Alpaca API information from profile")?
+    },
+    (None, None) => ApiInfo::from_env()
+      .with_context(|| "failed to retrieve Alpaca environment information")?,
+    _ => bail!("profile needs to provide both a key ID and a secret"),
+  };
+
+  if let Some(url) = &connection.api_base_url {
+    api_info.api_base_url = url
+      .parse()
+      .with_context(|| format!("invalid API base URL: {}", url))?;
+  }
+  if let Some(url) = &connection.data_base_url {
+    api_info.data_base_url = url
+      .parse()
+      .with_context(|| format!("invalid data base URL: {}", url))?;
+  }
+
+  let proxy = Proxy::resolve(connection.proxy.as_deref())?;
+  let ca_certs = connection
+    .ca_cert
+    .as_ref()
+    .map(|path| {
+      read(path).with_context(|| format!("failed to read CA certificates {}", path.display()))
+    })
+    .transpose()?;
+  let client = Client::new(api_info, proxy, ca_certs.as_deref())?;
+  Ok(client)
+}
+
+
+/// Resolve an account name, preferring the one provided on the command
+/// line over the one from the profile over the default.
+fn account<'s>(arg: &'s Option<String>, profile: &'s Option<String>, default: &'s str) -> &'s str {
+  arg.as_deref().or(profile.as_deref()).unwrap_or(default)
+}
 
-  let currency = client
-    .issue::<account::Get>(&())
-    .await
-    .with_context(|| "failed to retrieve account information")?
-    .currency;
 
-  loop {
-    let (req, activities, remainder) = activites_for_a_day(client, unprocessed, request).await?;
-    if activities.is_empty() {
-      assert!(remainder.is_empty());
-      break
-    }
+/// Append the current prices of all held assets to a price database
+/// and write balance assertions for all positions and the cash balance,
+/// as part of the `sync` command.
+async fn sync_positions(
+  client: &Client,
+  timeout: Option<StdDuration>,
+  update_prices: Option<&Path>,
+  reconcile: Option<&Path>,
+  backup: bool,
+  ctx: &Context,
+) -> Result<()> {
+  let (account, positions) = join(
+    timed(timeout, issue::<account::Get>(client, &())),
+    timed(timeout, issue::<positions::List>(client, &())),
+  )
+  .await;
+  let account = account
+    .classify(Failure::Api)
+    .context("failed to retrieve account information")?;
+  let mut positions = positions
+    .classify(Failure::Api)
+    .context("failed to retrieve open positions")?;
+  positions.sort_by(|position1, position2| position1.symbol.cmp(&position2.symbol));
 
-    request = req;
-    unprocessed = remainder;
+  let today = ctx.today;
+  if let Some(path) = update_prices {
+    let symbols = positions
+      .iter()
+      // Alpaca does not provide historical bars for option contracts,
+      // so we can't report prices for them.
+      .filter(|position| OptionContract::parse(&position.symbol).is_none())
+      .map(|position| position.symbol.clone())
+      .collect();
+    let mut file = AtomicFile::create(path, true)?;
+    let options = PriceOptions::default();
+    let () = prices_get(client, timeout, symbols, today, today, &options, &mut file)
+      .await
+      .with_context(|| format!("failed to update price database {}", path.display()))?;
+    let () = file.commit(backup)?;
+  }
 
-    let activities = merge_partial_fills(activities);
-    let activities = if force_separate_fees {
-      activities
-        .into_iter()
-        .map(Activity::from)
-        .collect::<VecDeque<_>>()
-    } else {
-      associate_fees_with_trades(activities)?
+  if let Some(path) = reconcile {
+    let mut transaction = Transaction::new(today, None, "Reconciliation");
+    for position in &positions {
+      let amount = Amount::Assertion {
+        commodity: position.symbol.clone(),
+        balance: position.quantity.clone(),
+      };
+      transaction.postings.push(Posting::new(
+        ctx.investment_account(&position.symbol, None),
+        Some(amount),
+      ));
+    }
+    let amount = Amount::Assertion {
+      commodity: ctx.currency.clone(),
+      balance: account.cash,
     };
+    transaction
+      .postings
+      .push(Posting::new(&ctx.accounts.brokerage, Some(amount)));
 
-    for activity in activities {
-      match &activity {
-        Activity::Trade(trade, fees) => print_trade(
-          trade,
-          fees,
-          investment_account,
-          brokerage_account,
-          brokerage_fee_account,
-          sec_fee_account,
-          finra_taf_account,
-          registry,
-          &currency,
-        )?,
-        Activity::NonTrade(non_trade) => print_non_trade(
-          non_trade,
-          investment_account,
-          brokerage_account,
-          brokerage_fee_account,
-          dividend_account,
-          sec_fee_account,
-          finra_taf_account,
-          registry,
-          &currency,
-        )?,
-      }
-    }
+    let mut file = AtomicFile::create(path, false)?;
+    writeln!(file, "{transaction}")
+      .with_context(|| format!("failed to write {}", path.display()))?;
+    let () = file.commit(backup)?;
   }
+
+  ctx.summary.borrow_mut().positions = Some(positions.len());
   Ok(())
 }
 
 
-/// Retrieve and print the price of the asset with the given symbol.
-async fn price_get<F>(
-  client: &Client,
-  symbol: String,
-  date: NaiveDate,
-  clock: Shared<F>,
-) -> Result<()>
-where
-  F: Future<Output = Result<clock::Clock, Arc<RequestError<clock::GetError>>>>,
-{
-  let today = Local::now().date_naive();
-  ensure!(date <= today, "the provided date needs to be in the past");
+/// Export account activity and, as part of the `sync` command, update
+/// the prices of and reconcile all currently held positions.
+#[allow(clippy::too_many_arguments)]
+async fn activity_export(
+  activity: ActivityArgs,
+  update_prices: Option<PathBuf>,
+  reconcile: Option<PathBuf>,
+  profile: &Profile,
+  connection: &Connection,
+  timeout: Option<StdDuration>,
+  pager: Option<&str>,
+  no_pager: bool,
+) -> Result<()> {
+  let registry = Registry::load_all(&activity.registry).classify(Failure::Config)?;
+  let payees = if let Some(payees) = activity.payee_aliases {
+    let file = input::open(&payees)
+      .with_context(|| format!("failed to open payee alias file {}", payees.display()))
+      .classify(Failure::Config)?;
+    json_from_reader::<_, HashMap<String, String>>(file)
+      .with_context(|| format!("failed to read payee aliases {}", payees.display()))
+      .classify(Failure::Config)?
+  } else {
+    HashMap::new()
+  };
+  let translations = if let Some(path) = activity.translations {
+    let file = input::open(&path)
+      .with_context(|| format!("failed to open translation file {}", path.display()))
+      .classify(Failure::Config)?;
+    json_from_reader::<_, HashMap<String, String>>(file)
+      .with_context(|| format!("failed to read translations {}", path.display()))
+      .classify(Failure::Config)?
+  } else {
+    HashMap::new()
+  };
+  let virtual_postings = if let Some(path) = activity.virtual_postings {
+    let file = input::open(&path)
+      .with_context(|| format!("failed to open virtual postings file {}", path.display()))
+      .classify(Failure::Config)?;
+    json_from_reader::<_, VirtualPostings>(file)
+      .with_context(|| format!("failed to read virtual postings {}", path.display()))
+      .classify(Failure::Config)?
+  } else {
+    VirtualPostings::default()
+  };
+  let transfer_rules = if let Some(path) = activity.transfer_accounts {
+    let file = input::open(&path)
+      .with_context(|| format!("failed to open transfer account file {}", path.display()))
+      .classify(Failure::Config)?;
+    json_from_reader::<_, Vec<TransferRule>>(file)
+      .with_context(|| format!("failed to read transfer accounts {}", path.display()))
+      .classify(Failure::Config)?
+  } else {
+    Vec::new()
+  };
 
-  let start = date - Duration::weeks(2);
-  let start = New_York
-    .with_ymd_and_hms(start.year(), start.month(), start.day(), 0, 0, 0)
-    .unwrap()
-    .with_timezone(&Utc);
-  let end = min(date + Duration::weeks(1), today);
-  let end = New_York
-    .with_ymd_and_hms(end.year(), end.month(), end.day(), 0, 0, 0)
-    .unwrap()
-    .with_timezone(&Utc);
+  let client = if activity.render_fixture.is_none() {
+    Some(Arc::new(
+      new_client(profile, connection).classify(Failure::Config)?,
+    ))
+  } else {
+    None
+  };
+  let currency = if let Some(client) = &client {
+    // The account currency is needed for formatting every single
+    // transaction, so retrieve it once upfront.
+    timed(timeout, issue::<account::Get>(client, &()))
+      .await
+      .classify(Failure::Api)
+      .with_context(|| "failed to retrieve account information")?
+      .currency
+  } else {
+    FIXTURE_CURRENCY.to_string()
+  };
 
-  let request = bars::ListReqInit {
-    adjustment: Some(bars::Adjustment::All),
-    ..Default::default()
-  }
-  .init(symbol.clone(), start, end, bars::TimeFrame::OneDay);
+  let accounts = Accounts {
+    investment: account(
+      &activity.investment_account,
+      &profile.investment_account,
+      DEFAULT_INVESTMENT_ACCOUNT,
+    )
+    .to_string(),
+    brokerage: account(
+      &activity.brokerage_account,
+      &profile.brokerage_account,
+      DEFAULT_BROKERAGE_ACCOUNT,
+    )
+    .to_string(),
+    brokerage_fee: account(
+      &activity.brokerage_fee_account,
+      &profile.brokerage_fee_account,
+      DEFAULT_BROKERAGE_FEE_ACCOUNT,
+    )
+    .to_string(),
+    dividend: account(
+      &activity.dividend_account,
+      &profile.dividend_account,
+      DEFAULT_DIVIDEND_ACCOUNT,
+    )
+    .to_string(),
+    non_qualified_dividend: activity.non_qualified_dividend_account,
+    sec_fee: account(
+      &activity.sec_fee_account,
+      &profile.sec_fee_account,
+      DEFAULT_SEC_FEE_ACCOUNT,
+    )
+    .to_string(),
+    finra_taf: account(
+      &activity.finra_taf_account,
+      &profile.finra_taf_account,
+      DEFAULT_FINRA_TAF_ACCOUNT,
+    )
+    .to_string(),
+    reorg_fee: activity.reorg_fee_account,
+    transfer_fee: activity.transfer_fee_account,
+    option_premium: activity.option_premium_account,
+    reorg: activity.reorg_account,
+    capital_gains: activity.capital_gains_account,
+    sweep: activity.sweep_account,
+    crypto_wallet: activity.crypto_wallet_account,
+    network_fee: activity.network_fee_account,
+    crypto_reward: activity.crypto_reward_account,
+    interest: INTEREST_ACCOUNT.to_string(),
+    cash_program_interest: activity.cash_program_interest_account,
+  };
+  let ctx = Context {
+    accounts,
+    registry,
+    payees,
+    interest_receivable: activity.interest_accrual_account,
+    accruals: RefCell::default(),
+    virtual_postings,
+    transfer_rules,
+    transfer_tags: activity.transfer_tags,
+    translations,
+    currency,
+    strategies: activity.strategies,
+    transaction_codes: activity
+      .transaction_codes
+      .into_iter()
+      .map(|code| (code.kind.to_uppercase(), code.code))
+      .collect(),
+    strategy_accounts: activity.strategy_accounts,
+    per_symbol_accounts: activity.per_symbol_accounts,
+    per_symbol_adr_fee_accounts: activity.per_symbol_adr_fee_accounts,
+    order_details: activity.order_details,
+    fill_times: activity.fill_times,
+    roc_reduces_basis: activity.roc_reduces_basis,
+    comments: activity.comments,
+    security_ids: activity.security_ids,
+    fee_totals: activity.fee_totals,
+    dividend_date: activity.dividend_date,
+    timezone: activity.timezone,
+    today: Utc::now().with_timezone(&activity.timezone).date_naive(),
+    explain: activity.explain,
+    preview: activity.preview,
+    option_premium: activity.option_premium,
+    premiums: RefCell::default(),
+    ids: activity.ids.into_iter().collect(),
+    filter_description: activity.filter_description,
+    exclude_description: activity.exclude_description,
+    filter: activity.filter.map(Filter::new),
+    declarations: RefCell::new(activity.pedantic.then(Declarations::default)),
+    references: RefCell::default(),
+    emit_unknown: activity.emit_unknown,
+    strict: activity.strict,
+    ignored: RefCell::default(),
+    cash_balance: RefCell::new(activity.opening_balance.clone()),
+    summary: RefCell::default(),
+    warnings: RefCell::default(),
+  };
 
-  let bars = client.issue::<bars::List>(&request);
+  if let Some(path) = &activity.accounts_file {
+    let () = validate_accounts(path, &ctx).classify(Failure::Config)?;
+  }
 
-  let (response1, response2) = join(bars, clock).await;
-  let mut bars = response1
-    .with_context(|| {
-      format!(
-        "failed to retrieve historical aggregate bars for {}",
-        symbol
-      )
-    })?
-    .bars;
-  let clock = response2.context("failed to retrieve current market clock")?;
+  let output = match (activity.split_by, activity.output_dir.clone()) {
+    // When resuming from a previous run we have to append to
+    // existing files.
+    (Some(split_by), Some(dir)) => {
+      Output::split(dir, split_by, activity.state.is_some(), activity.backup)
+    },
+    _ => Output::paged(pager, no_pager)?,
+  };
 
-  let key_fn = |bar: &bars::Bar| bar.time;
-  // Alpaca does not document a specific order in which the bars are
-  // reported, so sort them to be sure they are ascending.
-  bars.sort_unstable_by_key(key_fn);
+  let begin = if let Some(begin) = activity.begin {
+    let last_run = if begin == Begin::LastRun {
+      let path = activity
+        .state
+        .as_deref()
+        .context("beginning at the last run requires a state file")
+        .classify(Failure::Config)?;
+      State::load(path)?.map(|state| state.date)
+    } else {
+      None
+    };
+    // Relative dates are interpreted in the configured time zone, the
+    // same one the dates of activities are derived in.
+    begin.resolve(ctx.today, last_run)
+  } else {
+    None
+  };
 
-  let mut utc_date = New_York
-    .with_ymd_and_hms(date.year(), date.month(), date.day(), 0, 0, 0)
-    .unwrap()
-    .with_timezone(&Utc);
+  let options = ProcessOptions {
+    begin,
+    category: activity.category,
+    force_separate_fees: activity.force_separate_fees,
+    group_fills_per_order: activity.group_fills,
+    summary: activity.summarize_trades,
+    collect_failed: activity.error_report.is_some(),
+  };
 
-  // If the market is currently open (or opens later today) then we are
-  // interested in yesterday's date. The reason being that Alpaca
-  // would report bars for the ongoing day, and those will change until
-  // we reached the end of the trading day.
-  if clock.open || clock.next_open.date_naive() == utc_date.date_naive() {
-    utc_date = utc_date - Duration::days(1);
-  }
+  let export = async {
+    match (&client, &activity.render_fixture) {
+      (Some(client), _) => {
+        activities_list(
+          client,
+          timeout,
+          &options,
+          activity.order,
+          &activity.exclude_status,
+          activity.emit_declarations.as_deref(),
+          activity.emit_periodic.as_deref(),
+          activity.interest_accruals.as_deref(),
+          activity.error_report.as_deref(),
+          activity.state.as_deref(),
+          activity.cache.as_deref(),
+          activity.refresh_after,
+          activity.limit,
+          activity.chunk_days,
+          activity.dump_http.as_deref(),
+          activity.prices.as_deref(),
+          activity.backup,
+          output,
+          &ctx,
+        )
+        .await
+      },
+      (None, Some(path)) => {
+        render_fixture(
+          path,
+          &options,
+          activity.interest_accruals.as_deref(),
+          output,
+          &ctx,
+        )
+        .await
+      },
+      (None, None) => unreachable!(),
+    }
+  };
+  let sync = async {
+    match &client {
+      Some(client) if update_prices.is_some() || reconcile.is_some() => {
+        sync_positions(
+          client,
+          timeout,
+          update_prices.as_deref(),
+          reconcile.as_deref(),
+          activity.backup,
+          &ctx,
+        )
+        .await
+      },
+      _ => Ok(()),
+    }
+  };
+  // Prices and positions are independent of the activities, so
+  // retrieve them while the export is in progress.
+  let (result, synced) = join(export, sync).await;
+  let result = result.and(synced);
 
-  let bar = match bars.binary_search_by_key(&utc_date, key_fn) {
-    Ok(index) => bars.get(index).unwrap(),
-    Err(index) => {
-      // The index reported here is where we would insert. But given
-      // that we do not insert we have to subtract one in order to get
-      // the previous bar.
-      if let Some(bar) = bars.get(index.saturating_sub(1)) {
-        bar
-      } else {
-        // The index does not exist, meaning that we are past the last
-        // bar that we received. Just pick the last one then.
-        bars
-          .last()
-          .ok_or_else(|| anyhow!("no historical bars found for {}", symbol))?
-      }
+  let result = match (result, &activity.output_dir) {
+    (Ok(()), Some(dir)) if activity.git_commit => {
+      let paths = [
+        Some(dir.as_path()),
+        activity.prices.as_deref(),
+        update_prices.as_deref(),
+        reconcile.as_deref(),
+      ];
+      let paths = paths.into_iter().flatten().collect::<Vec<_>>();
+      let message = ctx.summary.borrow().format_commit(&ctx.currency);
+      git::commit(dir, &paths, &message)
     },
+    (result, _) => result,
   };
 
-  println!(
-    "P {date} 23:59:59 {sym} USD {price}",
-    date = New_York
-      .from_utc_datetime(&bar.time.naive_utc())
-      .date_naive(),
-    sym = symbol,
-    price = bar.close.display().min_precision(2),
-  );
-  Ok(())
-}
+  if let Some(url) = &activity.notify {
+    let text = match &result {
+      Ok(()) => ctx.summary.borrow().format(&ctx.currency),
+      Err(err) => format!("apcaledge: account activity export failed: {err:#}"),
+    };
+    if let Err(err) = notify::post(url, &text).await {
+      warn!("{err:#}");
+    }
+  }
 
+  if let Some(path) = &activity.warnings {
+    if let Err(err) = warnings::write(path, &ctx.warnings.borrow()) {
+      warn!("{err:#}");
+    }
+  }
 
-/// Retrieve and print the price the given list of assets.
-async fn prices_get(client: &Client, symbols: Vec<String>, date: NaiveDate) -> Result<()> {
-  // We need the current market clock to decide which price exactly to
-  // report. But we only want to make one market clock request. So we
-  // have to `Arc` up the error here in order for us to be able to share
-  // the future.
-  let clock = client.issue::<clock::Get>(&()).map_err(Arc::new).shared();
-
-  #[allow(clippy::manual_try_fold)]
-  let () = iter(symbols)
-    .map(Ok)
-    .map_ok(|symbol| price_get(client, symbol, date, clock.clone()))
-    .try_buffer_unordered(32)
-    // We use `fold` here to make sure that we process all items, such
-    // that all successfully retrieved prices are printed.
-    .fold(Ok(()), |acc, result| ready(acc.and(result)))
-    .await?;
-  Ok(())
+  if result.is_ok() && (update_prices.is_some() || reconcile.is_some()) {
+    eprintln!("{}", ctx.summary.borrow().format(&ctx.currency));
+  }
+  result
 }
 
 
-async fn run() -> Result<()> {
-  let args = Args::from_args();
+async fn run(args: Args) -> Result<()> {
   let level = match args.verbosity {
     0 => LevelFilter::WARN,
     1 => LevelFilter::INFO,
@@ -822,47 +4418,177 @@ async fn run() -> Result<()> {
 
   set_global_subscriber(subscriber).with_context(|| "failed to set tracing subscriber")?;
 
-  let api_info =
-    ApiInfo::from_env().with_context(|| "failed to retrieve Alpaca environment information")?;
-  let mut client = Client::new(api_info);
+  let config = args
+    .config
+    .as_deref()
+    .map(Config::load)
+    .transpose()
+    .classify(Failure::Config)?
+    .unwrap_or_default();
+  let default_profile = Profile::default();
+  let profile = args
+    .profile
+    .as_deref()
+    .map(|name| config.profile(name))
+    .transpose()
+    .classify(Failure::Config)?
+    .unwrap_or(&default_profile);
+
+  let timeout = args.timeout.map(StdDuration::from_secs);
 
   match args.command {
     Command::Activity(activity) => {
-      let registry = activity.registry;
-      let file = File::open(&registry)
-        .with_context(|| format!("failed to open registry file {}", registry.display()))?;
-      let registry = json_from_reader::<_, HashMap<String, String>>(file)
-        .with_context(|| format!("failed to read registry {}", registry.display()))?;
-
-      activities_list(
-        &mut client,
-        activity.begin,
-        activity.force_separate_fees,
-        &activity.investment_account,
-        &activity.brokerage_account,
-        &activity.brokerage_fee_account,
-        &activity.dividend_account,
-        &activity.sec_fee_account,
-        &activity.finra_taf_account,
-        &registry,
+      activity_export(
+        activity,
+        None,
+        None,
+        profile,
+        &args.connection,
+        timeout,
+        args.pager.as_deref(),
+        args.no_pager,
+      )
+      .await
+    },
+    Command::Sync(sync) => {
+      activity_export(
+        sync.activity,
+        sync.update_prices,
+        sync.reconcile,
+        profile,
+        &args.connection,
+        timeout,
+        args.pager.as_deref(),
+        args.no_pager,
+      )
+      .await
+    },
+    Command::Doctor => doctor(profile, &args.connection, timeout, &mut stdout()).await,
+    Command::Registry(RegistryCommand::Init(init)) => {
+      let client = new_client(profile, &args.connection).classify(Failure::Config)?;
+      registry_init(&client, timeout, &init.registry).await
+    },
+    Command::Registry(RegistryCommand::Check(check)) => {
+      let client = new_client(profile, &args.connection).classify(Failure::Config)?;
+      registry_check(&client, timeout, &check.registry, &mut stdout()).await
+    },
+    Command::Form8949(form) => {
+      let client = new_client(profile, &args.connection).classify(Failure::Config)?;
+      form_8949(
+        &client,
+        timeout,
+        form.year,
+        form.holding_periods.then_some(form.long_term_days),
+        &mut stdout(),
       )
       .await
     },
-    Command::Prices(prices) => prices_get(&client, prices.symbols, prices.date.0).await,
+    Command::Calendar(calendar) => {
+      let client = new_client(profile, &args.connection).classify(Failure::Config)?;
+      let begin = calendar.begin.0;
+      let end = calendar
+        .end
+        .map(|end| end.0)
+        .unwrap_or_else(|| begin + Duration::days(30));
+      calendar_list(&client, timeout, begin, end, calendar.json, &mut stdout()).await
+    },
+    Command::History(history_args) => {
+      let client = new_client(profile, &args.connection).classify(Failure::Config)?;
+      let end = history_args.end.0;
+      let begin = history_args
+        .begin
+        .map(|begin| begin.0)
+        .unwrap_or_else(|| end - Duration::days(30));
+      let ledger = history_args
+        .ledger
+        .then_some(history_args.commodity.as_str());
+      history(&client, timeout, begin, end, ledger, &mut stdout()).await
+    },
+    Command::OpeningBalances(balances) => {
+      let client = new_client(profile, &args.connection).classify(Failure::Config)?;
+      let accounts = OpeningAccounts {
+        investment: account(
+          &balances.investment_account,
+          &profile.investment_account,
+          DEFAULT_INVESTMENT_ACCOUNT,
+        ),
+        per_symbol: balances.per_symbol_accounts,
+        brokerage: account(
+          &balances.brokerage_account,
+          &profile.brokerage_account,
+          DEFAULT_BROKERAGE_ACCOUNT,
+        ),
+        equity: &balances.equity_account,
+      };
+      opening_balances(&client, timeout, balances.date.0, &accounts, &mut stdout()).await
+    },
+    Command::Prices(prices) if prices.from_csv.is_some() => {
+      // We know the path is present.
+      let path = prices.from_csv.unwrap();
+      let file = input::open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))
+        .classify(Failure::Config)?;
+      prices_from_csv(BufReader::new(file), &mut stdout())
+        .with_context(|| format!("failed to convert prices from {}", path.display()))
+    },
+    Command::Prices(prices) => {
+      let client = new_client(profile, &args.connection).classify(Failure::Config)?;
+      let previous = if let Some(path) = &prices.price_db {
+        let file = input::open(path)
+          .with_context(|| format!("failed to open price database {}", path.display()))
+          .classify(Failure::Config)?;
+        read_price_db(BufReader::new(file))
+          .with_context(|| format!("failed to read price database {}", path.display()))?
+      } else {
+        HashMap::new()
+      };
+      let mut symbols = prices.symbols;
+      if let Some(name) = &prices.watchlist {
+        let () = symbols.extend(watchlist_symbols(&client, timeout, name).await?);
+      }
+
+      let mut output = Vec::new();
+      if let Some(commodity) = &prices.equity {
+        let () = equity_get(&client, timeout, commodity, prices.date.0, &mut output).await?;
+      }
+      let result = prices_get(
+        &client,
+        timeout,
+        symbols,
+        prices.date.0,
+        Local::now().date_naive(),
+        &prices.options,
+        &mut output,
+      )
+      .await;
+
+      let output = String::from_utf8_lossy(&output);
+      if let Some(max_change) = &prices.max_price_change {
+        let () = check_prices(&output, &previous, max_change);
+      }
+      let () = stdout().write_all(output.as_bytes())?;
+      result
+    },
   }
 }
 
 fn main() {
-  let rt = Builder::new_current_thread().enable_io().build().unwrap();
-  let exit_code = rt
-    .block_on(run())
-    .map(|_| 0)
-    .map_err(|e| {
-      eprint!("{}", e);
-      e.chain().skip(1).for_each(|cause| eprint!(": {}", cause));
-      eprintln!();
-    })
-    .unwrap_or(1);
+  let args = Args::from_args();
+  let mut builder = match args.jobs {
+    Some(jobs) if jobs > 1 => {
+      let mut builder = Builder::new_multi_thread();
+      builder.worker_threads(jobs);
+      builder
+    },
+    _ => Builder::new_current_thread(),
+  };
+  let rt = builder.enable_io().enable_time().build().unwrap();
+  let exit_code = rt.block_on(run(args)).map(|_| 0).unwrap_or_else(|e| {
+    eprint!("{}", e);
+    e.chain().skip(1).for_each(|cause| eprint!(": {}", cause));
+    eprintln!();
+    exit_code(&e)
+  });
   // We exit the process the hard way next, so make sure to flush
   // buffered content.
   let _ = stdout().flush();
@@ -874,9 +4600,64 @@ fn main() {
 mod tests {
   use super::*;
 
+  use std::str::FromStr as _;
+
   use serde_json::from_str as from_json;
 
 
+  /// Check that interest is accrued at the end of the period it was
+  /// paid for.
+  #[test]
+  fn interest_accrual_dates() {
+    let date = |year, month, day| NaiveDate::from_ymd_opt(year, month, day).unwrap();
+
+    let paid = date(2024, 1, 3);
+    assert_eq!(
+      interest_accrual_date(Some("Interest for December"), paid),
+      date(2023, 12, 31)
+    );
+    assert_eq!(
+      interest_accrual_date(Some("Interest for November"), paid),
+      date(2023, 11, 30)
+    );
+    assert_eq!(interest_accrual_date(None, paid), date(2023, 12, 31));
+    // Interest paid out for the current month is not accrued ahead of
+    // the payment.
+    assert_eq!(
+      interest_accrual_date(Some("Interest for January"), paid),
+      paid
+    );
+    assert_eq!(
+      interest_accrual_date(Some("Interest for February"), date(2024, 3, 1)),
+      date(2024, 2, 29)
+    );
+  }
+
+
+  /// Check that we determine the start of a day in a time zone
+  /// correctly.
+  #[test]
+  fn day_starts() {
+    let date = NaiveDate::from_ymd_opt(2024, 1, 2).unwrap();
+    let start = day_start(date, Tz::Asia__Tokyo);
+    assert_eq!(start.to_rfc3339(), "2024-01-01T15:00:00+00:00");
+
+    let start = day_start(date, Tz::UTC);
+    assert_eq!(start.to_rfc3339(), "2024-01-02T00:00:00+00:00");
+
+    // Midnight did not exist in Sao Paulo when daylight saving time
+    // started in 2018.
+    let date = NaiveDate::from_ymd_opt(2018, 11, 4).unwrap();
+    let start = day_start(date, Tz::America__Sao_Paulo);
+    assert!(
+      start
+        <= Tz::America__Sao_Paulo
+          .with_ymd_and_hms(2018, 11, 4, 1, 0, 0)
+          .unwrap()
+    );
+  }
+
+
   /// Test merging of partial fills.
   #[test]
   fn merge_activities_simple() {
@@ -939,7 +4720,7 @@ mod tests {
 ]"#;
     let activities = from_json::<VecDeque<account_activities::Activity>>(activities).unwrap();
     let activities = merge_partial_fills(activities);
-    let activities = associate_fees_with_trades(activities).unwrap();
+    let activities = associate_fees_with_trades(activities);
 
     assert_eq!(activities.len(), 1);
     match &activities[0] {
@@ -957,4 +4738,326 @@ mod tests {
       _ => panic!("encountered unexpected account activity"),
     }
   }
+
+
+  /// Check that fees and partial fills are matched up with trades
+  /// reported after and before them, respectively.
+  #[test]
+  fn associate_fees_and_trades_out_of_order() {
+    let activities = r#"[
+{"id":"11111111111111111::22222222-3333-4444-5555-666666666666","activity_type":"FEE","date":"2021-06-15","net_amount":"-0.01","description":"TAF fee for proceed of 56 shares (2 trades) on 2021-06-15 by 999999999","status":"executed"},
+{"id":"44444444444444444::55555555-6666-7777-8888-999999999999","activity_type":"FILL","transaction_time":"2021-06-15T16:19:18.136Z","type":"fill","price":"9.33","qty":"54","side":"sell","symbol":"XYZ","leaves_qty":"0","order_id":"12345678-9012-3456-7890-123456789012","cum_qty":"56","order_status":"filled"},
+{"id":"11111111111111111::11111111-1111-1111-1111-111111111111","activity_type":"FILL","transaction_time":"2021-06-15T16:19:18.136Z","type":"fill","price":"9.33","qty":"56","side":"buy","symbol":"BCD","leaves_qty":"0","order_id":"00000000-0000-0000-0000-000000000000","cum_qty":"56","order_status":"filled"},
+{"id":"777777777777777777::88888888-9999-1111-2222-333333333333","activity_type":"FILL","transaction_time":"2021-06-15T16:18:56.299Z","type":"partial_fill","price":"9.33","qty":"2","side":"sell","symbol":"XYZ","leaves_qty":"54","order_id":"12345678-9012-3456-7890-123456789012","cum_qty":"2","order_status":"partially_filled"}
+]"#;
+    let activities = from_json::<VecDeque<account_activities::Activity>>(activities).unwrap();
+    let activities = merge_partial_fills(activities);
+    assert_eq!(activities.len(), 3);
+
+    let activities = associate_fees_with_trades(activities);
+    assert_eq!(activities.len(), 2);
+    match &activities[0] {
+      Activity::Trade(trade, fees) => {
+        assert_eq!(trade.symbol, "XYZ");
+        assert_eq!(trade.quantity, Num::from(56));
+        assert_eq!(fees.len(), 1);
+      },
+      _ => panic!("encountered unexpected account activity"),
+    }
+    match &activities[1] {
+      Activity::Trade(trade, fees) => {
+        assert_eq!(trade.symbol, "BCD");
+        assert!(fees.is_empty());
+      },
+      _ => panic!("encountered unexpected account activity"),
+    }
+  }
+
+
+  /// Check that reorganization and transfer fees are classified
+  /// correctly.
+  #[test]
+  fn classify_reorg_and_transfer_fees() {
+    let fee = r#"{"id":"11111111111111111::22222222-3333-4444-5555-666666666666","activity_type":"FEE","date":"2023-03-01","net_amount":"-5.00","description":"Mandatory Reorg Fee XYZ","status":"executed"}"#;
+    let fee = from_json::<account_activities::NonTradeActivity>(fee).unwrap();
+    let accounts = Accounts {
+      investment: "Investment".to_string(),
+      brokerage: "Brokerage".to_string(),
+      brokerage_fee: "Fee".to_string(),
+      dividend: "Dividend".to_string(),
+      non_qualified_dividend: "Non-Qualified".to_string(),
+      sec_fee: "SEC".to_string(),
+      finra_taf: "TAF".to_string(),
+      reorg_fee: "Reorg".to_string(),
+      transfer_fee: "Transfer".to_string(),
+      option_premium: "Premium".to_string(),
+      reorg: "Reorganization".to_string(),
+      capital_gains: "Capital Gains".to_string(),
+      sweep: "Sweep".to_string(),
+      crypto_wallet: "Wallet".to_string(),
+      network_fee: "Network Fee".to_string(),
+      crypto_reward: "Reward".to_string(),
+      interest: "Interest".to_string(),
+      cash_program_interest: "Cash Program".to_string(),
+    };
+    let kind = classify_fee(&fee).unwrap();
+    assert_eq!(fee_account(&kind, &accounts), "Reorg");
+
+    let fee = r#"{"id":"11111111111111111::22222222-3333-4444-5555-666666666666","activity_type":"FEE","date":"2023-03-01","net_amount":"-25.00","description":"Outgoing Wire Fee","status":"executed"}"#;
+    let fee = from_json::<account_activities::NonTradeActivity>(fee).unwrap();
+    let kind = classify_fee(&fee).unwrap();
+    assert_eq!(fee_account(&kind, &accounts), "Transfer");
+  }
+
+
+  /// Test grouping of fills belonging to the same order.
+  #[test]
+  fn group_fills_per_order() {
+    let activities = r#"[
+{"id":"11111111111111111::11111111-1111-1111-1111-111111111111","activity_type":"FILL","transaction_time":"2021-06-17T15:35:39.608Z","type":"partial_fill","price":"422.5","qty":"100","side":"buy","symbol":"XYZ","leaves_qty":"75","order_id":"12345678-9123-4567-8912-345678912345","cum_qty":"100","order_status":"partially_filled"},
+{"id":"22222222222222222::22222222-2222-2222-2222-222222222222","activity_type":"DIV","date":"2021-06-17","net_amount":"1.87","description":"Cash DIV @ 0.17, Pos QTY: 11.0, Rec Date: 2021-05-20","symbol":"EFG","qty":"11","per_share_amount":"0.17","status":"executed"},
+{"id":"33333333333333333::33333333-3333-3333-3333-333333333333","activity_type":"FILL","transaction_time":"2021-06-17T15:35:39.781Z","type":"fill","price":"422.6","qty":"75","side":"buy","symbol":"XYZ","leaves_qty":"0","order_id":"12345678-9123-4567-8912-345678912345","cum_qty":"175","order_status":"filled"}
+]"#;
+    let activities = from_json::<VecDeque<account_activities::Activity>>(activities).unwrap();
+    let activities = merge_partial_fills(activities);
+    assert_eq!(activities.len(), 3);
+
+    let activities = activities
+      .into_iter()
+      .map(Activity::from)
+      .collect::<VecDeque<_>>();
+    let activities = group_fills(activities);
+    assert_eq!(activities.len(), 2);
+    match &activities[0] {
+      Activity::Order(trades, fees) => {
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[0].price, Num::from_str("422.5").unwrap());
+        assert_eq!(trades[1].price, Num::from_str("422.6").unwrap());
+        assert!(fees.is_empty());
+      },
+      _ => panic!("encountered unexpected account activity"),
+    }
+    assert!(matches!(&activities[1], Activity::NonTrade(..)));
+  }
+
+
+  /// Check that we can parse account declarations from a journal.
+  #[test]
+  fn parse_declarations() {
+    let journal = br#"; A comment
+account Assets:Alpaca Brokerage
+account Income:Dividend  ; note
+account Expenses:Broker:Fee
+  note Fees charged by the broker
+commodity USD
+2021-06-15 * Transfer
+  Assets:Alpaca Brokerage    1000.00 USD
+  Assets:Bank
+"#;
+    let accounts = parse_account_declarations(&journal[..]).unwrap();
+    let expected = [
+      "Assets:Alpaca Brokerage",
+      "Income:Dividend",
+      "Expenses:Broker:Fee",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect::<HashSet<_>>();
+    assert_eq!(accounts, expected);
+  }
+
+
+  /// Check that we convert CSV prices into price directives.
+  #[test]
+  fn csv_prices() {
+    let csv = br#"Symbol,Date,Price
+XYZ,2021-06-15,9.33
+FUND1, 2021-06-16 , 101.5, EUR
+"#;
+    let mut out = Vec::new();
+    let () = prices_from_csv(&csv[..], &mut out).unwrap();
+    let expected = r#"P 2021-06-15 23:59:59 XYZ USD 9.33
+P 2021-06-16 23:59:59 "FUND1" EUR 101.50
+"#;
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+    let result = prices_from_csv(&b"XYZ,2021-06-15"[..], &mut Vec::new());
+    assert!(result.is_err());
+  }
+
+
+  /// Check that sales are reported with fees and the contract
+  /// multiplier factored in, skipping short sales.
+  #[test]
+  fn report_form_8949_sales() {
+    let json = r#"[
+{"id":"1","activity_type":"FILL","transaction_time":"2021-06-14T14:00:00Z","type":"fill","price":"10","qty":"10","side":"buy","symbol":"XYZ","leaves_qty":"0","order_id":"11111111-1111-1111-1111-111111111111","cum_qty":"10","order_status":"filled"},
+{"id":"2","activity_type":"FILL","transaction_time":"2021-06-14T15:00:00Z","type":"fill","price":"2.5","qty":"1","side":"buy","symbol":"AAPL240621C00190000","leaves_qty":"0","order_id":"22222222-2222-2222-2222-222222222222","cum_qty":"1","order_status":"filled"},
+{"id":"3","activity_type":"FILL","transaction_time":"2021-06-15T14:00:00Z","type":"fill","price":"12","qty":"10","side":"sell","symbol":"XYZ","leaves_qty":"0","order_id":"33333333-3333-3333-3333-333333333333","cum_qty":"10","order_status":"filled"},
+{"id":"4","activity_type":"FEE","date":"2021-06-15","net_amount":"-0.01","description":"TAF fee for proceed of 10 shares (1 trades) on 2021-06-15 by 999999999","status":"executed"},
+{"id":"5","activity_type":"FILL","transaction_time":"2021-06-15T15:00:00Z","type":"fill","price":"3","qty":"1","side":"sell","symbol":"AAPL240621C00190000","leaves_qty":"0","order_id":"44444444-4444-4444-4444-444444444444","cum_qty":"1","order_status":"filled"},
+{"id":"6","activity_type":"FILL","transaction_time":"2021-06-16T14:00:00Z","type":"fill","price":"50","qty":"2","side":"sell_short","symbol":"ABC","leaves_qty":"0","order_id":"55555555-5555-5555-5555-555555555555","cum_qty":"2","order_status":"filled"},
+{"id":"7","activity_type":"FILL","transaction_time":"2021-06-16T15:00:00Z","type":"fill","price":"20","qty":"5","side":"sell","symbol":"DEF","leaves_qty":"0","order_id":"66666666-6666-6666-6666-666666666666","cum_qty":"5","order_status":"filled"}
+]"#;
+    let activities = from_json::<Vec<account_activities::Activity>>(json).unwrap();
+
+    let mut out = Vec::new();
+    let () = report_sales(activities, None, None, &mut out).unwrap();
+
+    let expected = r#"Description,Date Acquired,Date Sold,Proceeds,Cost Basis,Gain or Loss
+10 XYZ,06/14/2021,06/15/2021,119.99,100.00,19.99
+1 AAPL240621C00190000,06/14/2021,06/15/2021,300.00,250.00,50.00
+"#;
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+  }
+
+  /// Check that missing and unused registry entries are reported.
+  #[test]
+  fn report_registry_entries() {
+    let registry =
+      from_json::<Registry>(r#"{"ABC": "ABC Holdings", "XYZ": "XYZ Corp", "OLD": "Old Inc"}"#)
+        .unwrap();
+    let symbols = ["ABC", "XYZ", "NEW", "AAPL240621C00190000"]
+      .into_iter()
+      .map(String::from)
+      .collect();
+
+    let mut out = Vec::new();
+    let missing = report_registry(&registry, &symbols, &mut out).unwrap();
+    assert_eq!(missing, 1);
+
+    let expected = r#"missing: NEW
+unused: OLD
+"#;
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+  }
+
+  /// Check that outcomes of `doctor` checks are reported as expected.
+  #[test]
+  fn report_checks() {
+    let mut out = Vec::new();
+    let () = report_check(&mut out, "market clock", &Ok(()), "unused").unwrap();
+    let result = Err(anyhow!("request timed out")).context("failed to retrieve clock");
+    let () = report_check(&mut out, "market data", &result, "check the URL").unwrap();
+
+    let expected = r#"ok      market clock
+FAILED  market data: failed to retrieve clock: request timed out
+        hint: check the URL
+"#;
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+  }
+
+  /// Check that we derive the crypto currency pair to price a coin
+  /// with correctly.
+  #[test]
+  fn crypto_pairs() {
+    assert_eq!(crypto_pair("BTC", "USD"), "BTC/USD");
+    assert_eq!(crypto_pair("BTCUSD", "USD"), "BTC/USD");
+    assert_eq!(crypto_pair("ETH/USD", "USD"), "ETH/USD");
+  }
+
+  /// Check that the daily equity is reported as CSV and as price
+  /// directives.
+  #[test]
+  fn report_daily_equity() {
+    let days = [
+      DailyEquity {
+        date: NaiveDate::from_ymd_opt(2024, 1, 2).unwrap(),
+        equity: Num::new(100005, 10),
+        profit_loss: Num::from(0),
+        profit_loss_pct: Num::from(0),
+      },
+      DailyEquity {
+        date: NaiveDate::from_ymd_opt(2024, 1, 3).unwrap(),
+        equity: Num::new(1012345, 100),
+        profit_loss: Num::new(12295, 100),
+        profit_loss_pct: Num::new(12294, 1000000),
+      },
+    ];
+
+    let mut out = Vec::new();
+    let () = report_history(&days, None, &mut out).unwrap();
+    let expected = r#"Date,Equity,Profit or Loss,Profit or Loss (%)
+2024-01-02,10000.50,0.00,0.00
+2024-01-03,10123.45,122.95,1.23
+"#;
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+    let mut out = Vec::new();
+    let () = report_history(&days, Some(("NETWORTH", "USD")), &mut out).unwrap();
+    let expected = r#"P 2024-01-02 23:59:59 NETWORTH USD 10000.50
+P 2024-01-03 23:59:59 NETWORTH USD 10123.45
+"#;
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+  }
+
+
+  /// Check that we detect price changes caused by splits.
+  #[test]
+  fn split_ratios() {
+    let ratio = split_ratio(&Num::from(400), &Num::new(10050, 100));
+    assert_eq!(ratio, Some((Num::from(4), Num::from(1))));
+    let ratio = split_ratio(&Num::from(2), &Num::new(1990, 100));
+    assert_eq!(ratio, Some((Num::from(1), Num::from(10))));
+    let ratio = split_ratio(&Num::from(100), &Num::from(70));
+    assert_eq!(ratio, None);
+  }
+
+  /// Check that we read the most recent prices from a price database.
+  #[test]
+  fn price_db() {
+    let db = br#"P 2021-06-14 23:59:59 XYZ USD 9.10
+; A comment
+P 2021-06-15 23:59:59 XYZ USD 9.33
+P 2021-06-15 23:59:59 "FUND1" USD 101.50
+"#;
+    let prices = read_price_db(&db[..]).unwrap();
+    assert_eq!(prices.len(), 2);
+    assert_eq!(prices["XYZ"], Num::new(933, 100));
+    assert_eq!(prices["FUND1"], Num::new(1015, 10));
+  }
+
+
+  /// Check that we calculate prices from bars correctly.
+  #[test]
+  fn bar_prices() {
+    let bars = r#"[
+{"t":"2021-06-14T04:00:00Z","o":10,"c":10,"h":10,"l":10,"v":100,"vw":9},
+{"t":"2021-06-15T04:00:00Z","o":12,"c":12,"h":12,"l":12,"v":300,"vw":11},
+{"t":"2021-06-16T04:00:00Z","o":14,"c":14,"h":14,"l":14,"v":0,"vw":13}
+]"#;
+    let bars = from_json::<Vec<bars::Bar>>(bars).unwrap();
+
+    let mut options = PriceOptions::default();
+    assert_eq!(bar_price(&bars, &options), Num::from(14));
+
+    options.price_window = 2;
+    assert_eq!(bar_price(&bars, &options), Num::from(13));
+
+    options.price_field = PriceField::Vwap;
+    options.price_window = 1;
+    assert_eq!(bar_price(&bars, &options), Num::from(13));
+
+    options.price_window = 3;
+    assert_eq!(bar_price(&bars, &options), Num::new(4200, 400));
+  }
+
+
+  /// Check that we detect pass-thru charges recurring monthly.
+  #[test]
+  fn detect_recurring_charges() {
+    let charges = r#"[
+{"id":"1","activity_type":"PTC","date":"2021-03-01","net_amount":"-9","description":"Market data","status":"executed"},
+{"id":"2","activity_type":"PTC","date":"2021-04-01","net_amount":"-9","description":"Market data","status":"executed"},
+{"id":"3","activity_type":"PTC","date":"2021-05-03","net_amount":"-9","description":"Market data","status":"executed"},
+{"id":"4","activity_type":"PTC","date":"2021-04-15","net_amount":"-2","description":"Paper statement","status":"executed"},
+{"id":"5","activity_type":"PTC","date":"2021-06-15","net_amount":"-2","description":"Paper statement","status":"executed"},
+{"id":"6","activity_type":"PTC","date":"2021-07-15","net_amount":"-2","description":"Paper statement","status":"executed"}
+]"#;
+    let charges = from_json::<Vec<account_activities::NonTradeActivity>>(charges).unwrap();
+    let recurring = recurring_charges(&charges);
+
+    assert_eq!(recurring.len(), 1);
+    assert_eq!(recurring[0].id, "3");
+  }
 }
@@ -0,0 +1,78 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use chrono::NaiveDate;
+
+use num_decimal::Num;
+
+use serde_json::json;
+
+use crate::client;
+use crate::ledger::format_price;
+
+
+/// A summary of the activities processed in a run.
+#[derive(Debug, Default)]
+pub struct Summary {
+  /// The number of activities processed.
+  pub activities: usize,
+  /// The total of all fees charged.
+  pub fees: Num,
+  /// The total of all dividends received.
+  pub dividends: Num,
+  /// The date of the earliest activity processed.
+  pub first: Option<NaiveDate>,
+  /// The date of the latest activity processed.
+  pub last: Option<NaiveDate>,
+  /// The number of positions reconciled, if any.
+  pub positions: Option<usize>,
+}
+
+impl Summary {
+  /// Format the summary as a human readable message.
+  pub fn format(&self, currency: &str) -> String {
+    let mut text = format!(
+      "apcaledge: processed {} new activities; fees: {}; dividends: {}",
+      self.activities,
+      format_price(&self.fees, currency),
+      format_price(&self.dividends, currency),
+    );
+    if let Some(positions) = self.positions {
+      text += &format!("; positions: {positions}");
+    }
+    text
+  }
+
+  /// Format the summary as a git commit message.
+  pub fn format_commit(&self, currency: &str) -> String {
+    let subject = match (self.first, self.last) {
+      (Some(first), Some(last)) if first != last => {
+        format!("Import account activities from {first} to {last}")
+      },
+      (Some(date), _) | (_, Some(date)) => format!("Import account activities of {date}"),
+      (None, None) => "Update journal".to_string(),
+    };
+    format!(
+      "{subject}\n\nProcessed {} new activities; fees: {}; dividends: {}\n",
+      self.activities,
+      format_price(&self.fees, currency),
+      format_price(&self.dividends, currency),
+    )
+  }
+}
+
+
+/// Post a message to a webhook at the given URL.
+///
+/// The message is sent as a JSON object of the form `{"text": ...}`,
+/// which is understood by Slack and Matrix (hookshot) webhooks and
+/// trivial to consume by generic HTTP endpoints.
+pub async fn post(url: &str, text: &str) -> Result<()> {
+  let body = json!({ "text": text }).to_string();
+  client::post_json(url, body)
+    .await
+    .with_context(|| format!("failed to send notification to {}", url))
+}
@@ -0,0 +1,141 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::str::FromStr as _;
+
+use chrono::NaiveDate;
+
+use num_decimal::Num;
+
+use once_cell::sync::Lazy;
+
+use regex::Regex;
+
+
+/// The regular expression for matching OCC option symbols, e.g.,
+/// AAPL240621C00190000.
+static OCC_RE: Lazy<Regex> = Lazy::new(|| {
+  Regex::new(
+    r"^(?P<underlying>[A-Z][A-Z0-9.]{0,5})(?P<expiration>\d{6})(?P<kind>[CP])(?P<strike>\d{8})$",
+  )
+  .unwrap()
+});
+
+/// The number of shares of the underlying a standard option contract
+/// covers.
+const CONTRACT_MULTIPLIER: i64 = 100;
+
+
+/// The kind of an option contract.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptionKind {
+  Call,
+  Put,
+}
+
+
+/// An option contract, as decoded from an OCC symbol.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptionContract {
+  /// The symbol of the underlying.
+  pub underlying: String,
+  /// The expiration date of the contract.
+  pub expiration: NaiveDate,
+  /// The strike price.
+  pub strike: Num,
+  /// Whether we are dealing with a call or put.
+  pub kind: OptionKind,
+}
+
+impl OptionContract {
+  /// Try decoding the given symbol as an OCC option symbol, returning
+  /// `None` if it is not one.
+  pub fn parse(symbol: &str) -> Option<Self> {
+    let captures = OCC_RE.captures(symbol)?;
+    let expiration = NaiveDate::parse_from_str(&captures["expiration"], "%y%m%d").ok()?;
+    let kind = match &captures["kind"] {
+      "C" => OptionKind::Call,
+      "P" => OptionKind::Put,
+      _ => unreachable!(),
+    };
+    // The strike price is encoded with three implied decimal places.
+    let strike = Num::from_str(&captures["strike"]).ok()? / 1000;
+
+    Some(Self {
+      underlying: captures["underlying"].to_string(),
+      expiration,
+      strike,
+      kind,
+    })
+  }
+}
+
+impl Display for OptionContract {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    let kind = match self.kind {
+      OptionKind::Call => 'C',
+      OptionKind::Put => 'P',
+    };
+    write!(
+      f,
+      "{} {} {}{}",
+      self.underlying,
+      self.expiration.format("%Y-%m-%d"),
+      self.strike.display(),
+      kind
+    )
+  }
+}
+
+
+/// Retrieve the factor to scale the per-share price Alpaca reports for
+/// the security with the given symbol by, to get to the price of a
+/// single unit of it. That is the contract multiplier for option
+/// contracts and one for everything else.
+pub fn contract_multiplier(symbol: &str) -> Num {
+  if OptionContract::parse(symbol).is_some() {
+    Num::from(CONTRACT_MULTIPLIER)
+  } else {
+    Num::from(1)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+
+  /// Check that we can decode OCC option symbols.
+  #[test]
+  fn parse_occ_symbol() {
+    let contract = OptionContract::parse("AAPL240621C00190000").unwrap();
+    assert_eq!(contract.underlying, "AAPL");
+    assert_eq!(
+      contract.expiration,
+      NaiveDate::from_ymd_opt(2024, 6, 21).unwrap()
+    );
+    assert_eq!(contract.strike, Num::from(190));
+    assert_eq!(contract.kind, OptionKind::Call);
+    assert_eq!(contract.to_string(), "AAPL 2024-06-21 190C");
+
+    let contract = OptionContract::parse("SPY231215P00452500").unwrap();
+    assert_eq!(contract.strike, Num::new(4525, 10));
+    assert_eq!(contract.kind, OptionKind::Put);
+    assert_eq!(contract.to_string(), "SPY 2023-12-15 452.5P");
+
+    assert_eq!(OptionContract::parse("AAPL"), None);
+    assert_eq!(OptionContract::parse("AAPL241321C00190000"), None);
+  }
+
+  /// Check that option contracts are scaled by the contract
+  /// multiplier.
+  #[test]
+  fn contract_multipliers() {
+    assert_eq!(contract_multiplier("AAPL240621C00190000"), Num::from(100));
+    assert_eq!(contract_multiplier("AAPL"), Num::from(1));
+  }
+}
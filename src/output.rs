@@ -0,0 +1,231 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::create_dir_all;
+use std::fs::read_dir;
+use std::io::stdout;
+use std::io::Stdout;
+use std::io::Write;
+use std::mem::take;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use chrono::Datelike as _;
+use chrono::NaiveDate;
+
+use crate::args::SplitBy;
+use crate::atomic::AtomicFile;
+use crate::pager::Pager;
+
+
+/// The name of the file including all the individual journals.
+const MASTER_FILE: &str = "main.journal";
+
+
+/// The destination for emitted transactions.
+pub enum Output {
+  /// Write everything to stdout.
+  Stdout(Stdout),
+  /// Write everything to stdout through a pager.
+  Pager(Pager),
+  /// Write transactions into one file per month or year below a
+  /// directory.
+  ///
+  /// Files are replaced atomically once flushed, so that an aborted
+  /// run never leaves a partially written file behind.
+  Split {
+    /// The directory to write files to.
+    dir: PathBuf,
+    /// The granularity at which to split.
+    split_by: SplitBy,
+    /// Whether to append to files existing prior to this run instead
+    /// of overwriting them.
+    append: bool,
+    /// Whether to keep a copy of the previous version of each file
+    /// replaced.
+    backup: bool,
+    /// The file currently being written to.
+    current: Option<AtomicFile>,
+    /// Files written to before but not yet flushed, by path.
+    pending: BTreeMap<PathBuf, AtomicFile>,
+    /// All the files written to as part of this run.
+    written: BTreeSet<PathBuf>,
+  },
+}
+
+impl Output {
+  /// Create an `Output` writing to stdout.
+  pub fn stdout() -> Self {
+    Self::Stdout(stdout())
+  }
+
+  /// Create an `Output` writing to stdout, through a pager if stdout
+  /// refers to a terminal and paging is not disabled.
+  pub fn paged(pager: Option<&str>, no_pager: bool) -> Result<Self> {
+    let pager = if no_pager { None } else { Pager::spawn(pager)? };
+    Ok(pager.map(Self::Pager).unwrap_or_else(Self::stdout))
+  }
+
+  /// Create an `Output` splitting transactions into files below the
+  /// given directory.
+  pub fn split(dir: PathBuf, split_by: SplitBy, append: bool, backup: bool) -> Self {
+    Self::Split {
+      dir,
+      split_by,
+      append,
+      backup,
+      current: None,
+      pending: BTreeMap::new(),
+      written: BTreeSet::new(),
+    }
+  }
+
+  /// Retrieve the writer for transactions dated at the given date.
+  pub fn writer(&mut self, date: NaiveDate) -> Result<&mut dyn Write> {
+    match self {
+      Self::Stdout(stdout) => Ok(stdout),
+      Self::Pager(pager) => Ok(pager),
+      Self::Split {
+        dir,
+        split_by,
+        append,
+        current,
+        pending,
+        written,
+        ..
+      } => {
+        let path = match split_by {
+          SplitBy::Month => dir
+            .join(format!("{:04}", date.year()))
+            .join(format!("{:02}.journal", date.month())),
+          SplitBy::Year => dir.join(format!("{:04}.journal", date.year())),
+        };
+
+        let reuse = matches!(current, Some(current) if current.path() == path);
+        if !reuse {
+          if let Some(file) = current.take() {
+            let _prev = pending.insert(file.path().to_path_buf(), file);
+          }
+
+          let file = if let Some(file) = pending.remove(&path) {
+            file
+          } else {
+            if let Some(parent) = path.parent() {
+              let () = create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {}", parent.display()))?;
+            }
+
+            // Files we already wrote to in this run always get appended
+            // to, as we may switch back and forth between them.
+            let append = *append || written.contains(&path);
+            let _inserted = written.insert(path.clone());
+            AtomicFile::create(&path, append)?
+          };
+          *current = Some(file);
+        }
+        // We just made sure that `current` is set.
+        let file = current.as_mut().unwrap();
+        Ok(file)
+      },
+    }
+  }
+
+  /// Flush all buffered output, replacing the files written to.
+  pub fn flush(&mut self) -> Result<()> {
+    match self {
+      Self::Stdout(stdout) => stdout.flush().context("failed to flush stdout"),
+      Self::Pager(pager) => pager.flush().context("failed to flush pager"),
+      Self::Split {
+        backup,
+        current,
+        pending,
+        ..
+      } => {
+        let files = current
+          .take()
+          .into_iter()
+          .chain(take(pending).into_values());
+        for file in files {
+          let () = file.commit(*backup)?;
+        }
+        Ok(())
+      },
+    }
+  }
+
+  /// Flush all buffered output and, if splitting output into files,
+  /// (re-)generate the master file including all of them. If output is
+  /// paged, wait for the pager to exit.
+  pub fn finish(mut self) -> Result<()> {
+    let () = self.flush()?;
+
+    if let Self::Pager(pager) = self {
+      return pager.finish()
+    }
+
+    if let Self::Split {
+      dir,
+      split_by,
+      backup,
+      ..
+    } = &self
+    {
+      let journals = journals(dir, *split_by)?;
+      let path = dir.join(MASTER_FILE);
+      let mut file = AtomicFile::create(&path, false)?;
+      for journal in journals {
+        writeln!(file, "include {}", journal.display())
+          .with_context(|| format!("failed to write {}", path.display()))?;
+      }
+      let () = file.commit(*backup)?;
+    }
+    Ok(())
+  }
+}
+
+
+/// Check whether the given file name is made up of the given number of
+/// digits followed by the provided extension (if any).
+fn is_numbered(name: &str, digits: usize, extension: &str) -> bool {
+  name.len() == digits + extension.len()
+    && name.ends_with(extension)
+    && name[..digits].chars().all(|c| c.is_ascii_digit())
+}
+
+
+/// Find all journal files below the given directory, returning their
+/// paths relative to it in chronological order.
+fn journals(dir: &Path, split_by: SplitBy) -> Result<BTreeSet<PathBuf>> {
+  let entries = |dir: &Path| -> Result<Vec<String>> {
+    read_dir(dir)
+      .with_context(|| format!("failed to read directory {}", dir.display()))?
+      .map(|entry| {
+        let entry = entry.with_context(|| format!("failed to read directory {}", dir.display()))?;
+        Ok(entry.file_name().to_string_lossy().into_owned())
+      })
+      .collect()
+  };
+
+  let mut journals = BTreeSet::new();
+  for name in entries(dir)? {
+    match split_by {
+      SplitBy::Year if is_numbered(&name, 4, ".journal") => {
+        let _inserted = journals.insert(PathBuf::from(name));
+      },
+      SplitBy::Month if is_numbered(&name, 4, "") && dir.join(&name).is_dir() => {
+        for month in entries(&dir.join(&name))? {
+          if is_numbered(&month, 2, ".journal") {
+            let _inserted = journals.insert(Path::new(&name).join(month));
+          }
+        }
+      },
+      _ => (),
+    }
+  }
+  Ok(journals)
+}
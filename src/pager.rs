@@ -0,0 +1,113 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::env::var_os;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+
+/// The pager to use if none is configured.
+const DEFAULT_PAGER: &str = "less";
+
+
+/// Check whether stdout refers to a terminal.
+fn stdout_is_terminal() -> bool {
+  // `std::io::IsTerminal` is not available on our minimum supported
+  // Rust version, so ask the shell instead.
+  Command::new("sh")
+    .args(["-c", "test -t 1"])
+    .stdin(Stdio::null())
+    .stdout(Stdio::inherit())
+    .stderr(Stdio::null())
+    .status()
+    .map(|status| status.success())
+    .unwrap_or(false)
+}
+
+
+/// A pager that output is piped through.
+#[derive(Debug)]
+pub struct Pager {
+  /// The pager process.
+  child: Child,
+  /// The pager's stdin, or `None` if the pager exited early.
+  stdin: Option<ChildStdin>,
+}
+
+impl Pager {
+  /// Spawn a pager if stdout refers to a terminal.
+  ///
+  /// The pager command is taken from `command`, if provided, or the
+  /// `APCALEDGE_PAGER` and `PAGER` environment variables, in that
+  /// order, falling back to `less`. An empty command or `cat` disables
+  /// paging.
+  pub fn spawn(command: Option<&str>) -> Result<Option<Self>> {
+    let command = command
+      .map(OsString::from)
+      .or_else(|| var_os("APCALEDGE_PAGER"))
+      .or_else(|| var_os("PAGER"))
+      .unwrap_or_else(|| OsString::from(DEFAULT_PAGER));
+
+    if command.is_empty() || command == "cat" || !stdout_is_terminal() {
+      return Ok(None)
+    }
+
+    let mut pager = Command::new("sh");
+    let _cmd = pager.arg("-c").arg(&command).stdin(Stdio::piped());
+    // Just like git, make `less` exit right away if the output fits on
+    // a single screen and pass through color escape sequences, unless
+    // the user configured it differently.
+    if var_os("LESS").is_none() {
+      let _cmd = pager.env("LESS", "FRX");
+    }
+
+    let mut child = pager
+      .spawn()
+      .with_context(|| format!("failed to spawn pager `{}`", command.to_string_lossy()))?;
+    let stdin = child.stdin.take();
+    Ok(Some(Self { child, stdin }))
+  }
+
+  /// Close the pager's input and wait for the user to exit it.
+  pub fn finish(mut self) -> Result<()> {
+    drop(self.stdin.take());
+    let _status = self.child.wait().context("failed to wait for pager")?;
+    Ok(())
+  }
+}
+
+impl Write for Pager {
+  fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+    if let Some(stdin) = &mut self.stdin {
+      match stdin.write(buf) {
+        // The user quit the pager before all output was written. That
+        // is not an error; we just discard everything from here on.
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => self.stdin = None,
+        result => return result,
+      }
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> IoResult<()> {
+    match &mut self.stdin {
+      Some(stdin) => match stdin.flush() {
+        Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+          self.stdin = None;
+          Ok(())
+        },
+        result => result,
+      },
+      None => Ok(()),
+    }
+  }
+}
@@ -0,0 +1,155 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde::Deserialize;
+use serde_json::from_reader as json_from_reader;
+
+use crate::input;
+
+
+/// An entry in the registry.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Entry {
+  /// Just the name of the asset.
+  Name(String),
+  /// The name of the asset along with security identifiers.
+  Details {
+    /// The name of the asset.
+    name: String,
+    /// The International Securities Identification Number.
+    #[serde(default)]
+    isin: Option<String>,
+    /// The Committee on Uniform Securities Identification Procedures
+    /// number.
+    #[serde(default)]
+    cusip: Option<String>,
+  },
+}
+
+impl Entry {
+  /// Retrieve the name of the asset.
+  pub fn name(&self) -> &str {
+    match self {
+      Self::Name(name) | Self::Details { name, .. } => name,
+    }
+  }
+
+  /// Retrieve the asset's ISIN, if known.
+  pub fn isin(&self) -> Option<&str> {
+    match self {
+      Self::Name(..) => None,
+      Self::Details { isin, .. } => isin.as_deref(),
+    }
+  }
+
+  /// Retrieve the asset's CUSIP, if known.
+  pub fn cusip(&self) -> Option<&str> {
+    match self {
+      Self::Name(..) => None,
+      Self::Details { cusip, .. } => cusip.as_deref(),
+    }
+  }
+}
+
+
+/// A registry mapping symbols to names and, optionally, security
+/// identifiers.
+///
+/// The registry is a JSON object with symbols as keys. Values are
+/// either the name of the asset or an object of the form
+/// `{"name": ..., "isin": ..., "cusip": ...}`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Registry(HashMap<String, Entry>);
+
+impl Registry {
+  /// Load the registry from the JSON file at the given path (see
+  /// `input::open` for the supported kinds of paths).
+  pub fn load(path: &Path) -> Result<Self> {
+    let file = input::open(path)
+      .with_context(|| format!("failed to open registry file {}", path.display()))?;
+    let registry = json_from_reader::<_, Self>(file)
+      .with_context(|| format!("failed to read registry {}", path.display()))?;
+    Ok(registry)
+  }
+
+  /// Load and merge the registries at the given paths, with entries
+  /// from later registries overriding those from earlier ones.
+  pub fn load_all(paths: &[PathBuf]) -> Result<Self> {
+    paths.iter().try_fold(Self::default(), |mut merged, path| {
+      let () = merged.merge(Self::load(path)?);
+      Ok(merged)
+    })
+  }
+
+  /// Merge another registry into this one, with its entries taking
+  /// precedence.
+  fn merge(&mut self, other: Self) {
+    let () = self.0.extend(other.0);
+  }
+
+  /// Look up the entry for the given symbol.
+  pub fn get(&self, symbol: &str) -> Option<&Entry> {
+    self.0.get(symbol)
+  }
+
+  /// Retrieve all symbols present in the registry.
+  pub fn symbols(&self) -> impl Iterator<Item = &str> {
+    self.0.keys().map(String::as_str)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use serde_json::from_str as from_json;
+
+
+  /// Check that we can parse registries mixing plain names and entries
+  /// with security identifiers.
+  #[test]
+  fn parse_registry() {
+    let registry = r#"{
+  "ABC": "ABC Holdings",
+  "XYZ": {"name": "XYZ Corp", "isin": "US0000000001", "cusip": "000000000"},
+  "EFG": {"name": "EFG Inc"}
+}"#;
+    let registry = from_json::<Registry>(registry).unwrap();
+
+    let abc = registry.get("ABC").unwrap();
+    assert_eq!(abc.name(), "ABC Holdings");
+    assert_eq!(abc.isin(), None);
+
+    let xyz = registry.get("XYZ").unwrap();
+    assert_eq!(xyz.name(), "XYZ Corp");
+    assert_eq!(xyz.isin(), Some("US0000000001"));
+    assert_eq!(xyz.cusip(), Some("000000000"));
+
+    let efg = registry.get("EFG").unwrap();
+    assert_eq!(efg.name(), "EFG Inc");
+    assert_eq!(efg.cusip(), None);
+
+    assert!(registry.get("HIJ").is_none());
+  }
+
+  /// Check that entries of a merged registry override existing ones.
+  #[test]
+  fn merge_registries() {
+    let mut registry = from_json::<Registry>(r#"{"ABC": "ABC", "XYZ": "XYZ"}"#).unwrap();
+    let overrides = from_json::<Registry>(r#"{"XYZ": "XYZ Corp"}"#).unwrap();
+    let () = registry.merge(overrides);
+
+    assert_eq!(registry.get("ABC").unwrap().name(), "ABC");
+    assert_eq!(registry.get("XYZ").unwrap().name(), "XYZ Corp");
+  }
+}
@@ -0,0 +1,56 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use chrono::NaiveDate;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::from_reader as json_from_reader;
+use serde_json::to_writer_pretty as to_json_writer_pretty;
+
+use crate::atomic::AtomicFile;
+
+
+/// The state persisted between runs, allowing us to resume where we
+/// left off.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct State {
+  /// The ID of the last activity that was emitted.
+  pub last_id: String,
+  /// The date of the last activity that was emitted.
+  pub date: NaiveDate,
+}
+
+impl State {
+  /// Load the state from the file at the given path, if it exists.
+  pub fn load(path: &Path) -> Result<Option<Self>> {
+    let file = match File::open(path) {
+      Ok(file) => file,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+      Err(err) => {
+        return Err(err).with_context(|| format!("failed to open state file {}", path.display()))
+      },
+    };
+
+    let state = json_from_reader::<_, Self>(file)
+      .with_context(|| format!("failed to read state {}", path.display()))?;
+    Ok(Some(state))
+  }
+
+  /// Save the state to the file at the given path, atomically
+  /// replacing the previous one, so that we never end up with a
+  /// partially written state.
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let mut file = AtomicFile::create(path, false)?;
+    let () = to_json_writer_pretty(&mut file, self)
+      .with_context(|| format!("failed to write state to {}", path.display()))?;
+    file.commit(false)
+  }
+}
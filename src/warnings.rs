@@ -0,0 +1,83 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde::Serialize;
+use serde_json::to_writer_pretty as to_json_writer_pretty;
+
+
+/// The kind of a warning.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+  /// An activity that can't be represented got ignored.
+  IgnoredActivity,
+  /// An activity got booked using a fallback, e.g., against the `XXX`
+  /// placeholder account or without the trade it belongs to.
+  Fallback,
+  /// A partial fill could not be merged with the fill completing its
+  /// order, which is reported later.
+  PartialFill,
+  /// The running cash balance turned negative.
+  NegativeCash,
+  /// An activity failed to be processed.
+  Failure,
+}
+
+
+/// A warning about something that may require the user's attention.
+#[derive(Debug, Serialize)]
+pub struct Warning {
+  /// The kind of warning.
+  pub kind: Kind,
+  /// The ID of the activity the warning is about, if any.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub activity: Option<String>,
+  /// A human readable description of the problem.
+  pub message: String,
+}
+
+
+/// Write the provided warnings to the file at the given path, in JSON
+/// format.
+pub fn write(path: &Path, warnings: &[Warning]) -> Result<()> {
+  let file = File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+  let () = to_json_writer_pretty(file, warnings)
+    .with_context(|| format!("failed to write warnings to {}", path.display()))?;
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use serde_json::to_string as to_json;
+
+
+  /// Check that warnings are serialized as expected.
+  #[test]
+  fn serialize_warning() {
+    let warning = Warning {
+      kind: Kind::PartialFill,
+      activity: Some("1234".to_string()),
+      message: "partial fill".to_string(),
+    };
+    let json = to_json(&warning).unwrap();
+    let expected = r#"{"kind":"partial-fill","activity":"1234","message":"partial fill"}"#;
+    assert_eq!(json, expected);
+
+    let warning = Warning {
+      kind: Kind::NegativeCash,
+      activity: None,
+      message: "negative".to_string(),
+    };
+    let json = to_json(&warning).unwrap();
+    assert_eq!(json, r#"{"kind":"negative-cash","message":"negative"}"#);
+  }
+}
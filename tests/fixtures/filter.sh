@@ -0,0 +1,3 @@
+#!/bin/sh
+# Rename a payee and drop all transactions dated 2021-06-17.
+exec sed -e 's/"payee":"XYZ Corp"/"payee":"XYZ Corporation"/' -e '/"date":"2021-06-17"/d'
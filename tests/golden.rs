@@ -0,0 +1,77 @@
+// Copyright (C) 2026 Daniel Mueller <deso@posteo.net>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Golden-file tests rendering the account activities in
+//! `tests/fixtures/<name>.json` and comparing the output against
+//! `tests/fixtures/<name>.ledger`. Additional arguments to pass to the
+//! program can be provided in `tests/fixtures/<name>.args`.
+//!
+//! Set the `APCALEDGE_UPDATE_GOLDEN` environment variable to update
+//! the expected output of existing fixtures instead. Every updated
+//! fixture is reported, so that changes can be reviewed. New fixtures
+//! require a `.ledger` file to be created explicitly.
+
+use std::env::var_os;
+use std::fs::read_dir;
+use std::fs::read_to_string;
+use std::fs::write;
+use std::path::Path;
+use std::process::Command;
+
+
+/// Render the fixture at the given path using the program's hidden
+/// `--render-fixture` mode.
+fn render(fixture: &Path) -> String {
+  let registry = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/registry.json");
+  let args = read_to_string(fixture.with_extension("args")).unwrap_or_default();
+  let output = Command::new(env!("CARGO_BIN_EXE_apcaledge"))
+    .arg("activity")
+    .arg(registry)
+    .arg("--render-fixture")
+    .arg(fixture)
+    .args(args.split_whitespace())
+    .output()
+    .unwrap();
+
+  assert!(
+    output.status.success(),
+    "failed to render {}: {}",
+    fixture.display(),
+    String::from_utf8_lossy(&output.stderr)
+  );
+  String::from_utf8(output.stdout).unwrap()
+}
+
+
+/// Check that all fixtures render to the expected output.
+#[test]
+fn golden_files() {
+  let update = var_os("APCALEDGE_UPDATE_GOLDEN").is_some();
+  let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+  let mut count = 0;
+
+  for entry in read_dir(&fixtures).unwrap() {
+    let path = entry.unwrap().path();
+    if path.extension().map(|ext| ext != "json").unwrap_or(true)
+      || path.file_stem() == Some("registry".as_ref())
+    {
+      continue
+    }
+
+    let actual = render(&path);
+    let ledger = path.with_extension("ledger");
+    let expected = read_to_string(&ledger)
+      .unwrap_or_else(|err| panic!("failed to read {}: {err}", ledger.display()));
+    if update {
+      if actual != expected {
+        eprintln!("updating {}", ledger.display());
+        let () = write(&ledger, &actual).unwrap();
+      }
+    } else {
+      assert_eq!(actual, expected, "{}", path.display());
+    }
+    count += 1;
+  }
+
+  assert_ne!(count, 0);
+}